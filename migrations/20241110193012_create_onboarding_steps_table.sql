@@ -0,0 +1,12 @@
+-- Add migration script here
+CREATE TABLE onboarding_steps (
+   onboarding_step_id uuid NOT NULL,
+   title TEXT NOT NULL,
+   text_content TEXT NOT NULL,
+   html_content TEXT NOT NULL,
+   -- How long after confirmation the step should be delivered.
+   -- The welcome email is simply a step with no delay.
+   delay_hours INT NOT NULL CHECK (delay_hours >= 0),
+   created_at timestamptz NOT NULL,
+   PRIMARY KEY(onboarding_step_id)
+);
@@ -0,0 +1,18 @@
+-- Add migration script here
+-- The delivery queue now carries both newsletter issues and onboarding steps,
+-- so a task is identified by its own id rather than by (issue, email).
+BEGIN;
+    ALTER TABLE issue_delivery_queue DROP CONSTRAINT issue_delivery_queue_pkey;
+    ALTER TABLE issue_delivery_queue ADD COLUMN task_id BIGSERIAL PRIMARY KEY;
+    ALTER TABLE issue_delivery_queue ALTER COLUMN newsletter_issue_id DROP NOT NULL;
+    ALTER TABLE issue_delivery_queue ADD COLUMN onboarding_step_id uuid NULL
+        REFERENCES onboarding_steps (onboarding_step_id) ON DELETE CASCADE;
+    -- Tasks are not picked up by the worker before this point in time.
+    ALTER TABLE issue_delivery_queue ADD COLUMN execute_after timestamptz NOT NULL DEFAULT now();
+    ALTER TABLE issue_delivery_queue ADD CONSTRAINT issue_delivery_queue_content_check
+        CHECK ((newsletter_issue_id IS NULL) <> (onboarding_step_id IS NULL));
+    ALTER TABLE issue_delivery_queue ADD CONSTRAINT issue_delivery_queue_issue_email_key
+        UNIQUE (newsletter_issue_id, subscriber_email);
+    ALTER TABLE issue_delivery_queue ADD CONSTRAINT issue_delivery_queue_step_email_key
+        UNIQUE (onboarding_step_id, subscriber_email);
+COMMIT;
@@ -0,0 +1,18 @@
+-- Add migration script here
+-- Emails that must go out as a consequence of a committed change (e.g. a new
+-- subscriber's confirmation email). Rows are written in the same transaction as
+-- the change itself and sent later on by a background dispatcher.
+CREATE TABLE email_outbox (
+   email_id uuid NOT NULL,
+   recipient TEXT NOT NULL,
+   subject TEXT NOT NULL,
+   html_content TEXT NOT NULL,
+   text_content TEXT NOT NULL,
+   created_at timestamptz NOT NULL,
+   n_retries SMALLINT NOT NULL DEFAULT 0,
+   execute_after timestamptz NOT NULL DEFAULT now(),
+   last_error TEXT NULL,
+   -- Set once the dispatcher gives up on the email
+   failed_at timestamptz NULL,
+   PRIMARY KEY(email_id)
+);
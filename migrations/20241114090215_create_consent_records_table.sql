@@ -0,0 +1,18 @@
+-- Add migration script here
+-- Append-only ledger proving when and how a subscriber agreed to receive emails.
+CREATE TABLE consent_records (
+   consent_record_id uuid NOT NULL,
+   subscriber_id uuid NOT NULL
+      REFERENCES subscriptions (id),
+   -- `subscribed` when the form is submitted, `confirmed` when the link is clicked.
+   event TEXT NOT NULL CHECK (event IN ('subscribed', 'confirmed')),
+   -- Where the consent was collected (e.g. the signup form that was used).
+   source TEXT NOT NULL,
+   ip_address TEXT NULL,
+   user_agent TEXT NULL,
+   consent_text_version TEXT NOT NULL,
+   recorded_at timestamptz NOT NULL,
+   PRIMARY KEY(consent_record_id)
+);
+
+CREATE INDEX consent_records_subscriber_id_idx ON consent_records (subscriber_id, recorded_at);
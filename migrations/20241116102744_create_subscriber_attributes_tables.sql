@@ -0,0 +1,30 @@
+-- Add migration script here
+-- Extra fields admins can ask subscribers for, on top of their name and email.
+CREATE TABLE subscriber_attribute_definitions (
+   attribute_id uuid NOT NULL,
+   -- Name of the field in the signup form, e.g. `company`.
+   key TEXT NOT NULL UNIQUE,
+   label TEXT NOT NULL,
+   kind TEXT NOT NULL CHECK (kind IN ('text', 'number', 'date', 'boolean', 'enum')),
+   -- The allowed values of an `enum` attribute, empty for every other kind.
+   options TEXT[] NOT NULL,
+   required BOOLEAN NOT NULL,
+   created_at timestamptz NOT NULL,
+   PRIMARY KEY(attribute_id)
+);
+
+-- Values are stored in the column matching the kind of the attribute
+-- (`enum` values are stored as text).
+CREATE TABLE subscriber_attribute_values (
+   subscriber_id uuid NOT NULL
+      REFERENCES subscriptions (id) ON DELETE CASCADE,
+   attribute_id uuid NOT NULL
+      REFERENCES subscriber_attribute_definitions (attribute_id) ON DELETE CASCADE,
+   text_value TEXT NULL,
+   number_value DOUBLE PRECISION NULL,
+   date_value DATE NULL,
+   boolean_value BOOLEAN NULL,
+   updated_at timestamptz NOT NULL,
+   PRIMARY KEY(subscriber_id, attribute_id),
+   CHECK (num_nonnulls(text_value, number_value, date_value, boolean_value) = 1)
+);
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Deliveries failing because of a transient provider error are retried with a backoff.
+ALTER TABLE issue_delivery_queue ADD COLUMN n_retries SMALLINT NOT NULL DEFAULT 0;
@@ -0,0 +1,23 @@
+-- Add migration script here
+-- One row per request made to the email provider for a delivery task.
+-- Tasks are deleted once done: this is what remains of them.
+CREATE TABLE delivery_log (
+   delivery_id BIGSERIAL PRIMARY KEY,
+   task_id BIGINT NOT NULL,
+   newsletter_issue_id uuid NULL
+     REFERENCES newsletter_issues (newsletter_issue_id),
+   onboarding_step_id uuid NULL
+     REFERENCES onboarding_steps (onboarding_step_id) ON DELETE SET NULL,
+   subscriber_email TEXT NOT NULL,
+   -- 1 for the first attempt, incremented on every retry.
+   attempt SMALLINT NOT NULL,
+   attempted_at timestamptz NOT NULL,
+   outcome TEXT NOT NULL
+     CHECK (outcome IN ('sent', 'transient_failure', 'permanent_failure')),
+   -- The provider's id for the email, to correlate bounce and open events.
+   message_id TEXT NULL,
+   error_code BIGINT NULL,
+   error_message TEXT NULL
+);
+CREATE INDEX delivery_log_message_id_idx ON delivery_log (message_id);
+CREATE INDEX delivery_log_subscriber_email_idx ON delivery_log (subscriber_email);
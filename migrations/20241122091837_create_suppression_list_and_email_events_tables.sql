@@ -0,0 +1,26 @@
+-- Add migration script here
+-- Addresses we must not email anymore, whether or not they belong to a subscriber.
+CREATE TABLE suppression_list (
+   email TEXT NOT NULL,
+   reason TEXT NOT NULL
+     CHECK (reason IN ('hard_bounce', 'spam_complaint', 'manual_suppression', 'provider_rejected')),
+   suppressed_at timestamptz NOT NULL,
+   PRIMARY KEY(email)
+);
+
+-- Bounces, spam complaints and subscription changes reported by the email provider.
+CREATE TABLE email_events (
+   email_event_id uuid NOT NULL,
+   -- `Bounce`, `SpamComplaint` or `SubscriptionChange`, as named by Postmark.
+   record_type TEXT NOT NULL,
+   email TEXT NOT NULL,
+   -- Matches `delivery_log.message_id` for emails sent by the delivery worker.
+   message_id TEXT NULL,
+   description TEXT NULL,
+   -- Whether the event got the address added to the suppression list.
+   suppressed BOOLEAN NOT NULL,
+   occurred_at timestamptz NOT NULL,
+   received_at timestamptz NOT NULL,
+   PRIMARY KEY(email_event_id)
+);
+CREATE INDEX email_events_received_at_idx ON email_events (received_at);
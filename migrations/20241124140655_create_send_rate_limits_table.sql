@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Token buckets shared by all delivery workers to enforce the send rate limits.
+CREATE TABLE send_rate_limits (
+   -- `*` for the global limit, the recipient domain otherwise.
+   bucket TEXT NOT NULL,
+   tokens DOUBLE PRECISION NOT NULL,
+   refilled_at timestamptz NOT NULL,
+   PRIMARY KEY(bucket)
+);
@@ -0,0 +1,16 @@
+-- Add migration script here
+-- Written (and committed) before a delivery is handed to the email provider.
+-- If the worker crashes before its batch is committed, the task comes back with its intent:
+-- the provider is asked about `delivery_key` before the email is sent again.
+CREATE TABLE send_intents (
+   task_id BIGINT NOT NULL
+     REFERENCES issue_delivery_queue (task_id) ON DELETE CASCADE,
+   -- Sent along with the email as metadata, the provider can be searched by it.
+   delivery_key uuid NOT NULL UNIQUE,
+   -- When the email was last handed to the provider
+   attempted_at timestamptz NOT NULL,
+   -- Set as soon as the provider accepts the email
+   accepted_at timestamptz NULL,
+   message_id TEXT NULL,
+   PRIMARY KEY(task_id)
+);
@@ -0,0 +1,21 @@
+-- Add migration script here
+-- Tasks point at the subscriber rather than carrying a copy of their email address:
+-- the worker reads the address (and whether they can still be emailed) at send time.
+BEGIN;
+    ALTER TABLE issue_delivery_queue ADD COLUMN subscriber_id uuid NULL
+        REFERENCES subscriptions (id) ON DELETE CASCADE;
+    UPDATE issue_delivery_queue
+    SET subscriber_id = subscriptions.id
+    FROM subscriptions
+    WHERE subscriptions.email = issue_delivery_queue.subscriber_email;
+    -- Nobody to deliver these to anymore
+    DELETE FROM issue_delivery_queue WHERE subscriber_id IS NULL;
+    ALTER TABLE issue_delivery_queue ALTER COLUMN subscriber_id SET NOT NULL;
+    ALTER TABLE issue_delivery_queue DROP CONSTRAINT issue_delivery_queue_issue_email_key;
+    ALTER TABLE issue_delivery_queue DROP CONSTRAINT issue_delivery_queue_step_email_key;
+    ALTER TABLE issue_delivery_queue DROP COLUMN subscriber_email;
+    ALTER TABLE issue_delivery_queue ADD CONSTRAINT issue_delivery_queue_issue_subscriber_key
+        UNIQUE (newsletter_issue_id, subscriber_id);
+    ALTER TABLE issue_delivery_queue ADD CONSTRAINT issue_delivery_queue_step_subscriber_key
+        UNIQUE (onboarding_step_id, subscriber_id);
+COMMIT;
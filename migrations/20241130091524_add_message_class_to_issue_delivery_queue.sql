@@ -0,0 +1,15 @@
+-- Add migration script here
+-- Deliveries are served by priority: transactional emails first, then scheduled ones
+-- (e.g. onboarding steps), then bulk sends (newsletter issues).
+BEGIN;
+    ALTER TABLE issue_delivery_queue ADD COLUMN message_class TEXT NULL
+        CHECK (message_class IN ('transactional', 'scheduled', 'bulk'));
+    UPDATE issue_delivery_queue
+    SET message_class = CASE
+        WHEN onboarding_step_id IS NOT NULL THEN 'scheduled'
+        ELSE 'bulk'
+    END;
+    ALTER TABLE issue_delivery_queue ALTER COLUMN message_class SET NOT NULL;
+    CREATE INDEX issue_delivery_queue_message_class_idx
+        ON issue_delivery_queue (message_class, execute_after);
+COMMIT;
@@ -0,0 +1,24 @@
+-- Add migration script here
+-- Background jobs of any kind: the payload is the job serialized as JSON,
+-- `job_type` tells which handler it goes to.
+CREATE TABLE jobs (
+   job_id BIGSERIAL PRIMARY KEY,
+   job_type TEXT NOT NULL,
+   payload JSONB NOT NULL,
+   -- At most one job with the same key (and type) is waiting or running at any time.
+   unique_key TEXT NULL,
+   state TEXT NOT NULL
+     CHECK (state IN ('queued', 'running', 'succeeded', 'failed')),
+   -- Not picked up by a runner before this point in time
+   run_at timestamptz NOT NULL,
+   n_attempts SMALLINT NOT NULL DEFAULT 0,
+   max_attempts SMALLINT NOT NULL,
+   -- A running job whose runner has not finished it by then is assumed to be lost.
+   locked_until timestamptz NULL,
+   last_error TEXT NULL,
+   created_at timestamptz NOT NULL,
+   finished_at timestamptz NULL
+);
+CREATE UNIQUE INDEX jobs_unique_key_idx ON jobs (job_type, unique_key)
+   WHERE state IN ('queued', 'running');
+CREATE INDEX jobs_run_at_idx ON jobs (run_at) WHERE state IN ('queued', 'running');
@@ -0,0 +1,12 @@
+-- Add migration script here
+-- Idempotency keys are scoped to whoever sent the request: a user (`user:<user_id>`)
+-- or an API client (`api_client:<name>`), rather than always to a user.
+ALTER TABLE idempotency ADD COLUMN scope TEXT NULL;
+UPDATE idempotency SET scope = 'user:' || user_id;
+ALTER TABLE idempotency ALTER COLUMN scope SET NOT NULL;
+ALTER TABLE idempotency DROP CONSTRAINT idempotency_pkey;
+ALTER TABLE idempotency DROP COLUMN user_id;
+ALTER TABLE idempotency ADD PRIMARY KEY (scope, idempotency_key);
+-- SHA-256 of the request a key was first used with: reusing the key for a different request
+-- is rejected. NULL for responses saved before fingerprints were recorded.
+ALTER TABLE idempotency ADD COLUMN request_fingerprint BYTEA NULL;
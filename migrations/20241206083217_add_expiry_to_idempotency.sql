@@ -0,0 +1,6 @@
+-- Add migration script here
+-- Past this point a key can be used again for a new request, and its saved response is purged.
+ALTER TABLE idempotency ADD COLUMN expires_at timestamptz NULL;
+UPDATE idempotency SET expires_at = created_at + interval '24 hours';
+ALTER TABLE idempotency ALTER COLUMN expires_at SET NOT NULL;
+CREATE INDEX idempotency_expires_at_idx ON idempotency (expires_at);
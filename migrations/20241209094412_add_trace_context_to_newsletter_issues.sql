@@ -0,0 +1,4 @@
+-- Add migration script here
+-- W3C trace context (`traceparent`, `tracestate`) of the request that published the issue:
+-- the spans delivering it link back to that request.
+ALTER TABLE newsletter_issues ADD COLUMN trace_context JSONB NULL;
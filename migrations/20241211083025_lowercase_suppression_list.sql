@@ -0,0 +1,11 @@
+-- Add migration script here
+-- Addresses are suppressed regardless of case: they are stored lowercased.
+DELETE FROM suppression_list
+WHERE ctid NOT IN (
+    SELECT DISTINCT ON (lower(email)) ctid
+    FROM suppression_list
+    ORDER BY lower(email), suppressed_at
+);
+UPDATE suppression_list SET email = lower(email) WHERE email <> lower(email);
+ALTER TABLE suppression_list ADD CONSTRAINT suppression_list_email_lowercase_check
+    CHECK (email = lower(email));
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- The provider is searched a few times before we conclude it never received an email:
+-- it may not be searchable right away.
+-- How many searches came back empty since the email was last handed to the provider
+ALTER TABLE send_intents ADD COLUMN n_searches SMALLINT NOT NULL DEFAULT 0;
+ALTER TABLE send_intents ADD COLUMN searched_at timestamptz NULL;
@@ -0,0 +1,5 @@
+-- Add migration script here
+-- Transactional emails go through the email outbox, never through the delivery queue.
+ALTER TABLE issue_delivery_queue DROP CONSTRAINT issue_delivery_queue_message_class_check;
+ALTER TABLE issue_delivery_queue ADD CONSTRAINT issue_delivery_queue_message_class_check
+    CHECK (message_class IN ('scheduled', 'bulk'));
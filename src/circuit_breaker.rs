@@ -0,0 +1,195 @@
+use crate::configuration::CircuitBreakerSettings;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Stops calling a failing dependency for a while, rather than waiting on it every time.
+///
+/// While closed, calls go through. After `failure_threshold` consecutive failures the circuit
+/// opens: calls are refused right away for `open_duration`. Then a single probe call is let
+/// through: its outcome closes the circuit, or opens it again for another `open_duration`.
+///
+/// Clones share their state, which is local to the process.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+enum State {
+    Closed { consecutive_failures: u32 },
+    Open { until: Instant },
+    // A probe call is in flight
+    HalfOpen { probe_started_at: Instant },
+}
+
+impl CircuitBreaker {
+    pub fn new(settings: &CircuitBreakerSettings) -> Self {
+        Self {
+            failure_threshold: settings.failure_threshold.max(1),
+            open_duration: settings.open_duration(),
+            state: Arc::new(Mutex::new(State::Closed {
+                consecutive_failures: 0,
+            })),
+        }
+    }
+
+    /// A closed circuit with the same settings, not sharing its state with `self`.
+    pub fn fresh(&self) -> Self {
+        Self {
+            failure_threshold: self.failure_threshold,
+            open_duration: self.open_duration,
+            state: Arc::new(Mutex::new(State::Closed {
+                consecutive_failures: 0,
+            })),
+        }
+    }
+
+    /// Whether a call may go through now. If not, returns how long until it is worth asking again.
+    ///
+    /// Every call let through must report its outcome with `record_success` or `record_failure`.
+    pub fn try_acquire(&self) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now < until => Err(until - now),
+            // A probe that never reported back (e.g. its future was dropped) doesn't
+            // keep the circuit half-open forever.
+            State::HalfOpen { probe_started_at }
+                if now.duration_since(probe_started_at) < self.open_duration =>
+            {
+                Err(self.open_duration - now.duration_since(probe_started_at))
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                tracing::info!("Probing a dependency behind an open circuit");
+                *state = State::HalfOpen {
+                    probe_started_at: now,
+                };
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            tracing::info!("Closing the circuit: the dependency is back");
+        }
+        *state = State::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let consecutive_failures = match *state {
+            State::Closed {
+                consecutive_failures,
+            } => consecutive_failures + 1,
+            // The probe failed
+            State::HalfOpen { .. } => self.failure_threshold,
+            // A call let through before the circuit opened
+            State::Open { .. } => return,
+        };
+        *state = if consecutive_failures >= self.failure_threshold {
+            tracing::warn!(
+                consecutive_failures,
+                "Opening the circuit for {:?}: the dependency keeps failing",
+                self.open_duration
+            );
+            State::Open {
+                until: Instant::now() + self.open_duration,
+            }
+        } else {
+            State::Closed {
+                consecutive_failures,
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::circuit_breaker::CircuitBreaker;
+    use crate::configuration::CircuitBreakerSettings;
+    use claims::{assert_err, assert_ok};
+    use std::time::Duration;
+
+    fn circuit_breaker() -> CircuitBreaker {
+        CircuitBreaker::new(&CircuitBreakerSettings {
+            failure_threshold: 3,
+            open_seconds: 30,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_circuit_opens_after_consecutive_failures() {
+        let breaker = circuit_breaker();
+        for _ in 0..2 {
+            assert_ok!(breaker.try_acquire());
+            breaker.record_failure();
+        }
+        assert_ok!(breaker.try_acquire());
+        breaker.record_failure();
+
+        let retry_in = assert_err!(breaker.try_acquire());
+        assert_eq!(retry_in, Duration::from_secs(30));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_success_resets_the_failure_count() {
+        let breaker = circuit_breaker();
+        breaker.record_failure();
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+
+        assert_ok!(breaker.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_single_probe_is_let_through_once_the_circuit_has_been_open_long_enough() {
+        let breaker = circuit_breaker();
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        assert_ok!(breaker.try_acquire());
+        assert_err!(breaker.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_successful_probe_closes_the_circuit() {
+        let breaker = circuit_breaker();
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_ok!(breaker.try_acquire());
+
+        breaker.record_success();
+
+        assert_ok!(breaker.try_acquire());
+        assert_ok!(breaker.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failed_probe_opens_the_circuit_again() {
+        let breaker = circuit_breaker();
+        for _ in 0..3 {
+            breaker.record_failure();
+        }
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert_ok!(breaker.try_acquire());
+
+        breaker.record_failure();
+
+        let retry_in = assert_err!(breaker.try_acquire());
+        assert_eq!(retry_in, Duration::from_secs(30));
+    }
+}
@@ -1,11 +1,14 @@
 //! src/configuration.rs
 
 use crate::domain::SubscriberEmail;
-use crate::email_client::EmailClient;
+use crate::email_client::{EmailClient, Sandbox};
 use secrecy::{ExposeSecret, Secret};
-use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_aux::field_attributes::{
+    deserialize_number_from_string, deserialize_option_number_from_string,
+};
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use sqlx::ConnectOptions;
+use std::net::IpAddr;
 
 #[derive(serde::Deserialize, Clone)]
 pub struct Settings {
@@ -13,6 +16,213 @@ pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub consent: ConsentSettings,
+    pub delivery_worker: DeliveryWorkerSettings,
+    pub email_webhooks: EmailWebhookSettings,
+    pub idempotency: IdempotencySettings,
+    pub shutdown: ShutdownSettings,
+}
+
+/// What a `worker` process needs to run: it serves no API, hence no Redis nor HMAC secret.
+///
+/// Read from the same files and environment variables as `Settings`.
+#[derive(serde::Deserialize, Clone)]
+pub struct WorkerSettings {
+    pub database: DatabaseSettings,
+    pub email_client: EmailClientSettings,
+    pub delivery_worker: DeliveryWorkerSettings,
+    pub jobs: JobSettings,
+    pub worker: WorkerApplicationSettings,
+    pub shutdown: ShutdownSettings,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct WorkerApplicationSettings {
+    // Where a standalone worker answers health checks
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub host: String,
+    // How long a worker loop may go without beating before the health checks fail
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub stall_timeout_seconds: u64,
+}
+
+impl WorkerApplicationSettings {
+    pub fn stall_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.stall_timeout_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct JobSettings {
+    // Idle job runners are woken up when a job is enqueued, but they still check the `jobs`
+    // table this often in case a notification went missing.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub poll_interval_seconds: u64,
+}
+
+impl JobSettings {
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.poll_interval_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ShutdownSettings {
+    // How long in-flight requests and batches get to finish once we are asked to stop
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub timeout_seconds: u64,
+}
+
+impl ShutdownSettings {
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct IdempotencySettings {
+    // Responses are stored whole to be replayed on retries: larger ones fail the request instead
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_response_body_bytes: usize,
+    // How long a key is honoured: after that it is free to be used for a new request
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub retention_hours: u64,
+}
+
+impl IdempotencySettings {
+    pub fn retention(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.retention_hours * 60 * 60)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct TelemetrySettings {
+    // Where spans are exported with OTLP over HTTP, e.g. `http://localhost:4318/v1/traces`.
+    // They only end up in the logs when it is unset.
+    pub otlp_endpoint: Option<String>,
+    // Share of the traces we start that are exported, between 0 and 1.
+    // Requests coming with a trace context follow the caller's decision instead.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub sampling_ratio: f64,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct EmailWebhookSettings {
+    // Basic auth credentials the email provider must send along with its webhooks
+    pub username: String,
+    pub password: Secret<String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DeliveryWorkerSettings {
+    // How many tasks are claimed from the queue in a single transaction
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub batch_size: u16,
+    // How many emails of a batch are sent at the same time
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub concurrency: u16,
+    // Idle workers are woken up by producers, but they still check their queue
+    // this often in case a notification went missing.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub poll_interval_seconds: u64,
+    #[serde(default)]
+    pub rate_limit: SendRateSettings,
+    #[serde(default)]
+    pub lanes: LaneSettings,
+    #[serde(default)]
+    pub send_intents: SendIntentSettings,
+}
+
+/// How the provider is asked about emails a crashed worker may have handed over already.
+#[derive(serde::Deserialize, Clone)]
+pub struct SendIntentSettings {
+    // Emails are not searchable right away: wait this long after handing one over,
+    // and in between two searches.
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub search_delay_seconds: u64,
+    // Empty searches before we conclude the provider never received the email
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub search_attempts: i16,
+}
+
+impl Default for SendIntentSettings {
+    fn default() -> Self {
+        Self {
+            search_delay_seconds: 60,
+            search_attempts: 3,
+        }
+    }
+}
+
+impl SendIntentSettings {
+    pub fn search_delay(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.search_delay_seconds)
+    }
+}
+
+/// How each batch is shared between the classes of email, while they all have work waiting.
+///
+/// Shares are relative to each other. What a class does not use goes to the others,
+/// highest priority first.
+#[derive(serde::Deserialize, Clone)]
+pub struct LaneSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub scheduled: u16,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub bulk: u16,
+}
+
+impl Default for LaneSettings {
+    fn default() -> Self {
+        Self {
+            scheduled: 30,
+            bulk: 20,
+        }
+    }
+}
+
+/// How fast the delivery workers may send, all instances taken together.
+/// A missing limit means no limit.
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct SendRateSettings {
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub messages_per_second: Option<u32>,
+    // Applies to every recipient domain that is not listed in `domains`
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub per_domain_messages_per_second: Option<u32>,
+    #[serde(default)]
+    pub domains: Vec<DomainRateSettings>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct DomainRateSettings {
+    pub domain: String,
+    pub messages_per_second: u32,
+}
+
+impl SendRateSettings {
+    /// The limit for emails sent to `domain`, if any.
+    pub fn domain_limit(&self, domain: &str) -> Option<u32> {
+        self.domains
+            .iter()
+            .find(|settings| settings.domain.eq_ignore_ascii_case(domain))
+            .map(|settings| settings.messages_per_second)
+            .or(self.per_domain_messages_per_second)
+    }
+}
+
+impl DeliveryWorkerSettings {
+    pub fn poll_interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.poll_interval_seconds)
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct ConsentSettings {
+    // Version of the consent wording shown next to the signup form.
+    // Bump it whenever the wording changes.
+    pub text_version: String,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -21,6 +231,79 @@ pub struct EmailClientSettings {
     pub sender_email: String,
     timeout_milliseconds: u64,
     pub authorization_token: Secret<String>,
+    // Shared by the primary and the secondary provider, each with its own circuit
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerSettings,
+    // Transactional emails go through it while the primary provider is down
+    pub secondary: Option<SecondaryEmailProviderSettings>,
+    // Required outside of production, so that a copy of the production database never gets
+    // real subscribers emailed.
+    pub sandbox: Option<SandboxSettings>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SandboxSettings {
+    pub mode: SandboxMode,
+    // Addresses (or `@domain`s) still emailed in `redirect` mode
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+    // Where the emails to everyone else go in `redirect` mode. Without it they are only logged.
+    pub catch_all: Option<String>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxMode {
+    Redirect,
+    LogOnly,
+}
+
+impl SandboxSettings {
+    pub fn sandbox(&self) -> Result<Sandbox, String> {
+        match self.mode {
+            SandboxMode::LogOnly => Ok(Sandbox::LogOnly),
+            SandboxMode::Redirect => Ok(Sandbox::Redirect {
+                allowlist: self.allowlist.clone(),
+                catch_all: self
+                    .catch_all
+                    .clone()
+                    .map(SubscriberEmail::parse)
+                    .transpose()?,
+            }),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct SecondaryEmailProviderSettings {
+    // The provider must speak Postmark's API, with the same sender signature
+    pub base_url: String,
+    pub authorization_token: Secret<String>,
+}
+
+#[derive(serde::Deserialize, Clone)]
+pub struct CircuitBreakerSettings {
+    // Consecutive transient failures before we stop calling the provider
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub failure_threshold: u32,
+    // How long we stop calling it before probing it again
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub open_seconds: u64,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_seconds: 30,
+        }
+    }
+}
+
+impl CircuitBreakerSettings {
+    pub fn open_duration(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.open_seconds)
+    }
 }
 
 impl EmailClientSettings {
@@ -35,12 +318,38 @@ impl EmailClientSettings {
     pub fn client(self) -> EmailClient {
         let sender_email = self.sender().expect("Invalid sender email address");
         let timeout = self.timeout();
-        EmailClient::new(
+        let client = EmailClient::new(
             self.base_url,
             sender_email,
             self.authorization_token,
             timeout,
         )
+        .with_circuit_breaker(&self.circuit_breaker);
+        let client = match self.secondary {
+            Some(secondary) => {
+                client.with_secondary(secondary.base_url, secondary.authorization_token)
+            }
+            None => client,
+        };
+        match self.sandbox {
+            Some(sandbox) => client.with_sandbox(
+                sandbox
+                    .sandbox()
+                    .expect("Invalid sandbox catch-all address"),
+            ),
+            None => client,
+        }
+    }
+
+    /// Outside of production, emails must go through the sandbox.
+    fn check_sandbox(&self, environment: &Environment) -> Result<(), config::ConfigError> {
+        if self.sandbox.is_some() || matches!(environment, Environment::Production) {
+            return Ok(());
+        }
+        Err(config::ConfigError::Message(format!(
+            "The email sandbox is required in the `{}` environment: set `email_client.sandbox`",
+            environment.as_str()
+        )))
     }
 }
 
@@ -89,27 +398,49 @@ impl DatabaseSettings {
 pub struct ApplicationSettings {
     #[serde(deserialize_with = "deserialize_number_from_string")]
     pub port: u16,
+    // Where `GET /metrics` is served, apart from the API
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub metrics_port: u16,
     pub host: String,
     pub base_url: String,
     pub hmac_secret: Secret<String>,
+    // Reverse proxies allowed to tell us the client's address through `X-Forwarded-For`
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 pub fn get_configuration() -> Result<Settings, config::ConfigError> {
+    // Try to convert the configuration values it read into
+    // our Settings type
+    let settings = read_configuration()?.try_deserialize::<Settings>()?;
+    settings.email_client.check_sandbox(&environment())?;
+    Ok(settings)
+}
+
+/// The configuration of a standalone worker. Unlike `get_configuration`, it does not
+/// fail when the API-only settings (Redis, HMAC secret...) are missing.
+pub fn get_worker_configuration() -> Result<WorkerSettings, config::ConfigError> {
+    let settings = read_configuration()?.try_deserialize::<WorkerSettings>()?;
+    settings.email_client.check_sandbox(&environment())?;
+    Ok(settings)
+}
+
+/// How spans are exported. It is read on its own, before the subscriber is set up:
+/// the API and the workers share it.
+pub fn get_telemetry_configuration() -> Result<TelemetrySettings, config::ConfigError> {
+    read_configuration()?.get::<TelemetrySettings>("telemetry")
+}
+
+fn read_configuration() -> Result<config::Config, config::ConfigError> {
     let base_path = std::env::current_dir().expect("failed to determine current dir");
 
     let configuration_dir = base_path.join("configuration");
 
-    // Detect the running environment.
-    // Default to `local` if unspecified.
-    let environment: Environment = std::env::var("APP_ENVIRONMENT")
-        .unwrap_or_else(|_| "local".into())
-        .try_into()
-        .expect("Failed to parse APP_ENVIRONMENT.");
-
+    let environment = environment();
     let environment_filename = format!("{}.yaml", environment.as_str());
 
     // Initialise our configuration reader
-    let settings = config::Config::builder()
+    config::Config::builder()
         // Add configuration values from a file named `configuration.yaml`.
         .add_source(config::File::from(configuration_dir.join("base.yaml")))
         .add_source(config::File::from(
@@ -121,13 +452,22 @@ pub fn get_configuration() -> Result<Settings, config::ConfigError> {
         .add_source(
             config::Environment::with_prefix("APP")
                 .prefix_separator("_")
-                .separator("__"),
+                .separator("__")
+                // E.g. `APP_APPLICATION__TRUSTED_PROXIES=10.0.0.1,10.0.0.2`
+                .list_separator(",")
+                .with_list_parse_key("application.trusted_proxies")
+                .with_list_parse_key("email_client.sandbox.allowlist"),
         )
-        .build()?;
+        .build()
+}
 
-    // Try to convert the configuration values it read into
-    // our Settings type
-    settings.try_deserialize::<Settings>()
+/// Detect the running environment.
+/// Default to `local` if unspecified.
+fn environment() -> Environment {
+    std::env::var("APP_ENVIRONMENT")
+        .unwrap_or_else(|_| "local".into())
+        .try_into()
+        .expect("Failed to parse APP_ENVIRONMENT.")
 }
 
 /// The possible runtime environment for our application.
@@ -0,0 +1,118 @@
+use crate::configuration::ConsentSettings;
+use crate::startup::TrustedProxies;
+use crate::utils::client_ip;
+use actix_web::http::header::USER_AGENT;
+use actix_web::HttpRequest;
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+// Free-form values coming from the client are capped before being stored.
+const MAX_FIELD_LENGTH: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsentEvent {
+    Subscribed,
+    Confirmed,
+}
+
+impl ConsentEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConsentEvent::Subscribed => "subscribed",
+            ConsentEvent::Confirmed => "confirmed",
+        }
+    }
+}
+
+/// An entry of the consent ledger: when, where and how a subscriber agreed to receive emails.
+pub struct ConsentRecord {
+    pub event: String,
+    pub source: String,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub consent_text_version: String,
+    pub recorded_at: DateTime<Utc>,
+}
+
+impl ConsentRecord {
+    /// Capture the consent given through `request`.
+    pub fn from_request(
+        event: ConsentEvent,
+        source: &str,
+        request: &HttpRequest,
+        settings: &ConsentSettings,
+        trusted_proxies: &TrustedProxies,
+    ) -> Self {
+        let user_agent = request
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(truncate);
+        Self {
+            event: event.as_str().to_string(),
+            source: truncate(source),
+            ip_address: client_ip(request, &trusted_proxies.0).map(|ip| ip.to_string()),
+            user_agent,
+            consent_text_version: settings.text_version.clone(),
+            recorded_at: Utc::now(),
+        }
+    }
+}
+
+fn truncate(value: &str) -> String {
+    value.chars().take(MAX_FIELD_LENGTH).collect()
+}
+
+#[tracing::instrument(skip(transaction, record), fields(event = %record.event))]
+pub async fn record_consent(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    record: &ConsentRecord,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO consent_records (
+            consent_record_id,
+            subscriber_id,
+            event,
+            source,
+            ip_address,
+            user_agent,
+            consent_text_version,
+            recorded_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        record.event,
+        record.source,
+        record.ip_address,
+        record.user_agent,
+        record.consent_text_version,
+        record.recorded_at,
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// The consent ledger of a subscriber, oldest entry first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_consent_records(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Vec<ConsentRecord>, sqlx::Error> {
+    sqlx::query_as!(
+        ConsentRecord,
+        r#"
+        SELECT event, source, ip_address, user_agent, consent_text_version, recorded_at
+        FROM consent_records
+        WHERE subscriber_id = $1
+        ORDER BY recorded_at
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(pool)
+    .await
+}
@@ -1,7 +1,11 @@
 pub use new_subscriber::NewSubscriber;
+pub use subscriber_attribute::{
+    parse_attributes, AttributeDefinition, AttributeKind, AttributeValue, NewAttributeDefinition,
+};
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
 
 mod new_subscriber;
+mod subscriber_attribute;
 mod subscriber_email;
 mod subscriber_name;
@@ -0,0 +1,341 @@
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+use uuid::Uuid;
+
+const MAX_TEXT_LENGTH: usize = 1024;
+// Fields of the signup form that are not custom attributes.
+const RESERVED_KEYS: [&str; 3] = ["name", "email", "source"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttributeKind {
+    Text,
+    Number,
+    Date,
+    Boolean,
+    Enum,
+}
+
+impl AttributeKind {
+    pub fn parse(s: &str) -> Result<AttributeKind, String> {
+        match s {
+            "text" => Ok(Self::Text),
+            "number" => Ok(Self::Number),
+            "date" => Ok(Self::Date),
+            "boolean" => Ok(Self::Boolean),
+            "enum" => Ok(Self::Enum),
+            other => Err(format!("{} is not a supported attribute kind.", other)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Text => "text",
+            Self::Number => "number",
+            Self::Date => "date",
+            Self::Boolean => "boolean",
+            Self::Enum => "enum",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Text(String),
+    Number(f64),
+    Date(NaiveDate),
+    Boolean(bool),
+    // One of the options of the attribute
+    Enum(String),
+}
+
+impl AttributeValue {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Self::Text(s) | Self::Enum(s) => serde_json::Value::from(s.as_str()),
+            Self::Number(n) => serde_json::Value::from(*n),
+            Self::Date(d) => serde_json::Value::from(d.to_string()),
+            Self::Boolean(b) => serde_json::Value::from(*b),
+        }
+    }
+}
+
+impl std::fmt::Display for AttributeValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Text(s) | Self::Enum(s) => write!(f, "{}", s),
+            Self::Number(n) => write!(f, "{}", n),
+            // ISO 8601, the format expected by `<input type="date">`
+            Self::Date(d) => write!(f, "{}", d.format("%Y-%m-%d")),
+            Self::Boolean(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+/// An extra field admins asked subscribers for.
+#[derive(Debug, Clone)]
+pub struct AttributeDefinition {
+    pub attribute_id: Uuid,
+    pub key: String,
+    pub label: String,
+    pub kind: AttributeKind,
+    pub options: Vec<String>,
+    pub required: bool,
+}
+
+impl AttributeDefinition {
+    /// Validate the raw value submitted for this attribute.
+    /// Returns `None` if no value was provided and the attribute is optional.
+    pub fn parse_value(&self, raw: Option<&str>) -> Result<Option<AttributeValue>, String> {
+        let raw = match raw.map(str::trim) {
+            Some(raw) if !raw.is_empty() => raw,
+            _ if self.required => return Err(format!("{} is required.", self.label)),
+            _ => return Ok(None),
+        };
+        let invalid = || format!("{} is not a valid value for {}.", raw, self.label);
+        let value = match self.kind {
+            AttributeKind::Text => {
+                if raw.graphemes(true).count() > MAX_TEXT_LENGTH {
+                    return Err(format!("{} is too long.", self.label));
+                }
+                AttributeValue::Text(raw.to_string())
+            }
+            AttributeKind::Number => match raw.parse::<f64>() {
+                Ok(n) if n.is_finite() => AttributeValue::Number(n),
+                _ => return Err(invalid()),
+            },
+            AttributeKind::Date => AttributeValue::Date(
+                NaiveDate::parse_from_str(raw, "%Y-%m-%d").map_err(|_| invalid())?,
+            ),
+            AttributeKind::Boolean => match raw.to_lowercase().as_str() {
+                // `on` is what browsers submit for a ticked checkbox
+                "true" | "on" | "yes" | "1" => AttributeValue::Boolean(true),
+                "false" | "off" | "no" | "0" => AttributeValue::Boolean(false),
+                _ => return Err(invalid()),
+            },
+            AttributeKind::Enum => {
+                if !self.options.iter().any(|option| option == raw) {
+                    return Err(invalid());
+                }
+                AttributeValue::Enum(raw.to_string())
+            }
+        };
+        Ok(Some(value))
+    }
+}
+
+/// Validate the values submitted for every attribute in `definitions`.
+/// Fields that do not match any attribute are ignored.
+pub fn parse_attributes(
+    definitions: &[AttributeDefinition],
+    raw: &HashMap<String, String>,
+) -> Result<Vec<(Uuid, Option<AttributeValue>)>, String> {
+    definitions
+        .iter()
+        .map(|definition| {
+            let value = definition.parse_value(raw.get(&definition.key).map(String::as_str))?;
+            Ok((definition.attribute_id, value))
+        })
+        .collect()
+}
+
+/// An attribute an admin wants to add.
+#[derive(Debug)]
+pub struct NewAttributeDefinition {
+    pub key: String,
+    pub label: String,
+    pub kind: AttributeKind,
+    pub options: Vec<String>,
+    pub required: bool,
+}
+
+impl NewAttributeDefinition {
+    /// `options` is a comma-separated list, only used by `enum` attributes.
+    pub fn parse(
+        key: String,
+        label: String,
+        kind: &str,
+        options: &str,
+        required: bool,
+    ) -> Result<NewAttributeDefinition, String> {
+        // Keys end up as form field names and CSV headers: keep them simple.
+        let is_valid_key = key.len() <= 64
+            && key.starts_with(|c: char| c.is_ascii_lowercase())
+            && key
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !is_valid_key || RESERVED_KEYS.contains(&key.as_str()) {
+            return Err(format!("{} is not a valid attribute key.", key));
+        }
+        let label = label.trim().to_string();
+        if label.is_empty() || label.graphemes(true).count() > 256 {
+            return Err(format!("{} is not a valid attribute label.", label));
+        }
+        let kind = AttributeKind::parse(kind)?;
+        let options = match kind {
+            AttributeKind::Enum => {
+                let options: Vec<String> = options
+                    .split(',')
+                    .map(|option| option.trim().to_string())
+                    .filter(|option| !option.is_empty())
+                    .collect();
+                if options.is_empty() {
+                    return Err("An enum attribute needs at least one option.".into());
+                }
+                options
+            }
+            _ => vec![],
+        };
+        Ok(Self {
+            key,
+            label,
+            kind,
+            options,
+            required,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::domain::subscriber_attribute::{
+        parse_attributes, AttributeDefinition, AttributeKind, AttributeValue,
+        NewAttributeDefinition,
+    };
+    use chrono::NaiveDate;
+    use claims::{assert_err, assert_none, assert_ok};
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    fn definition(kind: AttributeKind, required: bool) -> AttributeDefinition {
+        AttributeDefinition {
+            attribute_id: Uuid::new_v4(),
+            key: "field".into(),
+            label: "Field".into(),
+            kind,
+            options: vec!["small".into(), "large".into()],
+            required,
+        }
+    }
+
+    #[test]
+    fn a_missing_optional_value_is_none() {
+        let definition = definition(AttributeKind::Text, false);
+        assert_none!(definition.parse_value(None).unwrap());
+        assert_none!(definition.parse_value(Some("  ")).unwrap());
+    }
+
+    #[test]
+    fn a_missing_required_value_is_rejected() {
+        let definition = definition(AttributeKind::Text, true);
+        assert_err!(definition.parse_value(None));
+        assert_err!(definition.parse_value(Some("")));
+    }
+
+    #[test]
+    fn values_are_parsed_according_to_their_kind() {
+        let cases = [
+            (
+                AttributeKind::Text,
+                "hello",
+                AttributeValue::Text("hello".into()),
+            ),
+            (AttributeKind::Number, "4.5", AttributeValue::Number(4.5)),
+            (
+                AttributeKind::Date,
+                "2024-11-16",
+                AttributeValue::Date(NaiveDate::from_ymd_opt(2024, 11, 16).unwrap()),
+            ),
+            (AttributeKind::Boolean, "on", AttributeValue::Boolean(true)),
+            (
+                AttributeKind::Boolean,
+                "false",
+                AttributeValue::Boolean(false),
+            ),
+            (
+                AttributeKind::Enum,
+                "large",
+                AttributeValue::Enum("large".into()),
+            ),
+        ];
+        for (kind, raw, expected) in cases {
+            let value = definition(kind, true).parse_value(Some(raw)).unwrap();
+            assert_eq!(value, Some(expected));
+        }
+    }
+
+    #[test]
+    fn values_of_the_wrong_kind_are_rejected() {
+        let cases = [
+            (AttributeKind::Number, "four"),
+            (AttributeKind::Number, "NaN"),
+            (AttributeKind::Date, "16/11/2024"),
+            (AttributeKind::Boolean, "maybe"),
+            (AttributeKind::Enum, "medium"),
+        ];
+        for (kind, raw) in cases {
+            assert_err!(definition(kind, true).parse_value(Some(raw)));
+        }
+    }
+
+    #[test]
+    fn an_overly_long_text_is_rejected() {
+        let raw = "a".repeat(1025);
+        assert_err!(definition(AttributeKind::Text, true).parse_value(Some(&raw)));
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let definitions = [definition(AttributeKind::Number, false)];
+        let raw = HashMap::from([
+            ("field".to_string(), "3".to_string()),
+            ("unknown".to_string(), "whatever".to_string()),
+        ]);
+        let values = parse_attributes(&definitions, &raw).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].1, Some(AttributeValue::Number(3.0)));
+    }
+
+    #[test]
+    fn a_valid_definition_is_accepted() {
+        assert_ok!(NewAttributeDefinition::parse(
+            "company_size".into(),
+            "Company size".into(),
+            "enum",
+            "small, large",
+            false,
+        ));
+    }
+
+    #[test]
+    fn invalid_or_reserved_keys_are_rejected() {
+        for key in [
+            "",
+            "Company",
+            "1st",
+            "with space",
+            "email",
+            "name",
+            "source",
+        ] {
+            assert_err!(NewAttributeDefinition::parse(
+                key.into(),
+                "Label".into(),
+                "text",
+                "",
+                false,
+            ));
+        }
+    }
+
+    #[test]
+    fn an_enum_without_options_is_rejected() {
+        assert_err!(NewAttributeDefinition::parse(
+            "size".into(),
+            "Size".into(),
+            "enum",
+            " , ",
+            false,
+        ));
+    }
+}
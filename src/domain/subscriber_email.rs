@@ -22,6 +22,14 @@ impl SubscriberEmail {
         }
         Ok(email)
     }
+
+    /// The part after the `@`, lowercased: mailbox providers don't care about case.
+    pub fn domain(&self) -> String {
+        self.mail
+            .rsplit_once('@')
+            .map(|(_, domain)| domain.to_lowercase())
+            .unwrap_or_default()
+    }
 }
 
 impl AsRef<str> for SubscriberEmail {
@@ -61,6 +69,12 @@ mod tests {
         assert_err!(SubscriberEmail::parse(email));
     }
 
+    #[test]
+    fn domain_is_lowercased() {
+        let email = SubscriberEmail::parse("Ursula@GMail.com".to_string()).unwrap();
+        assert_eq!(email.domain(), "gmail.com");
+    }
+
     #[derive(Debug, Clone)]
     struct ValidEmailFixture(pub String);
 
@@ -1,7 +1,136 @@
-use reqwest::Client;
+use metrics::counter;
+use reqwest::{Client, StatusCode};
 use secrecy::{ExposeSecret, Secret};
+use std::borrow::Cow;
+use std::time::Duration;
+use uuid::Uuid;
 
+use crate::circuit_breaker::CircuitBreaker;
+use crate::configuration::CircuitBreakerSettings;
 use crate::domain::SubscriberEmail;
+use crate::telemetry::trace_context_headers;
+
+// Postmark error codes meaning that the recipient itself cannot receive emails.
+// https://postmarkapp.com/developer/api/overview#error-codes
+const INVALID_RECIPIENT_ERROR_CODES: [i64; 2] = [
+    300, // Invalid email request (e.g. a malformed `To` address)
+    406, // Inactive recipient (hard bounced or marked us as spam)
+];
+
+/// Why the email provider did not accept an email.
+#[derive(thiserror::Error, Debug)]
+pub enum SendEmailError {
+    /// The provider refused the email: sending it again won't help.
+    #[error("The email provider rejected the email (HTTP {status}, error code {error_code:?}): {message}")]
+    Permanent {
+        status: StatusCode,
+        error_code: Option<i64>,
+        message: String,
+    },
+    /// The provider failed to handle the email (e.g. an outage or rate limiting).
+    #[error("The email provider failed to handle the email (HTTP {status}, error code {error_code:?}): {message}")]
+    Transient {
+        status: StatusCode,
+        error_code: Option<i64>,
+        message: String,
+    },
+    /// We could not reach the provider or it did not answer in time.
+    #[error("Failed to reach the email provider")]
+    Unreachable(#[source] reqwest::Error),
+    /// We did not even try: the provider failed too often lately, see `CircuitBreaker`.
+    #[error(
+        "The {provider} email provider is unavailable, it will be probed again in {retry_in:?}"
+    )]
+    Unavailable {
+        provider: &'static str,
+        retry_in: Duration,
+    },
+    /// We did not even try: the batch holds more messages than the provider accepts.
+    #[error(
+        "The batch holds {size} messages, the email provider accepts at most {MAX_BATCH_SIZE}"
+    )]
+    BatchTooLarge { size: usize },
+}
+
+impl SendEmailError {
+    /// Whether sending the same email again later may succeed.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, Self::Permanent { .. } | Self::BatchTooLarge { .. })
+    }
+
+    /// Whether the provider refused the recipient: they should not be emailed anymore.
+    pub fn is_recipient_rejected(&self) -> bool {
+        matches!(
+            self,
+            Self::Permanent { error_code: Some(code), .. } if INVALID_RECIPIENT_ERROR_CODES.contains(code)
+        )
+    }
+
+    /// Whether the provider may have accepted the email anyway, e.g. it did not answer in time.
+    pub fn may_have_been_accepted(&self) -> bool {
+        match self {
+            Self::Permanent { status, .. } | Self::Transient { status, .. } => {
+                status.is_success() || status.is_server_error()
+            }
+            Self::Unreachable(_) => true,
+            Self::Unavailable { .. } | Self::BatchTooLarge { .. } => false,
+        }
+    }
+
+    /// Whether the provider itself is failing, as opposed to refusing this email.
+    fn is_provider_failure(&self) -> bool {
+        matches!(self, Self::Transient { .. } | Self::Unreachable(_))
+    }
+
+    /// The provider's own error code, if it sent one back.
+    pub fn error_code(&self) -> Option<i64> {
+        match self {
+            Self::Permanent { error_code, .. } | Self::Transient { error_code, .. } => *error_code,
+            Self::Unreachable(_) | Self::Unavailable { .. } | Self::BatchTooLarge { .. } => None,
+        }
+    }
+
+    /// A label for the kind of error, e.g. in metrics.
+    pub fn class(&self) -> &'static str {
+        match self {
+            Self::Permanent { .. } => "permanent",
+            Self::Transient { .. } => "transient",
+            Self::Unreachable(_) => "unreachable",
+            Self::Unavailable { .. } => "unavailable",
+            Self::BatchTooLarge { .. } => "batch_too_large",
+        }
+    }
+
+    fn from_response(status: StatusCode, body: Option<ErrorResponse>) -> Self {
+        let (error_code, message) = match body {
+            Some(body) => (Some(body.error_code), body.message),
+            None => (
+                None,
+                status.canonical_reason().unwrap_or("Unknown error").into(),
+            ),
+        };
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            Self::Transient {
+                status,
+                error_code,
+                message,
+            }
+        } else {
+            Self::Permanent {
+                status,
+                error_code,
+                message,
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorResponse {
+    error_code: i64,
+    message: String,
+}
 
 #[derive(serde::Serialize)]
 #[serde(rename_all = "PascalCase")]
@@ -11,14 +140,196 @@ struct SendEmailRequest<'a> {
     subject: &'a str,
     html_body: &'a str,
     text_body: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata>,
 }
 
+// Custom metadata attached to a message. Messages can be searched by it.
+#[derive(serde::Serialize)]
+struct Metadata {
+    delivery_key: Uuid,
+}
+
+/// The most messages Postmark accepts in a single batch request.
+pub const MAX_BATCH_SIZE: usize = 500;
+
+/// One of the messages of a batch.
+pub struct OutgoingEmail<'a> {
+    pub recipient: &'a SubscriberEmail,
+    pub subject: &'a str,
+    pub html_content: &'a str,
+    pub text_content: &'a str,
+    // Identifies the delivery for good: see `find_sent_email`
+    pub delivery_key: Option<Uuid>,
+}
+
+/// What the provider tells us about an email it accepted.
+#[derive(Debug)]
+pub struct SentEmail {
+    // The provider's id for the email, used to correlate later events (bounces, opens...)
+    // `None` if the provider did not send one back.
+    pub message_id: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailResponse {
+    // 0 when the message was accepted
+    error_code: i64,
+    message: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct OutboundMessagesResponse {
+    messages: Vec<OutboundMessage>,
+}
+
+#[derive(serde::Deserialize)]
+struct OutboundMessage {
+    #[serde(rename = "MessageID")]
+    message_id: String,
+}
+
+/// Whether an email may go through the secondary provider while the primary one is down.
+///
+/// Secondary providers are meant for transactional volumes: bulk sends wait for the primary.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Failover {
+    Allowed,
+    Forbidden,
+}
+
+/// Keeps emails away from real recipients outside of production.
+#[derive(Clone, Debug)]
+pub enum Sandbox {
+    /// Emails to addresses outside of `allowlist` go to `catch_all` instead, or are only logged.
+    ///
+    /// Allowlist entries are addresses, or domains starting with `@` (e.g. `@example.com`).
+    Redirect {
+        allowlist: Vec<String>,
+        catch_all: Option<SubscriberEmail>,
+    },
+    /// Nothing is sent: emails are only logged.
+    LogOnly,
+}
+
+impl Sandbox {
+    /// Who actually gets an email meant for `recipient`, `None` if nobody does.
+    fn recipient_for<'a>(&'a self, recipient: &'a SubscriberEmail) -> Option<&'a SubscriberEmail> {
+        match self {
+            Sandbox::LogOnly => None,
+            Sandbox::Redirect {
+                allowlist,
+                catch_all,
+            } => {
+                let address = recipient.as_ref().to_lowercase();
+                let allowed = allowlist.iter().any(|entry| {
+                    let entry = entry.to_lowercase();
+                    match entry.starts_with('@') {
+                        true => address.ends_with(&entry),
+                        false => address == entry,
+                    }
+                });
+                if allowed {
+                    Some(recipient)
+                } else {
+                    catch_all.as_ref()
+                }
+            }
+        }
+    }
+
+    /// What happens to outgoing emails, for humans.
+    pub fn describe(&self) -> String {
+        match self {
+            Sandbox::LogOnly => "no email is sent, they are only logged".into(),
+            Sandbox::Redirect {
+                allowlist,
+                catch_all: Some(catch_all),
+            } => format!(
+                "only {} get their emails, everyone else's go to {}",
+                describe_allowlist(allowlist),
+                catch_all.as_ref()
+            ),
+            Sandbox::Redirect {
+                allowlist,
+                catch_all: None,
+            } => format!(
+                "only {} get their emails, everyone else's are only logged",
+                describe_allowlist(allowlist)
+            ),
+        }
+    }
+
+    fn mode(&self) -> &'static str {
+        match self {
+            Sandbox::Redirect { .. } => "redirect",
+            Sandbox::LogOnly => "log_only",
+        }
+    }
+}
+
+fn describe_allowlist(allowlist: &[String]) -> String {
+    match allowlist {
+        [] => "nobody".into(),
+        entries => entries.join(", "),
+    }
+}
+
+// An account on a provider speaking Postmark's API.
 #[derive(Clone)]
-pub struct EmailClient {
+struct Provider {
+    name: &'static str,
     base_url: String,
+    authorization_token: Secret<String>,
+    circuit_breaker: CircuitBreaker,
+}
+
+impl Provider {
+    /// Refuse to call the provider while its circuit is open.
+    fn acquire(&self) -> Result<(), SendEmailError> {
+        self.circuit_breaker
+            .try_acquire()
+            .map_err(|retry_in| SendEmailError::Unavailable {
+                provider: self.name,
+                retry_in,
+            })
+    }
+
+    /// Let the circuit breaker know how the call we were let through went.
+    fn record<T>(&self, result: &Result<T, SendEmailError>) {
+        match result {
+            Err(e) if e.is_provider_failure() => self.circuit_breaker.record_failure(),
+            _ => self.circuit_breaker.record_success(),
+        }
+    }
+
+    /// Count how the emails handed over to the provider fared.
+    fn count_sends<'a>(&self, outcomes: impl IntoIterator<Item = Result<(), &'a SendEmailError>>) {
+        for outcome in outcomes {
+            match outcome {
+                Ok(()) => counter!("emails_sent_total", "provider" => self.name).increment(1),
+                Err(e) => counter!(
+                    "email_send_failures_total",
+                    "provider" => self.name,
+                    "error_class" => e.class()
+                )
+                .increment(1),
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EmailClient {
     http_client: Client,
     sender: SubscriberEmail,
-    authorization_token: Secret<String>,
+    primary: Provider,
+    secondary: Option<Provider>,
+    sandbox: Option<Sandbox>,
 }
 
 impl EmailClient {
@@ -31,40 +342,368 @@ impl EmailClient {
         let client = Client::builder().timeout(time_out).build().unwrap();
         Self {
             sender,
+            http_client: client,
+            primary: Provider {
+                name: "primary",
+                base_url,
+                authorization_token,
+                circuit_breaker: CircuitBreaker::new(&CircuitBreakerSettings::default()),
+            },
+            secondary: None,
+            sandbox: None,
+        }
+    }
+
+    /// Rewrite recipients, or only log emails, see `Sandbox`.
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        tracing::warn!(
+            sandbox.mode = sandbox.mode(),
+            "Sandbox mode: {}",
+            sandbox.describe()
+        );
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    pub fn sandbox(&self) -> Option<&Sandbox> {
+        self.sandbox.as_ref()
+    }
+
+    /// Who gets an email meant for `recipient`, and with which subject.
+    /// `None` if the sandbox keeps it from being sent at all.
+    fn sandboxed<'a>(
+        &'a self,
+        recipient: &'a SubscriberEmail,
+        subject: &'a str,
+        text_content: &str,
+    ) -> Option<(&'a SubscriberEmail, Cow<'a, str>)> {
+        let Some(sandbox) = &self.sandbox else {
+            return Some((recipient, Cow::Borrowed(subject)));
+        };
+        match sandbox.recipient_for(recipient) {
+            Some(to) if std::ptr::eq(to, recipient) => Some((recipient, Cow::Borrowed(subject))),
+            Some(to) => {
+                tracing::info!(
+                    sandbox.mode = sandbox.mode(),
+                    recipient = %recipient.as_ref(),
+                    redirected_to = %to.as_ref(),
+                    "Sandbox mode: redirecting an email"
+                );
+                // Tell whoever reads the catch-all inbox who the email was for.
+                let subject = format!("[Sandbox: to {}] {}", recipient.as_ref(), subject);
+                Some((to, Cow::Owned(subject)))
+            }
+            None => {
+                tracing::info!(
+                    sandbox.mode = sandbox.mode(),
+                    recipient = %recipient.as_ref(),
+                    subject,
+                    text_content,
+                    "Sandbox mode: logging an email instead of sending it"
+                );
+                None
+            }
+        }
+    }
+
+    /// Stop calling a provider that keeps failing, see `CircuitBreaker`.
+    pub fn with_circuit_breaker(mut self, settings: &CircuitBreakerSettings) -> Self {
+        self.primary.circuit_breaker = CircuitBreaker::new(settings);
+        self
+    }
+
+    /// Send the emails allowed to fail over through this provider while the primary one is down.
+    ///
+    /// Call it after `with_circuit_breaker`: the secondary provider gets a circuit of its own.
+    pub fn with_secondary(mut self, base_url: String, authorization_token: Secret<String>) -> Self {
+        self.secondary = Some(Provider {
+            name: "secondary",
             base_url,
             authorization_token,
-            http_client: client,
+            circuit_breaker: self.primary.circuit_breaker.fresh(),
+        });
+        self
+    }
+
+    /// The provider to send through right now.
+    ///
+    /// We only fail over once the primary's circuit is open: an email that failed on the
+    /// primary may have been accepted anyway, it must not go out through the secondary too.
+    fn route(&self, failover: Failover) -> Result<&Provider, SendEmailError> {
+        let unavailable = match self.primary.acquire() {
+            Ok(()) => return Ok(&self.primary),
+            Err(e) => e,
+        };
+        match &self.secondary {
+            Some(secondary) if failover == Failover::Allowed => {
+                secondary.acquire()?;
+                tracing::warn!(
+                    "The primary email provider is unavailable, failing over to the secondary one"
+                );
+                Ok(secondary)
+            }
+            _ => Err(unavailable),
         }
     }
 
+    /// Send a single email. They are transactional (e.g. confirmation emails): they fail over.
     pub async fn send_email(
         &self,
         recipient: &SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), reqwest::Error> {
-        let url = format!("{}/email", self.base_url);
-
+    ) -> Result<SentEmail, SendEmailError> {
+        let Some((recipient, subject)) = self.sandboxed(recipient, subject, text_content) else {
+            return Ok(SentEmail { message_id: None });
+        };
+        let provider = self.route(Failover::Allowed)?;
         let request_body = SendEmailRequest {
-            subject,
+            subject: &subject,
             to: recipient.as_ref(),
             html_body: html_content,
             text_body: text_content,
             from: self.sender.as_ref(),
+            metadata: None,
         };
+        let result = self.post_email(provider, &request_body).await;
+        provider.record(&result);
+        provider.count_sends([result.as_ref().map(|_| ())]);
+        result
+    }
+
+    async fn post_email(
+        &self,
+        provider: &Provider,
+        request_body: &SendEmailRequest<'_>,
+    ) -> Result<SentEmail, SendEmailError> {
+        let url = format!("{}/email", provider.base_url);
+        let response = self
+            .http_client
+            .post(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                provider.authorization_token.expose_secret(),
+            )
+            .headers(trace_context_headers())
+            .json(request_body)
+            .send()
+            .await
+            .map_err(SendEmailError::Unreachable)?;
+
+        let status = response.status();
+        if status.is_success() {
+            let message_id = response
+                .json::<SendEmailResponse>()
+                .await
+                .ok()
+                .and_then(|body| body.message_id);
+            return Ok(SentEmail { message_id });
+        }
+        // Postmark describes what went wrong in the body: keep it for the caller.
+        let body = response.json::<ErrorResponse>().await.ok();
+        Err(SendEmailError::from_response(status, body))
+    }
+
+    /// Send up to `MAX_BATCH_SIZE` emails with a single request.
+    ///
+    /// Returns an error if the request as a whole failed, or was not even made because `emails`
+    /// holds more than `MAX_BATCH_SIZE` messages. Otherwise one result per email,
+    /// in the same order as `emails`.
+    ///
+    /// It is fine with a single email: unlike `send_email`, it tags every message with its
+    /// `delivery_key`, so the delivery worker sends everything through it.
+    pub async fn send_email_batch(
+        &self,
+        emails: &[OutgoingEmail<'_>],
+        failover: Failover,
+    ) -> Result<Vec<Result<SentEmail, SendEmailError>>, SendEmailError> {
+        if emails.len() > MAX_BATCH_SIZE {
+            return Err(SendEmailError::BatchTooLarge { size: emails.len() });
+        }
+        if self.sandbox.is_none() {
+            return self.send_through(emails, failover).await;
+        }
+        // Only send what the sandbox lets through, the others count as sent.
+        let sandboxed: Vec<_> = emails
+            .iter()
+            .map(|email| self.sandboxed(email.recipient, email.subject, email.text_content))
+            .collect();
+        let to_send: Vec<_> = emails
+            .iter()
+            .zip(&sandboxed)
+            .filter_map(|(email, sandboxed)| {
+                sandboxed
+                    .as_ref()
+                    .map(|(recipient, subject)| OutgoingEmail {
+                        recipient,
+                        subject,
+                        ..*email
+                    })
+            })
+            .collect();
+        let mut sent = match to_send.is_empty() {
+            true => vec![],
+            false => self.send_through(&to_send, failover).await?,
+        }
+        .into_iter();
+        Ok(sandboxed
+            .iter()
+            .map(|sandboxed| match sandboxed {
+                Some(_) => sent.next().expect("One result per email sent"),
+                None => Ok(SentEmail { message_id: None }),
+            })
+            .collect())
+    }
+
+    async fn send_through(
+        &self,
+        emails: &[OutgoingEmail<'_>],
+        failover: Failover,
+    ) -> Result<Vec<Result<SentEmail, SendEmailError>>, SendEmailError> {
+        let provider = self.route(failover)?;
+        let result = self.post_email_batch(provider, emails).await;
+        provider.record(&result);
+        match &result {
+            Ok(results) => provider.count_sends(results.iter().map(|r| r.as_ref().map(|_| ()))),
+            Err(e) => provider.count_sends(emails.iter().map(|_| Err(e))),
+        }
+        result
+    }
 
-        self.http_client
+    async fn post_email_batch(
+        &self,
+        provider: &Provider,
+        emails: &[OutgoingEmail<'_>],
+    ) -> Result<Vec<Result<SentEmail, SendEmailError>>, SendEmailError> {
+        let url = format!("{}/email/batch", provider.base_url);
+        let request_body: Vec<_> = emails
+            .iter()
+            .map(|email| SendEmailRequest {
+                subject: email.subject,
+                to: email.recipient.as_ref(),
+                html_body: email.html_content,
+                text_body: email.text_content,
+                from: self.sender.as_ref(),
+                metadata: email
+                    .delivery_key
+                    .map(|delivery_key| Metadata { delivery_key }),
+            })
+            .collect();
+
+        let response = self
+            .http_client
             .post(&url)
             .header(
                 "X-Postmark-Server-Token",
-                self.authorization_token.expose_secret(),
+                provider.authorization_token.expose_secret(),
             )
+            .headers(trace_context_headers())
             .json(&request_body)
             .send()
-            .await?
-            .error_for_status()?;
-        Ok(())
+            .await
+            .map_err(SendEmailError::Unreachable)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.json::<ErrorResponse>().await.ok();
+            return Err(SendEmailError::from_response(status, body));
+        }
+        // The messages were handed over: if we can't make sense of the answer,
+        // report a permanent failure rather than risk sending them twice.
+        let results = match response.json::<Vec<SendEmailResponse>>().await {
+            Ok(results) if results.len() == emails.len() => results,
+            _ => {
+                return Err(SendEmailError::Permanent {
+                    status,
+                    error_code: None,
+                    message: "Unexpected response to a batch request".into(),
+                })
+            }
+        };
+        Ok(results
+            .into_iter()
+            .map(|result| match result.error_code {
+                0 => Ok(SentEmail {
+                    message_id: result.message_id,
+                }),
+                // Single messages rejected by Postmark get a 422 outside of batches.
+                error_code => Err(SendEmailError::Permanent {
+                    status: StatusCode::UNPROCESSABLE_ENTITY,
+                    error_code: Some(error_code),
+                    message: result.message,
+                }),
+            })
+            .collect())
+    }
+
+    /// Look for an email we sent earlier with `delivery_key`, `None` if the provider never got it.
+    ///
+    /// The email may have failed over: every provider is searched. Postmark takes a little
+    /// while to make new messages searchable.
+    pub async fn find_sent_email(
+        &self,
+        delivery_key: Uuid,
+    ) -> Result<Option<SentEmail>, SendEmailError> {
+        let mut error = None;
+        for provider in std::iter::once(&self.primary).chain(&self.secondary) {
+            let result = match provider.acquire() {
+                Ok(()) => {
+                    let result = self.search_sent_email(provider, delivery_key).await;
+                    provider.record(&result);
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(Some(sent)) => return Ok(Some(sent)),
+                Ok(None) => {}
+                // It may be on the provider we could not search.
+                Err(e) => error = Some(e),
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    async fn search_sent_email(
+        &self,
+        provider: &Provider,
+        delivery_key: Uuid,
+    ) -> Result<Option<SentEmail>, SendEmailError> {
+        let url = format!("{}/messages/outbound", provider.base_url);
+        let response = self
+            .http_client
+            .get(&url)
+            .header(
+                "X-Postmark-Server-Token",
+                provider.authorization_token.expose_secret(),
+            )
+            .headers(trace_context_headers())
+            .header("Accept", "application/json")
+            .query(&[
+                ("count", "1"),
+                ("offset", "0"),
+                ("metadata_delivery_key", &delivery_key.to_string()),
+            ])
+            .send()
+            .await
+            .map_err(SendEmailError::Unreachable)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.json::<ErrorResponse>().await.ok();
+            return Err(SendEmailError::from_response(status, body));
+        }
+        let body = response
+            .json::<OutboundMessagesResponse>()
+            .await
+            .map_err(SendEmailError::Unreachable)?;
+        Ok(body.messages.into_iter().next().map(|message| SentEmail {
+            message_id: Some(message.message_id),
+        }))
     }
 }
 
@@ -74,12 +713,16 @@ mod tests {
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::{Paragraph, Sentence};
     use fake::{Fake, Faker};
+    use reqwest::StatusCode;
     use secrecy::Secret;
-    use wiremock::matchers::{any, header, header_exists, method, path};
+    use wiremock::matchers::{any, header, header_exists, method, path, query_param};
     use wiremock::{Mock, MockServer, Request, ResponseTemplate};
 
+    use crate::configuration::CircuitBreakerSettings;
     use crate::domain::SubscriberEmail;
-    use crate::email_client::EmailClient;
+    use crate::email_client::{
+        EmailClient, Failover, OutgoingEmail, Sandbox, SendEmailError, SentEmail, MAX_BATCH_SIZE,
+    };
 
     /// An implementation that adds common methods for test
     impl EmailClient {
@@ -99,6 +742,7 @@ mod tests {
         }
 
         /// Create a test instance of `EmailClient`.
+        #[allow(clippy::self_named_constructors)]
         pub fn email_client(base_url: String) -> EmailClient {
             let time_out = std::time::Duration::from_millis(200);
             EmailClient::new(
@@ -147,7 +791,11 @@ mod tests {
             .and(path("/email"))
             .and(method("POST"))
             .and(SendEmailBodyMatcher)
-            .respond_with(ResponseTemplate::new(200))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ErrorCode": 0,
+                "Message": "OK",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817"
+            })))
             // We expect the mock to be called at least once.
             // If that does not happen, the `MockServer` will panic on shutdown,
             // causing the whole test to fail.
@@ -165,7 +813,11 @@ mod tests {
             .await;
 
         // Assert
-        assert_ok!(response)
+        let sent = assert_ok!(response);
+        assert_eq!(
+            sent.message_id.as_deref(),
+            Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+        );
     }
 
     #[tokio::test]
@@ -192,7 +844,197 @@ mod tests {
             .await;
 
         // Assert
-        assert_err!(result);
+        let error = assert_err!(result);
+        assert!(error.is_retryable());
+        assert!(!error.is_recipient_rejected());
+    }
+
+    #[tokio::test]
+    async fn an_invalid_recipient_is_a_permanent_error() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                "ErrorCode": 300,
+                "Message": "Invalid 'To' address: 'nope'."
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let result = email_client
+            .send_email(
+                &EmailClient::fake_email(),
+                &EmailClient::fake_subject(),
+                &EmailClient::fake_content(),
+                &EmailClient::fake_content(),
+            )
+            .await;
+
+        // Assert
+        let error = assert_err!(result);
+        assert!(!error.is_retryable());
+        assert!(error.is_recipient_rejected());
+        assert_eq!(error.error_code(), Some(300));
+        match error {
+            SendEmailError::Permanent {
+                status, message, ..
+            } => {
+                assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+                assert_eq!(message, "Invalid 'To' address: 'nope'.");
+            }
+            other => panic!("Expected a permanent error, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limiting_is_a_transient_error() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let result = email_client
+            .send_email(
+                &EmailClient::fake_email(),
+                &EmailClient::fake_subject(),
+                &EmailClient::fake_content(),
+                &EmailClient::fake_content(),
+            )
+            .await;
+
+        // Assert
+        let error = assert_err!(result);
+        assert!(error.is_retryable());
+        assert_eq!(error.error_code(), None);
+    }
+
+    #[tokio::test]
+    async fn send_email_batch_returns_a_result_per_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(path("/email/batch"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 0, "Message": "OK", "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d"},
+                {"ErrorCode": 300, "Message": "Invalid 'To' address."}
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let recipients = [EmailClient::fake_email(), EmailClient::fake_email()];
+        let subject = EmailClient::fake_subject();
+        let content = EmailClient::fake_content();
+        let emails: Vec<_> = recipients
+            .iter()
+            .map(|recipient| OutgoingEmail {
+                recipient,
+                subject: &subject,
+                html_content: &content,
+                text_content: &content,
+                delivery_key: Some(uuid::Uuid::new_v4()),
+            })
+            .collect();
+
+        // Act
+        let results = email_client
+            .send_email_batch(&emails, Failover::Forbidden)
+            .await
+            .unwrap();
+
+        // Assert
+        let requests = mock_server.received_requests().await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 2);
+        assert_eq!(body[1]["To"], recipients[1].as_ref());
+        assert_eq!(
+            body[1]["Metadata"]["delivery_key"],
+            emails[1].delivery_key.unwrap().to_string()
+        );
+        assert_eq!(results.len(), 2);
+        let sent = assert_ok!(&results[0]);
+        assert_eq!(
+            sent.message_id.as_deref(),
+            Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+        );
+        let error = results[1].as_ref().unwrap_err();
+        assert!(!error.is_retryable());
+        assert!(error.is_recipient_rejected());
+    }
+
+    #[tokio::test]
+    async fn send_email_batch_fails_as_a_whole_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let recipient = EmailClient::fake_email();
+        let emails = [OutgoingEmail {
+            recipient: &recipient,
+            subject: "subject",
+            html_content: "content",
+            text_content: "content",
+            delivery_key: None,
+        }];
+
+        // Act
+        let result = email_client
+            .send_email_batch(&emails, Failover::Forbidden)
+            .await;
+
+        // Assert
+        let error = assert_err!(result);
+        assert!(error.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn send_email_batch_refuses_batches_over_the_size_limit() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+        let recipient = EmailClient::fake_email();
+        let emails: Vec<_> = (0..=MAX_BATCH_SIZE)
+            .map(|_| OutgoingEmail {
+                recipient: &recipient,
+                subject: "subject",
+                html_content: "content",
+                text_content: "content",
+                delivery_key: None,
+            })
+            .collect();
+
+        // Act
+        let result = email_client
+            .send_email_batch(&emails, Failover::Forbidden)
+            .await;
+
+        // Assert
+        let error = assert_err!(result);
+        assert!(matches!(error, SendEmailError::BatchTooLarge { .. }));
+        assert!(!error.is_retryable());
     }
 
     #[tokio::test]
@@ -220,6 +1062,371 @@ mod tests {
             .await;
 
         // Assert
-        assert_err!(outcome);
+        let error = assert_err!(outcome);
+        assert!(matches!(error, SendEmailError::Unreachable(_)));
+        assert!(error.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn find_sent_email_searches_by_delivery_key() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri());
+        let delivery_key = uuid::Uuid::new_v4();
+
+        Mock::given(path("/messages/outbound"))
+            .and(method("GET"))
+            .and(query_param(
+                "metadata_delivery_key",
+                delivery_key.to_string(),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "TotalCount": 1,
+                "Messages": [{"MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d"}]
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let sent = email_client.find_sent_email(delivery_key).await.unwrap();
+
+        // Assert
+        assert_eq!(
+            sent.and_then(|sent| sent.message_id).as_deref(),
+            Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+        );
+    }
+
+    #[tokio::test]
+    async fn find_sent_email_returns_none_for_unknown_delivery_keys() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "TotalCount": 0,
+                "Messages": []
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let sent = email_client
+            .find_sent_email(uuid::Uuid::new_v4())
+            .await
+            .unwrap();
+
+        // Assert
+        assert!(sent.is_none());
+    }
+
+    fn circuit_breaker_settings() -> CircuitBreakerSettings {
+        CircuitBreakerSettings {
+            failure_threshold: 2,
+            open_seconds: 60,
+        }
+    }
+
+    async fn send_fake_email(email_client: &EmailClient) -> Result<SentEmail, SendEmailError> {
+        email_client
+            .send_email(
+                &EmailClient::fake_email(),
+                &EmailClient::fake_subject(),
+                &EmailClient::fake_content(),
+                &EmailClient::fake_content(),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn a_provider_that_keeps_failing_is_no_longer_called() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri())
+            .with_circuit_breaker(&circuit_breaker_settings());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            // The third email is refused without calling the provider
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        send_fake_email(&email_client).await.unwrap_err();
+        send_fake_email(&email_client).await.unwrap_err();
+        let result = send_fake_email(&email_client).await;
+
+        // Assert
+        let error = assert_err!(result);
+        assert!(matches!(error, SendEmailError::Unavailable { .. }));
+        assert!(error.is_retryable());
+        assert!(!error.may_have_been_accepted());
+    }
+
+    #[tokio::test]
+    async fn permanent_errors_do_not_open_the_circuit() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri())
+            .with_circuit_breaker(&circuit_breaker_settings());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+                "ErrorCode": 300,
+                "Message": "Invalid 'To' address: 'nope'."
+            })))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // Act & Assert
+        for _ in 0..3 {
+            let error = assert_err!(send_fake_email(&email_client).await);
+            assert!(matches!(error, SendEmailError::Permanent { .. }));
+        }
+    }
+
+    #[tokio::test]
+    async fn transactional_emails_fail_over_to_the_secondary_provider_while_the_primary_is_down() {
+        // Arrange
+        let primary = MockServer::start().await;
+        let secondary = MockServer::start().await;
+        let email_client = EmailClient::email_client(primary.uri())
+            .with_circuit_breaker(&circuit_breaker_settings())
+            .with_secondary(secondary.uri(), Secret::new(Faker.fake()));
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&primary)
+            .await;
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ErrorCode": 0,
+                "Message": "OK",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817"
+            })))
+            .expect(1)
+            .mount(&secondary)
+            .await;
+
+        // Act - The primary fails: the emails may have been accepted, they don't fail over
+        send_fake_email(&email_client).await.unwrap_err();
+        send_fake_email(&email_client).await.unwrap_err();
+        // Act - Its circuit is now open
+        let result = send_fake_email(&email_client).await;
+
+        // Assert
+        assert_ok!(result);
+    }
+
+    #[tokio::test]
+    async fn batches_that_may_not_fail_over_wait_for_the_primary_provider() {
+        // Arrange
+        let primary = MockServer::start().await;
+        let secondary = MockServer::start().await;
+        let email_client = EmailClient::email_client(primary.uri())
+            .with_circuit_breaker(&circuit_breaker_settings())
+            .with_secondary(secondary.uri(), Secret::new(Faker.fake()));
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&primary)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&secondary)
+            .await;
+        let recipient = EmailClient::fake_email();
+        let emails = [OutgoingEmail {
+            recipient: &recipient,
+            subject: "subject",
+            html_content: "content",
+            text_content: "content",
+            delivery_key: None,
+        }];
+
+        // Act
+        for _ in 0..2 {
+            assert_err!(
+                email_client
+                    .send_email_batch(&emails, Failover::Forbidden)
+                    .await
+            );
+        }
+        let result = email_client
+            .send_email_batch(&emails, Failover::Forbidden)
+            .await;
+
+        // Assert
+        let error = assert_err!(result);
+        assert!(matches!(error, SendEmailError::Unavailable { .. }));
+    }
+
+    #[tokio::test]
+    async fn find_sent_email_searches_the_secondary_provider_too() {
+        // Arrange
+        let primary = MockServer::start().await;
+        let secondary = MockServer::start().await;
+        let email_client = EmailClient::email_client(primary.uri())
+            .with_secondary(secondary.uri(), Secret::new(Faker.fake()));
+        let delivery_key = uuid::Uuid::new_v4();
+
+        Mock::given(path("/messages/outbound"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "TotalCount": 0,
+                "Messages": []
+            })))
+            .expect(1)
+            .mount(&primary)
+            .await;
+        Mock::given(path("/messages/outbound"))
+            .and(query_param(
+                "metadata_delivery_key",
+                delivery_key.to_string(),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "TotalCount": 1,
+                "Messages": [{"MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d"}]
+            })))
+            .expect(1)
+            .mount(&secondary)
+            .await;
+
+        // Act
+        let found = email_client.find_sent_email(delivery_key).await;
+
+        // Assert
+        let sent = assert_ok!(found).expect("The email was not found");
+        assert_eq!(
+            sent.message_id.as_deref(),
+            Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+        );
+    }
+
+    fn redirect_sandbox(allowlist: &[&str], catch_all: Option<&str>) -> Sandbox {
+        Sandbox::Redirect {
+            allowlist: allowlist.iter().map(|entry| entry.to_string()).collect(),
+            catch_all: catch_all.map(|address| SubscriberEmail::parse(address.into()).unwrap()),
+        }
+    }
+
+    async fn send_email_to(email_client: &EmailClient, recipient: &str) -> SentEmail {
+        email_client
+            .send_email(
+                &SubscriberEmail::parse(recipient.into()).unwrap(),
+                "Subject",
+                &EmailClient::fake_content(),
+                &EmailClient::fake_content(),
+            )
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_sandbox_redirects_emails_outside_of_the_allowlist_to_the_catch_all_address() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri()).with_sandbox(
+            redirect_sandbox(&["@example.com"], Some("catch-all@example.com")),
+        );
+
+        Mock::given(path("/email"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        send_email_to(&email_client, "ursula@example.com").await;
+        send_email_to(&email_client, "ursula@gmail.com").await;
+
+        // Assert
+        let requests = mock_server.received_requests().await.unwrap();
+        let bodies: Vec<serde_json::Value> = requests
+            .iter()
+            .map(|request| serde_json::from_slice(&request.body).unwrap())
+            .collect();
+        assert_eq!(bodies[0]["To"], "ursula@example.com");
+        assert_eq!(bodies[0]["Subject"], "Subject");
+        assert_eq!(bodies[1]["To"], "catch-all@example.com");
+        assert_eq!(
+            bodies[1]["Subject"],
+            "[Sandbox: to ursula@gmail.com] Subject"
+        );
+    }
+
+    #[tokio::test]
+    async fn the_sandbox_only_logs_emails_in_log_only_mode() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client =
+            EmailClient::email_client(mock_server.uri()).with_sandbox(Sandbox::LogOnly);
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let sent = send_email_to(&email_client, "ursula@example.com").await;
+
+        // Assert
+        assert_eq!(sent.message_id, None);
+    }
+
+    #[tokio::test]
+    async fn the_sandbox_only_sends_the_emails_of_a_batch_it_lets_through() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = EmailClient::email_client(mock_server.uri())
+            .with_sandbox(redirect_sandbox(&["le_guin@example.com"], None));
+
+        Mock::given(path("/email/batch"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!([
+                {"ErrorCode": 0, "Message": "OK", "MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d"}
+            ])))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let recipients = [
+            SubscriberEmail::parse("ursula@example.com".into()).unwrap(),
+            SubscriberEmail::parse("le_guin@example.com".into()).unwrap(),
+        ];
+        let emails: Vec<_> = recipients
+            .iter()
+            .map(|recipient| OutgoingEmail {
+                recipient,
+                subject: "subject",
+                html_content: "content",
+                text_content: "content",
+                delivery_key: None,
+            })
+            .collect();
+
+        // Act
+        let results = email_client
+            .send_email_batch(&emails, Failover::Forbidden)
+            .await
+            .unwrap();
+
+        // Assert
+        let requests = mock_server.received_requests().await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["To"], "le_guin@example.com");
+        assert_eq!(results.len(), 2);
+        assert_eq!(assert_ok!(&results[0]).message_id, None);
+        assert_eq!(
+            assert_ok!(&results[1]).message_id.as_deref(),
+            Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+        );
     }
 }
@@ -0,0 +1,67 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+// How many events the admin page shows.
+const MAX_LISTED_EVENTS: i64 = 100;
+
+/// Something the email provider told us about an address after the fact
+/// (a bounce, a spam complaint...).
+pub struct EmailEvent {
+    pub record_type: String,
+    pub email: String,
+    pub message_id: Option<String>,
+    pub description: Option<String>,
+    pub suppressed: bool,
+    pub occurred_at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(skip(transaction, event), fields(record_type = %event.record_type))]
+pub async fn record_email_event(
+    transaction: &mut Transaction<'_, Postgres>,
+    event: &EmailEvent,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO email_events (
+            email_event_id,
+            record_type,
+            email,
+            message_id,
+            description,
+            suppressed,
+            occurred_at,
+            received_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        Uuid::new_v4(),
+        event.record_type,
+        event.email,
+        event.message_id,
+        event.description,
+        event.suppressed,
+        event.occurred_at,
+        event.received_at,
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// The latest events received from the email provider, most recent first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_recent_email_events(pool: &PgPool) -> Result<Vec<EmailEvent>, sqlx::Error> {
+    sqlx::query_as!(
+        EmailEvent,
+        r#"
+        SELECT record_type, email, message_id, description, suppressed, occurred_at, received_at
+        FROM email_events
+        ORDER BY received_at DESC
+        LIMIT $1
+        "#,
+        MAX_LISTED_EVENTS,
+    )
+    .fetch_all(pool)
+    .await
+}
@@ -0,0 +1,316 @@
+use crate::configuration::WorkerSettings;
+use crate::domain::SubscriberEmail;
+use crate::email_client::{EmailClient, SendEmailError};
+use crate::issue_delivery_worker::ExecutionOutcome;
+use crate::liveness::Heartbeat;
+use crate::queue_listener::{notify_workers, QueueListener, EMAIL_OUTBOX_CHANNEL};
+use crate::shutdown::ShutdownSignal;
+use crate::startup::get_connection_pool;
+use crate::suppression::{suppress_recipient, SuppressionReason};
+use crate::telemetry::record_pool_usage;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::time::Duration;
+use tracing::field::display;
+use tracing::Span;
+use uuid::Uuid;
+
+// After this many failed attempts the email is marked as failed and left alone.
+const MAX_RETRIES: i16 = 10;
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+/// Store an email in the outbox.
+///
+/// The email is only visible to the dispatcher once `transaction` commits:
+/// either both the change that triggered it and the email are persisted, or neither is.
+#[tracing::instrument(skip_all, fields(email_id = tracing::field::Empty))]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_content: &str,
+    text_content: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let email_id = Uuid::new_v4();
+    Span::current().record("email_id", display(email_id));
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO email_outbox (
+            email_id,
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, now())
+        "#,
+        email_id,
+        recipient.as_ref(),
+        subject,
+        html_content,
+        text_content,
+    );
+    transaction.execute(query).await?;
+    notify_workers(transaction, EMAIL_OUTBOX_CHANNEL).await?;
+    Ok(email_id)
+}
+
+pub async fn run_dispatcher_until_stopped(
+    configuration: WorkerSettings,
+    shutdown: ShutdownSignal,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+
+    let email_client = configuration.email_client.client();
+    let poll_interval = configuration.delivery_worker.poll_interval();
+    let heartbeat = Heartbeat::register(
+        "email_outbox_dispatcher",
+        configuration.worker.stall_timeout(),
+    );
+    dispatcher_loop(
+        connection_pool,
+        email_client,
+        poll_interval,
+        heartbeat,
+        shutdown,
+    )
+    .await
+}
+
+async fn dispatcher_loop(
+    pg_pool: PgPool,
+    email_client: EmailClient,
+    poll_interval: Duration,
+    heartbeat: Heartbeat,
+    mut shutdown: ShutdownSignal,
+) -> Result<(), anyhow::Error> {
+    let mut listener = QueueListener::new(&pg_pool, EMAIL_OUTBOX_CHANNEL, poll_interval).await;
+    // The email being dispatched is always seen through, we only stop in between two.
+    while !shutdown.is_triggered() {
+        heartbeat.beat();
+        record_pool_usage("email_outbox_dispatcher", &pg_pool);
+        match try_dispatch_email(&pg_pool, &email_client).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                shutdown.unless_triggered(listener.wait()).await;
+            }
+            Err(_) => {
+                shutdown
+                    .unless_triggered(tokio::time::sleep(Duration::from_secs(1)))
+                    .await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+    tracing::info!("The email outbox dispatcher stopped dequeuing emails");
+    Ok(())
+}
+
+struct OutboxEmail {
+    email_id: Uuid,
+    recipient: String,
+    subject: String,
+    html_content: String,
+    text_content: String,
+    n_retries: i16,
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        email_id=tracing::field::Empty,
+        recipient=tracing::field::Empty
+    ),
+    err
+)]
+pub async fn try_dispatch_email(
+    pool: &PgPool,
+    email_client: &EmailClient,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let email = dequeue_email(pool).await?;
+    if email.is_none() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+    let (mut transaction, email) = email.unwrap();
+    Span::current()
+        .record("email_id", display(email.email_id))
+        .record("recipient", display(&email.recipient));
+
+    let recipient = match SubscriberEmail::parse(email.recipient.clone()) {
+        Ok(recipient) => recipient,
+        Err(e) => {
+            let e = anyhow::anyhow!(e);
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Giving up on an email from the outbox. Its recipient is invalid.",
+            );
+            mark_as_failed(transaction, &email, &e).await?;
+            return Ok(ExecutionOutcome::TaskCompleted);
+        }
+    };
+    let outcome = email_client
+        .send_email(
+            &recipient,
+            &email.subject,
+            &email.html_content,
+            &email.text_content,
+        )
+        .await;
+
+    match outcome {
+        Ok(_) => delete_email(transaction, email.email_id).await?,
+        // Nothing was sent: wait for the provider to be probed again, it doesn't count as a retry.
+        Err(SendEmailError::Unavailable { retry_in, .. }) => {
+            tracing::warn!("Deferring an email from the outbox: the email provider is unavailable");
+            defer(transaction, &email, retry_in).await?;
+        }
+        Err(e) if e.is_retryable() => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                error.code = e.error_code(),
+                n_retries = email.n_retries,
+                "Failed to dispatch an email from the outbox. Retrying later.",
+            );
+            schedule_retry(transaction, &email, &anyhow::Error::from(e)).await?;
+        }
+        Err(e) => {
+            // Retrying won't help: fail fast.
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                error.code = e.error_code(),
+                "The email provider rejected an email from the outbox. Giving up.",
+            );
+            if e.is_recipient_rejected() {
+                suppress_recipient(
+                    &mut transaction,
+                    recipient.as_ref(),
+                    SuppressionReason::ProviderRejected,
+                )
+                .await?;
+            }
+            mark_as_failed(transaction, &email, &anyhow::Error::from(e)).await?;
+        }
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+#[tracing::instrument(skip_all)]
+async fn dequeue_email(
+    pool: &PgPool,
+) -> Result<Option<(PgTransaction, OutboxEmail)>, anyhow::Error> {
+    let mut tx: PgTransaction = pool.begin().await?;
+    let email = sqlx::query_as!(
+        OutboxEmail,
+        r#"
+        SELECT email_id, recipient, subject, html_content, text_content, n_retries
+        FROM email_outbox
+        WHERE
+            failed_at IS NULL AND
+            execute_after <= now()
+        ORDER BY created_at
+        FOR UPDATE
+        SKIP LOCKED
+        LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    Ok(email.map(|email| (tx, email)))
+}
+
+#[tracing::instrument(skip_all)]
+async fn delete_email(mut transaction: PgTransaction, email_id: Uuid) -> Result<(), anyhow::Error> {
+    sqlx::query!("DELETE FROM email_outbox WHERE email_id = $1", email_id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Push the email back with an exponential backoff (1, 2, 4, ... minutes),
+/// or give up on it once it has been retried `MAX_RETRIES` times.
+#[tracing::instrument(skip_all)]
+async fn schedule_retry(
+    mut transaction: PgTransaction,
+    email: &OutboxEmail,
+    error: &anyhow::Error,
+) -> Result<(), anyhow::Error> {
+    let n_retries = email.n_retries + 1;
+    let give_up = n_retries >= MAX_RETRIES;
+    let backoff_minutes = 2_i32.pow(n_retries.min(12) as u32 - 1);
+    sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET
+            n_retries = $2,
+            execute_after = now() + make_interval(mins => $3),
+            last_error = $4,
+            failed_at = CASE WHEN $5 THEN now() ELSE NULL END
+        WHERE email_id = $1
+        "#,
+        email.email_id,
+        n_retries,
+        backoff_minutes,
+        format!("{:#}", error),
+        give_up,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    if give_up {
+        tracing::error!("Giving up on an email from the outbox after {n_retries} attempts");
+    }
+    Ok(())
+}
+
+/// Push the email back by `delay`, without counting it as a retry.
+#[tracing::instrument(skip_all)]
+async fn defer(
+    mut transaction: PgTransaction,
+    email: &OutboxEmail,
+    delay: Duration,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET execute_after = now() + make_interval(secs => $2)
+        WHERE email_id = $1
+        "#,
+        email.email_id,
+        delay.as_secs_f64(),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Leave the email in the outbox for later inspection, without ever attempting it again.
+#[tracing::instrument(skip_all)]
+async fn mark_as_failed(
+    mut transaction: PgTransaction,
+    email: &OutboxEmail,
+    error: &anyhow::Error,
+) -> Result<(), anyhow::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE email_outbox
+        SET
+            n_retries = $2,
+            last_error = $3,
+            failed_at = now()
+        WHERE email_id = $1
+        "#,
+        email.email_id,
+        email.n_retries + 1,
+        format!("{:#}", error),
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(())
+}
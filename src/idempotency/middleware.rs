@@ -0,0 +1,191 @@
+use crate::authentication::UserId;
+use crate::configuration::IdempotencySettings;
+use crate::idempotency::{
+    save_response, try_processing, IdempotencyKey, IdempotencyScope, NextAction,
+};
+use crate::utils::{e400, e500};
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header::HeaderName;
+use actix_web::middleware::Next;
+use actix_web::web::{Bytes, Data};
+use actix_web::{FromRequest, HttpMessage, HttpRequest};
+use anyhow::Context;
+use futures::lock::{MappedMutexGuard, Mutex, MutexGuard};
+use futures::Stream;
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Make retries of a request safe: the response to the first attempt is saved and replayed
+/// to the following ones, which are not processed again.
+///
+/// The key is read from the `Idempotency-Key` header or, for HTML forms, from the
+/// `idempotency_key` field. Keys are scoped to the caller, so the route must sit behind a
+/// middleware authenticating it (e.g. `reject_anonymous_users`).
+/// Reusing a key for a different request gets a 422, and a request arriving while another one
+/// with the same key is being processed gets a 409.
+///
+/// Error responses are not saved: whatever the route wrote through `IdempotentTransaction`
+/// is rolled back and the request can be retried with the same key.
+///
+/// A retry answered with the saved response runs the route's `OnReplay` hook, if any.
+pub async fn ensure_idempotency(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let scope = scope(&req).map_err(e500)?;
+    // The body is read upfront to fingerprint the request, then handed back to the route
+    let body = req.extract::<Bytes>().await?;
+    req.set_payload(bytes_to_payload(body.clone()));
+    let idempotency_key = idempotency_key(&req, &body)?;
+    let request_fingerprint = fingerprint(req.request(), &body);
+
+    let pool = app_data::<PgPool>(&req)?;
+    let settings = app_data::<IdempotencySettings>(&req)?;
+
+    let transaction = match try_processing(
+        &pool,
+        &scope,
+        &idempotency_key,
+        &request_fingerprint,
+        &settings,
+    )
+    .await?
+    {
+        NextAction::StartProcessing(transaction) => transaction,
+        NextAction::ReturnSavedResponse(saved_response) => {
+            if let Some(OnReplay(on_replay)) = req.app_data::<OnReplay>() {
+                on_replay();
+            }
+            return Ok(req.into_response(saved_response));
+        }
+    };
+    let slot = IdempotentTransaction(Rc::new(Mutex::new(Some(transaction))));
+    req.extensions_mut().insert(slot.clone());
+
+    let response = next.call(req).await?;
+
+    let transaction = slot.0.lock().await.take();
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        // Dropping the transaction rolls it back
+        return Ok(response.map_into_boxed_body());
+    }
+    let transaction = transaction
+        .context("The idempotency transaction was already ended")
+        .map_err(e500)?;
+    let (request, response) = response.into_parts();
+    let response = save_response(
+        transaction,
+        &scope,
+        &idempotency_key,
+        response.map_into_boxed_body(),
+        &settings,
+    )
+    .await
+    .map_err(e500)?;
+    Ok(ServiceResponse::new(request, response))
+}
+
+/// What the route does for a retry that `ensure_idempotency` answers with the saved response,
+/// e.g. send the flash message the first attempt sent. Registered as app data of the route.
+///
+/// The saved response is replayed as is: the flash message cookie is not part of it.
+#[derive(Clone, Copy)]
+pub struct OnReplay(pub fn());
+
+/// The transaction the idempotency key was claimed in, for routes behind `ensure_idempotency`.
+///
+/// What the route writes through it is committed together with the saved response,
+/// so a crash can't leave the work done without a response to replay.
+#[derive(Clone)]
+pub struct IdempotentTransaction(Rc<Mutex<Option<Transaction<'static, Postgres>>>>);
+
+impl IdempotentTransaction {
+    pub async fn lock(
+        &self,
+    ) -> MappedMutexGuard<'_, Option<Transaction<'static, Postgres>>, Transaction<'static, Postgres>>
+    {
+        MutexGuard::map(self.0.lock().await, |transaction| {
+            transaction
+                .as_mut()
+                .expect("The idempotency transaction is only ended once the route returned")
+        })
+    }
+}
+
+impl FromRequest for IdempotentTransaction {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Self>()
+                .cloned()
+                .ok_or_else(|| e500("The route is not wrapped in `ensure_idempotency`")),
+        )
+    }
+}
+
+fn scope(req: &ServiceRequest) -> Result<IdempotencyScope, anyhow::Error> {
+    let extensions = req.extensions();
+    if let Some(scope) = extensions.get::<IdempotencyScope>() {
+        return Ok(scope.clone());
+    }
+    extensions
+        .get::<UserId>()
+        .map(|user_id| IdempotencyScope::User(**user_id))
+        .context("Idempotent routes must sit behind a middleware authenticating the caller")
+}
+
+#[derive(serde::Deserialize)]
+struct IdempotencyKeyField {
+    idempotency_key: Option<String>,
+}
+
+fn idempotency_key(req: &ServiceRequest, body: &Bytes) -> Result<IdempotencyKey, actix_web::Error> {
+    let idempotency_key = match req.headers().get(IDEMPOTENCY_KEY) {
+        Some(value) => value.to_str().map_err(e400)?.to_owned(),
+        None if req.content_type() == "application/x-www-form-urlencoded" => {
+            serde_urlencoded::from_bytes::<IdempotencyKeyField>(body)
+                .map_err(e400)?
+                .idempotency_key
+                .ok_or_else(|| e400("The idempotency key is missing"))?
+        }
+        None => return Err(e400("The idempotency key is missing")),
+    };
+    idempotency_key.try_into().map_err(e400)
+}
+
+/// Tells apart two requests sent with the same idempotency key.
+fn fingerprint(req: &HttpRequest, body: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(b" ");
+    hasher.update(req.path());
+    hasher.update(b"?");
+    hasher.update(req.query_string());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hasher.finalize().to_vec()
+}
+
+fn bytes_to_payload(body: Bytes) -> Payload {
+    let stream: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(futures::stream::once(async { Ok(body) }));
+    Payload::from(stream)
+}
+
+fn app_data<T: 'static>(req: &ServiceRequest) -> Result<Data<T>, actix_web::Error> {
+    req.app_data::<Data<T>>()
+        .cloned()
+        .with_context(|| format!("{} is not registered", std::any::type_name::<T>()))
+        .map_err(e500)
+}
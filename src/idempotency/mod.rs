@@ -1,4 +1,10 @@
 mod key;
+mod middleware;
 mod persistence;
+mod purge;
+mod scope;
 pub use key::IdempotencyKey;
-pub use persistence::{get_saved_response, save_response, try_processing, NextAction};
+pub use middleware::{ensure_idempotency, IdempotentTransaction, OnReplay, IDEMPOTENCY_KEY};
+pub use persistence::{save_response, try_processing, IdempotencyError, NextAction};
+pub use purge::PurgeExpiredIdempotencyKeys;
+pub use scope::IdempotencyScope;
@@ -1,9 +1,13 @@
-use crate::idempotency::IdempotencyKey;
-use actix_web::body::to_bytes;
+use crate::configuration::IdempotencySettings;
+use crate::idempotency::{IdempotencyKey, IdempotencyScope};
+use crate::routes::error_chain_fmt;
+use actix_web::body::to_bytes_limited;
+use actix_web::http::header::{HeaderName, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING};
 use actix_web::http::StatusCode;
-use actix_web::HttpResponse;
+use actix_web::{HttpResponse, ResponseError};
+use anyhow::Context;
 use sqlx::{Executor, PgPool, Postgres, Transaction};
-use uuid::Uuid;
+use std::fmt::Formatter;
 
 #[derive(Debug, sqlx::Type)]
 #[sqlx(type_name = "header_pair")]
@@ -12,58 +16,37 @@ struct HeaderPairRecord {
     value: Vec<u8>,
 }
 
-pub async fn get_saved_response(
-    pool: &PgPool,
-    idempotency_key: &IdempotencyKey,
-    user_id: Uuid,
-) -> Result<Option<HttpResponse>, anyhow::Error> {
-    let saved_response = sqlx::query!(
-        r#"
-        SELECT
-            response_status_code as "response_status_code!",
-            response_headers as "response_headers!: Vec<HeaderPairRecord>",
-            response_body as "response_body!"
-        FROM idempotency
-        WHERE
-          user_id = $1 AND
-          idempotency_key = $2
-        "#,
-        user_id,
-        idempotency_key.as_ref()
-    )
-    .fetch_optional(pool)
-    .await?;
-    if let Some(row) = saved_response {
-        let status_code = StatusCode::from_u16(row.response_status_code.try_into()?)?;
-        let mut response = HttpResponse::build(status_code);
-        for HeaderPairRecord { name, value } in row.response_headers {
-            response.append_header((name, value));
-        }
-        Ok(Some(response.finish()))
-    } else {
-        Ok(None)
-    }
-}
-
 pub async fn save_response(
     // No longer a PgPool but rather a transaction
     mut transaction: Transaction<'static, Postgres>,
+    scope: &IdempotencyScope,
     idempotency_key: &IdempotencyKey,
-    user_id: Uuid,
     http_response: HttpResponse,
+    settings: &IdempotencySettings,
 ) -> Result<HttpResponse, anyhow::Error> {
     let (response_headers, response_body) = http_response.into_parts();
     let status_code = response_headers.status().as_u16() as i16;
 
-    // `MessageBody::Error` is not `Send` + `Sync`,
-    // therefore it doesn't play nicely with `anyhow`
-    let body = to_bytes(response_body)
+    // Streaming bodies are collected too: the replay must be the same bytes.
+    // Bailing out drops the transaction, so nothing is saved for an oversized response.
+    let body = to_bytes_limited(response_body, settings.max_response_body_bytes)
         .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "The response body is larger than {} bytes, it cannot be saved",
+                settings.max_response_body_bytes
+            )
+        })?
+        // `MessageBody::Error` is not `Send` + `Sync`,
+        // therefore it doesn't play nicely with `anyhow`
         .map_err(|e| anyhow::anyhow!("{}", e))?;
 
     let headers = {
         let mut h = Vec::with_capacity(response_headers.headers().len());
         for (name, value) in response_headers.headers() {
+            if is_framing_header(name) {
+                continue;
+            }
             let name = name.as_str().to_owned();
             let value = value.as_bytes().to_owned();
             h.push(HeaderPairRecord { name, value });
@@ -80,10 +63,10 @@ pub async fn save_response(
             response_headers = $4,
             response_body = $5
         WHERE 
-            user_id = $1 AND
+            scope = $1 AND
             idempotency_key = $2
         "#,
-            user_id,
+            scope.to_string(),
             idempotency_key.as_ref(),
             status_code,
             headers,
@@ -98,38 +81,147 @@ pub async fn save_response(
     Ok(http_response)
 }
 
+/// Describe how the original body was sent rather than the body itself:
+/// they are set again for the replayed one.
+fn is_framing_header(name: &HeaderName) -> bool {
+    [CONTENT_LENGTH, TRANSFER_ENCODING, CONNECTION].contains(name)
+}
+
+#[allow(clippy::large_enum_variant)]
 pub enum NextAction {
     StartProcessing(Transaction<'static, Postgres>),
     ReturnSavedResponse(HttpResponse),
 }
 
+#[derive(thiserror::Error)]
+pub enum IdempotencyError {
+    #[error("A request with this idempotency key is still being processed")]
+    InProgress,
+    #[error("This idempotency key was already used for a different request")]
+    KeyReused,
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for IdempotencyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for IdempotencyError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            IdempotencyError::InProgress => StatusCode::CONFLICT,
+            IdempotencyError::KeyReused => StatusCode::UNPROCESSABLE_ENTITY,
+            IdempotencyError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Claim `idempotency_key` for a request, or find the response saved for it.
+///
+/// The key stays claimed until the returned transaction ends: requests with the same key
+/// get `IdempotencyError::InProgress` in the meantime, rather than waiting on it.
 pub async fn try_processing(
     pool: &PgPool,
+    scope: &IdempotencyScope,
     idempotency_key: &IdempotencyKey,
-    user_id: Uuid,
-) -> Result<NextAction, anyhow::Error> {
-    let mut transaction = pool.begin().await?;
-    let sql_query = sqlx::query!(
+    request_fingerprint: &[u8],
+    settings: &IdempotencySettings,
+) -> Result<NextAction, IdempotencyError> {
+    let scope = scope.to_string();
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let claimed = sqlx::query_scalar!(
+        r#"SELECT pg_try_advisory_xact_lock(hashtext($1), hashtext($2)) AS "claimed!""#,
+        scope,
+        idempotency_key.as_ref()
+    )
+    .fetch_one(&mut *transaction)
+    .await
+    .context("Failed to claim the idempotency key")?;
+    if !claimed {
+        return Err(IdempotencyError::InProgress);
+    }
+
+    // An expired key is used again as if it were new
+    sqlx::query!(
         r#"
-        INSERT INTO idempotency (
-            user_id, 
-            idempotency_key,
-            created_at
-        ) 
-        VALUES ($1, $2, now()) 
-        ON CONFLICT DO NOTHING
+        DELETE FROM idempotency
+        WHERE
+          scope = $1 AND
+          idempotency_key = $2 AND
+          expires_at <= now()
         "#,
-        user_id,
+        scope,
         idempotency_key.as_ref()
-    );
-    let n_inserted_rows = transaction.execute(sql_query).await?.rows_affected();
-
-    if n_inserted_rows > 0 {
-        Ok(NextAction::StartProcessing(transaction))
-    } else {
-        let saved_response = get_saved_response(pool, idempotency_key, user_id)
-            .await?
-            .ok_or_else(|| anyhow::anyhow!("Expected saved response but found none"))?;
-        Ok(NextAction::ReturnSavedResponse(saved_response))
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to delete the expired idempotency key")?;
+
+    // Responses are saved in the transaction that inserted their row:
+    // a row we can see has its response.
+    let saved_response = sqlx::query!(
+        r#"
+        SELECT
+            response_status_code as "response_status_code!",
+            response_headers as "response_headers!: Vec<HeaderPairRecord>",
+            response_body as "response_body!",
+            request_fingerprint
+        FROM idempotency
+        WHERE
+          scope = $1 AND
+          idempotency_key = $2
+        "#,
+        scope,
+        idempotency_key.as_ref()
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to look up the saved response")?;
+
+    if let Some(row) = saved_response {
+        if row
+            .request_fingerprint
+            .is_some_and(|fingerprint| fingerprint != request_fingerprint)
+        {
+            return Err(IdempotencyError::KeyReused);
+        }
+        let status_code = u16::try_from(row.response_status_code)
+            .ok()
+            .and_then(|status_code| StatusCode::from_u16(status_code).ok())
+            .context("The saved status code is not a valid HTTP status code")?;
+        let mut response = HttpResponse::build(status_code);
+        for HeaderPairRecord { name, value } in row.response_headers {
+            response.append_header((name, value));
+        }
+        return Ok(NextAction::ReturnSavedResponse(
+            response.body(row.response_body),
+        ));
     }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO idempotency (
+            scope,
+            idempotency_key,
+            request_fingerprint,
+            created_at,
+            expires_at
+        )
+        VALUES ($1, $2, $3, now(), now() + make_interval(secs => $4))
+        "#,
+        scope,
+        idempotency_key.as_ref(),
+        request_fingerprint,
+        settings.retention().as_secs_f64(),
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to store the idempotency key")?;
+    Ok(NextAction::StartProcessing(transaction))
 }
@@ -0,0 +1,35 @@
+use crate::jobs::RecurringPurge;
+use sqlx::{Executor, Postgres, Transaction};
+use std::time::Duration;
+
+const IDEMPOTENCY_PURGE_BATCH_SIZE: i64 = 1000;
+
+/// Delete expired idempotency keys, with their saved responses.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PurgeExpiredIdempotencyKeys;
+
+impl RecurringPurge for PurgeExpiredIdempotencyKeys {
+    const JOB_TYPE: &'static str = "purge_expired_idempotency_keys";
+    const BATCH_SIZE: i64 = IDEMPOTENCY_PURGE_BATCH_SIZE;
+    const INTERVAL: Duration = Duration::from_secs(60 * 60);
+    const PURGED_METRIC: &'static str = "idempotency_keys_purged_total";
+
+    async fn purge(transaction: &mut Transaction<'static, Postgres>) -> Result<u64, sqlx::Error> {
+        // Keys being looked up right now are skipped, they are dealt with there
+        let query = sqlx::query!(
+            r#"
+            DELETE FROM idempotency
+            WHERE (scope, idempotency_key) IN (
+                SELECT scope, idempotency_key
+                FROM idempotency
+                WHERE expires_at <= now()
+                FOR UPDATE
+                SKIP LOCKED
+                LIMIT $1
+            )
+            "#,
+            IDEMPOTENCY_PURGE_BATCH_SIZE,
+        );
+        Ok(transaction.execute(query).await?.rows_affected())
+    }
+}
@@ -0,0 +1,20 @@
+use std::fmt::Formatter;
+use uuid::Uuid;
+
+/// Whose idempotency keys a request's key is checked against: the same key sent by two
+/// different callers refers to two different requests.
+#[derive(Debug, Clone)]
+pub enum IdempotencyScope {
+    User(Uuid),
+    // Middlewares authenticating API clients insert this scope in the request extensions
+    ApiClient(String),
+}
+
+impl std::fmt::Display for IdempotencyScope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdempotencyScope::User(user_id) => write!(f, "user:{user_id}"),
+            IdempotencyScope::ApiClient(name) => write!(f, "api_client:{name}"),
+        }
+    }
+}
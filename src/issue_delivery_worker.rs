@@ -1,37 +1,94 @@
-use crate::configuration::Settings;
+use crate::configuration::{
+    DeliveryWorkerSettings, LaneSettings, SendIntentSettings, WorkerSettings,
+};
 use crate::domain::SubscriberEmail;
-use crate::email_client::EmailClient;
+use crate::email_client::{
+    EmailClient, Failover, OutgoingEmail, SendEmailError, SentEmail, MAX_BATCH_SIZE,
+};
+use crate::jobs::Job;
+use crate::liveness::Heartbeat;
+use crate::queue_listener::{notify_workers, QueueListener, DELIVERY_QUEUE_CHANNEL};
+use crate::send_intent::{
+    forget_send_intents, record_acceptances, record_attempt, record_empty_search,
+    record_send_intents, SendIntent,
+};
+use crate::send_rate_limit::admit_recipients;
+use crate::shutdown::ShutdownSignal;
 use crate::startup::get_connection_pool;
-use sqlx::{PgPool, Postgres, Transaction};
+use crate::suppression::{suppress_recipient, SuppressionReason};
+use crate::telemetry::{link_to_trace_context, record_pool_usage};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use metrics::gauge;
+use sqlx::types::Json;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::collections::HashMap;
 use std::time::Duration;
 use tracing::field::display;
-use tracing::Span;
+use tracing::{Instrument, Span};
 use uuid::Uuid;
 
-pub async fn run_worker_until_stopped(configuration: Settings) -> Result<(), anyhow::Error> {
+pub async fn run_worker_until_stopped(
+    configuration: WorkerSettings,
+    shutdown: ShutdownSignal,
+) -> Result<(), anyhow::Error> {
     let connection_pool = get_connection_pool(&configuration.database);
 
     let email_client = configuration.email_client.client();
-    worker_loop(connection_pool, email_client).await
+    let heartbeat = Heartbeat::register("delivery_worker", configuration.worker.stall_timeout());
+    worker_loop(
+        connection_pool,
+        email_client,
+        configuration.delivery_worker,
+        heartbeat,
+        shutdown,
+    )
+    .await
 }
 
 type PgTransaction = Transaction<'static, Postgres>;
 
-async fn worker_loop(pg_pool: PgPool, email_client: EmailClient) -> Result<(), anyhow::Error> {
-    loop {
-        match try_execute_task(&pg_pool, &email_client).await {
+const MIN_IDLE_WAIT: Duration = Duration::from_millis(100);
+
+async fn worker_loop(
+    pg_pool: PgPool,
+    email_client: EmailClient,
+    settings: DeliveryWorkerSettings,
+    heartbeat: Heartbeat,
+    mut shutdown: ShutdownSignal,
+) -> Result<(), anyhow::Error> {
+    let mut listener =
+        QueueListener::new(&pg_pool, DELIVERY_QUEUE_CHANNEL, settings.poll_interval()).await;
+    // A batch in flight is always seen through (its emails may already be out):
+    // we only stop in between two batches.
+    while !shutdown.is_triggered() {
+        heartbeat.beat();
+        record_pool_usage("delivery_worker", &pg_pool);
+        match try_execute_batch(&pg_pool, &email_client, &settings).await {
             Ok(ExecutionOutcome::EmptyQueue) => {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+                // Deferred tasks (retries, throttled sends) come due without any notification.
+                let wait = async {
+                    match next_task_due_in(&pg_pool).await {
+                        // Due tasks we could not claim are locked by another worker: don't spin.
+                        Ok(Some(due_in)) => listener.wait_for(due_in.max(MIN_IDLE_WAIT)).await,
+                        _ => listener.wait().await,
+                    }
+                };
+                shutdown.unless_triggered(wait).await;
             }
             Err(_) => {
                 // if we experience a transient failure18, we need to sleep for a while to
                 // improve our future chances of success. This could be further refined by
                 // introducing an exponential backoff with jitter.
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                shutdown
+                    .unless_triggered(tokio::time::sleep(Duration::from_secs(1)))
+                    .await;
             }
             Ok(ExecutionOutcome::TaskCompleted) => {}
         }
     }
+    tracing::info!("The delivery worker stopped dequeuing tasks");
+    Ok(())
 }
 
 pub enum ExecutionOutcome {
@@ -39,96 +96,619 @@ pub enum ExecutionOutcome {
     EmptyQueue,
 }
 
-#[tracing::instrument(
-    skip_all,
-    fields(
-        newsletter_issue_id=tracing::field::Empty,
-        subscriber_email=tracing::field::Empty
-    ),
-    err
-)]
-pub async fn try_execute_task(
+/// Fan a newsletter issue out to the subscribers who can receive it, one delivery task each.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DeliverNewsletterIssue {
+    pub newsletter_issue_id: Uuid,
+}
+
+impl Job for DeliverNewsletterIssue {
+    const JOB_TYPE: &'static str = "deliver_newsletter_issue";
+
+    fn unique_key(&self) -> Option<String> {
+        Some(self.newsletter_issue_id.to_string())
+    }
+
+    async fn run(self, transaction: &mut PgTransaction) -> Result<(), anyhow::Error> {
+        enqueue_delivery_tasks(transaction, self.newsletter_issue_id).await?;
+        Ok(())
+    }
+}
+
+#[tracing::instrument(skip(transaction))]
+async fn enqueue_delivery_tasks(
+    transaction: &mut PgTransaction,
+    newsletter_issue_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let sql_query = sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (
+            newsletter_issue_id, 
+            subscriber_id,
+            message_class
+        )
+        SELECT $1, id, $2
+        FROM subscriptions
+        WHERE
+            status = 'confirmed' AND
+            lower(email) NOT IN (SELECT email FROM suppression_list)
+        "#,
+        newsletter_issue_id,
+        MessageClass::Bulk.as_str(),
+    );
+    transaction.execute(sql_query).await?;
+    notify_workers(transaction, DELIVERY_QUEUE_CHANNEL).await?;
+    Ok(())
+}
+
+/// Claim up to `batch_size` tasks, deliver them concurrently and commit their outcomes together.
+///
+/// The claimed rows stay locked until the batch is committed: other workers skip them and,
+/// if we crash midway, the locks are released with every task of the batch still in the queue.
+/// Tasks that hit an unexpected error are retried later, like transient delivery failures.
+#[tracing::instrument(skip_all, fields(n_tasks = tracing::field::Empty), err)]
+pub async fn try_execute_batch(
     pool: &PgPool,
     email_client: &EmailClient,
+    settings: &DeliveryWorkerSettings,
 ) -> Result<ExecutionOutcome, anyhow::Error> {
-    let task = dequeue_task(pool).await?;
-    if task.is_none() {
+    let (transaction, tasks) = dequeue_tasks(pool, settings).await?;
+    if tasks.is_empty() {
         return Ok(ExecutionOutcome::EmptyQueue);
     }
-    let (transaction, issue_id, email) = task.unwrap();
-    Span::current()
-        .record("newsletter_issue_id", display(issue_id))
-        .record("subscriber_email", display(&email));
-    match SubscriberEmail::parse(email.clone()) {
-        Ok(email) => {
-            let issue = get_issue(pool, issue_id).await?;
-            if let Err(e) = email_client
-                .send_email(
-                    &email,
-                    &issue.title,
-                    &issue.html_content,
-                    &issue.text_content,
-                )
-                .await
-            {
-                tracing::error!(
-                    error.cause_chain = ?e,
-                    error.message = %e,
-                    "Failed to deliver issue to a confirmed subscriber. Skipping.",
-                );
+    Span::current().record("n_tasks", tasks.len());
+
+    // Most tasks of a batch share the same content, fetch it once.
+    let contents = get_email_contents(pool, &tasks).await?;
+    // Subscribers may have changed their address, or left, since the tasks were enqueued.
+    let recipients = get_recipients(pool, &tasks).await?;
+
+    let mut outcomes = Vec::with_capacity(tasks.len());
+    let mut deliveries = vec![];
+    for task in &tasks {
+        let span = task_span(task);
+        match span.in_scope(|| prepare_delivery(task, &recipients, &contents)) {
+            Ok(Some((recipient, content))) => deliveries.push((task, recipient, content)),
+            Ok(None) => outcomes.push((task, TaskOutcome::Done, None)),
+            Err(e) => {
+                span.in_scope(|| {
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        error.message = %e,
+                        "Failed to execute a delivery task. It will be retried.",
+                    )
+                });
+                outcomes.push((task, TaskOutcome::Failed, None));
+            }
+        }
+    }
+
+    // Emails over the send rate limits wait for their turn in the queue.
+    let addresses: Vec<_> = deliveries.iter().map(|(_, address, _)| address).collect();
+    let admissions = admit_recipients(pool, &settings.rate_limit, &addresses).await?;
+    let mut admitted = Vec::with_capacity(deliveries.len());
+    for (delivery, admission) in deliveries.into_iter().zip(admissions) {
+        match admission {
+            None => admitted.push(delivery),
+            Some(delay) => outcomes.push((delivery.0, TaskOutcome::Deferred(delay), None)),
+        }
+    }
+
+    // A worker may have crashed after handing some of these emails to the provider,
+    // but before committing its batch: make sure nobody gets them twice.
+    let task_ids: Vec<_> = admitted.iter().map(|(task, _, _)| task.task_id).collect();
+    let intents = record_send_intents(pool, &task_ids).await?;
+    let mut deliveries = Vec::with_capacity(admitted.len());
+    for (task, recipient, content) in admitted {
+        let intent = &intents[&task.task_id];
+        let span = task_span(task);
+        match check_send_intent(pool, email_client, intent, &settings.send_intents)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(IntentCheck::Send) => deliveries.push(Delivery {
+                task,
+                recipient,
+                content,
+                delivery_key: intent.delivery_key,
+            }),
+            Ok(IntentCheck::AlreadyAccepted(attempt)) => {
+                outcomes.push((task, TaskOutcome::Done, Some(attempt)))
+            }
+            Ok(IntentCheck::Unsettled(delay)) => {
+                outcomes.push((task, TaskOutcome::Deferred(delay), None))
             }
+            // The provider may be struggling: don't ask again right away, and don't count it
+            // as a retry, nothing was sent.
+            Err(e) => {
+                span.in_scope(|| {
+                    tracing::error!(
+                        error.cause_chain = ?e,
+                        error.message = %e,
+                        "Failed to check whether an email was already sent. \
+                        It will be checked again later.",
+                    )
+                });
+                let delay = settings.send_intents.search_delay();
+                outcomes.push((task, TaskOutcome::Deferred(delay), None));
+            }
+        }
+    }
+
+    // Emails go out through Postmark's batch API. Each class of email spreads its share of
+    // the batch over its share of the `concurrency` requests sent at the same time:
+    // a large newsletter can't take up every connection while onboarding steps wait.
+    let mut lanes: Vec<(MessageClass, Vec<Delivery>)> = MessageClass::BY_PRIORITY
+        .into_iter()
+        .map(|class| (class, vec![]))
+        .collect();
+    for delivery in deliveries {
+        let class = MessageClass::parse(&delivery.task.message_class);
+        if let Some((_, lane)) = lanes.iter_mut().find(|(c, _)| *c == class) {
+            lane.push(delivery);
+        }
+    }
+    lanes.retain(|(_, lane)| !lane.is_empty());
+    let classes: Vec<_> = lanes.iter().map(|(class, _)| *class).collect();
+    let lane_deliveries = lanes.iter().map(|(class, lane)| {
+        let concurrency = class.concurrency_share(&classes, settings);
+        deliver_lane(pool, email_client, lane, concurrency)
+    });
+    let delivered = futures::future::join_all(lane_deliveries).await;
+    outcomes.extend(delivered.into_iter().flatten());
+
+    complete_batch(transaction, &outcomes, &recipients).await?;
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+// After this many transient or unexpected failures the delivery is dropped.
+const MAX_RETRIES: i16 = 10;
+
+enum TaskOutcome {
+    /// Delivered, or skipped for good.
+    Done,
+    /// The email provider had a transient failure: try again later.
+    Retry,
+    /// The email provider refused the recipient: stop emailing them.
+    RecipientRejected,
+    /// Not our turn yet (e.g. over the send rate limits): try again after the delay,
+    /// it doesn't count as a retry.
+    Deferred(Duration),
+    /// An unexpected error: try again later, it counts as a retry.
+    Failed,
+}
+
+/// A request made to the email provider, recorded in the delivery log.
+struct DeliveryAttempt {
+    attempted_at: DateTime<Utc>,
+    outcome: DeliveryOutcome,
+    message_id: Option<String>,
+    error_code: Option<i64>,
+    error_message: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum DeliveryOutcome {
+    Sent,
+    TransientFailure,
+    PermanentFailure,
+}
+
+impl DeliveryOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryOutcome::Sent => "sent",
+            DeliveryOutcome::TransientFailure => "transient_failure",
+            DeliveryOutcome::PermanentFailure => "permanent_failure",
+        }
+    }
+}
+
+impl DeliveryAttempt {
+    fn sent(attempted_at: DateTime<Utc>, sent: SentEmail) -> Self {
+        Self {
+            attempted_at,
+            outcome: DeliveryOutcome::Sent,
+            message_id: sent.message_id,
+            error_code: None,
+            error_message: None,
         }
+    }
+
+    fn failed(attempted_at: DateTime<Utc>, error: &SendEmailError) -> Self {
+        let outcome = if error.is_retryable() {
+            DeliveryOutcome::TransientFailure
+        } else {
+            DeliveryOutcome::PermanentFailure
+        };
+        Self {
+            attempted_at,
+            outcome,
+            message_id: None,
+            error_code: error.error_code(),
+            error_message: Some(error.to_string()),
+        }
+    }
+}
+
+struct Delivery<'a> {
+    task: &'a DeliveryTask,
+    recipient: SubscriberEmail,
+    content: &'a EmailContent,
+    delivery_key: Uuid,
+}
+
+type TaskResult<'a> = (&'a DeliveryTask, TaskOutcome, Option<DeliveryAttempt>);
+
+fn task_span(task: &DeliveryTask) -> Span {
+    let span = tracing::info_span!(
+        "Execute delivery task",
+        task_id = task.task_id,
+        newsletter_issue_id = tracing::field::Empty,
+        onboarding_step_id = tracing::field::Empty,
+        subscriber_id = %task.subscriber_id,
+    );
+    if let Some(issue_id) = task.newsletter_issue_id {
+        span.record("newsletter_issue_id", display(issue_id));
+    }
+    if let Some(step_id) = task.onboarding_step_id {
+        span.record("onboarding_step_id", display(step_id));
+    }
+    if let Some(Json(trace_context)) = &task.trace_context {
+        link_to_trace_context(&span, trace_context);
+    }
+    span
+}
+
+/// The recipient and content of the email to send for `task`, `None` if it must be skipped.
+fn prepare_delivery<'a>(
+    task: &DeliveryTask,
+    recipients: &HashMap<Uuid, Recipient>,
+    contents: &'a HashMap<ContentKey, EmailContent>,
+) -> Result<Option<(SubscriberEmail, &'a EmailContent)>, anyhow::Error> {
+    // Tasks are enqueued ahead of time (onboarding steps, large issues...): the subscriber
+    // might have left or been suppressed in the meantime.
+    let Some(recipient) = recipients.get(&task.subscriber_id) else {
+        tracing::info!("Skipping a delivery. The subscriber no longer exists");
+        return Ok(None);
+    };
+    if recipient.status != "confirmed" {
+        tracing::info!(
+            status = %recipient.status,
+            "Skipping a delivery. The subscriber is no longer confirmed"
+        );
+        return Ok(None);
+    }
+    if recipient.suppressed {
+        tracing::info!("Skipping a delivery. The subscriber's address is suppressed");
+        return Ok(None);
+    }
+    let email = match SubscriberEmail::parse(recipient.email.clone()) {
+        Ok(email) => email,
         Err(e) => {
             tracing::error!(
                 error.cause_chain = ?e,
                 error.message = %e,
                 "Skipping a confirmed subscriber. Their stored contact details are invalid",
             );
+            return Ok(None);
+        }
+    };
+    let content = contents
+        .get(&task.content_key()?)
+        .ok_or_else(|| anyhow::anyhow!("Missing content for task {}", task.task_id))?;
+    Ok(Some((email, content)))
+}
+
+enum IntentCheck {
+    Send,
+    /// Sent by a worker that crashed before completing the task.
+    AlreadyAccepted(DeliveryAttempt),
+    /// Too early to tell whether the last attempt went through.
+    Unsettled(Duration),
+}
+
+/// Whether the email of `intent` must be sent, or was already accepted by the provider.
+///
+/// An email of unknown fate is only sent again once the provider failed to find it
+/// `search_attempts` times in a row, searches being `search_delay` apart.
+async fn check_send_intent(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    intent: &SendIntent,
+    settings: &SendIntentSettings,
+) -> Result<IntentCheck, anyhow::Error> {
+    if !intent.previously_attempted {
+        return Ok(IntentCheck::Send);
+    }
+    if let Some(accepted_at) = intent.accepted_at {
+        tracing::warn!("Skipping an email the provider already accepted");
+        let sent = SentEmail {
+            message_id: intent.message_id.clone(),
+        };
+        return Ok(IntentCheck::AlreadyAccepted(DeliveryAttempt::sent(
+            accepted_at,
+            sent,
+        )));
+    }
+    // We don't know how the last attempt went: ask the provider.
+    let last_checked_at = intent.searched_at.unwrap_or(intent.attempted_at);
+    let elapsed = (Utc::now() - last_checked_at).to_std().unwrap_or_default();
+    let search_delay = settings.search_delay();
+    if elapsed < search_delay {
+        return Ok(IntentCheck::Unsettled(search_delay - elapsed));
+    }
+    let found = match email_client.find_sent_email(intent.delivery_key).await {
+        // Ask again once the provider is back.
+        Err(SendEmailError::Unavailable { retry_in, .. }) => {
+            return Ok(IntentCheck::Unsettled(retry_in))
+        }
+        found => found?,
+    };
+    match found {
+        Some(sent) => {
+            tracing::warn!("Skipping an email the provider already accepted");
+            record_acceptances(
+                pool,
+                &[intent.task_id],
+                std::slice::from_ref(&sent.message_id),
+            )
+            .await?;
+            Ok(IntentCheck::AlreadyAccepted(DeliveryAttempt::sent(
+                intent.attempted_at,
+                sent,
+            )))
+        }
+        None if intent.n_searches + 1 < settings.search_attempts => {
+            record_empty_search(pool, intent.task_id).await?;
+            Ok(IntentCheck::Unsettled(search_delay))
+        }
+        None => {
+            tracing::info!(
+                n_searches = intent.n_searches + 1,
+                "The provider never received the email, sending it again"
+            );
+            record_attempt(pool, intent.task_id).await?;
+            Ok(IntentCheck::Send)
         }
     }
-    delete_task(transaction, issue_id, &email).await?;
-    Ok(ExecutionOutcome::TaskCompleted)
 }
 
-#[tracing::instrument(skip_all)]
-async fn dequeue_task(
+/// Send the emails of a class over up to `concurrency` batch requests at the same time.
+async fn deliver_lane<'a>(
     pool: &PgPool,
-) -> Result<Option<(PgTransaction, Uuid, String)>, anyhow::Error> {
-    let mut tx: PgTransaction = pool.begin().await?;
-    let row = sqlx::query!(
-        r#"
-        SELECT newsletter_issue_id, subscriber_email
-        FROM issue_delivery_queue
-        FOR UPDATE
-        SKIP LOCKED
-        LIMIT 1
-        "#,
-    )
-    .fetch_optional(&mut *tx)
-    .await?;
+    email_client: &EmailClient,
+    lane: &[Delivery<'a>],
+    concurrency: usize,
+) -> Vec<TaskResult<'a>> {
+    let chunk_size = lane.len().div_ceil(concurrency).clamp(1, MAX_BATCH_SIZE);
+    // Futures are lazy: nothing is sent until `buffer_unordered` polls them.
+    let requests: Vec<_> = lane
+        .chunks(chunk_size)
+        .map(|chunk| deliver(pool, email_client, chunk))
+        .collect();
+    let delivered: Vec<_> = futures::stream::iter(requests)
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+    delivered.into_iter().flatten().collect()
+}
 
-    if let Some(r) = row {
-        Ok(Some((tx, r.newsletter_issue_id, r.subscriber_email)))
-    } else {
-        Ok(None)
+/// Send the emails of `deliveries` with a single batch request.
+///
+/// A lone email goes through the batch API as well: it is the one tagging messages with their
+/// delivery key, which `check_send_intent` searches the provider for after a crash.
+/// None of them fails over to the secondary provider, it is meant for transactional volumes.
+async fn deliver<'a>(
+    pool: &PgPool,
+    email_client: &EmailClient,
+    deliveries: &[Delivery<'a>],
+) -> Vec<TaskResult<'a>> {
+    let emails: Vec<_> = deliveries
+        .iter()
+        .map(|delivery| OutgoingEmail {
+            recipient: &delivery.recipient,
+            subject: &delivery.content.title,
+            html_content: &delivery.content.html_content,
+            text_content: &delivery.content.text_content,
+            delivery_key: Some(delivery.delivery_key),
+        })
+        .collect();
+    let attempted_at = Utc::now();
+    let sent = email_client
+        .send_email_batch(&emails, Failover::Forbidden)
+        .await;
+    settle_send_intents(pool, deliveries, &sent).await;
+    match sent {
+        // Nothing was sent: wait for the provider to be probed again, it doesn't count as a retry.
+        Err(SendEmailError::Unavailable { retry_in, .. }) => {
+            tracing::warn!(
+                n_tasks = deliveries.len(),
+                "Deferring deliveries: the email provider is unavailable"
+            );
+            deliveries
+                .iter()
+                .map(|Delivery { task, .. }| (*task, TaskOutcome::Deferred(retry_in), None))
+                .collect()
+        }
+        Ok(results) => deliveries
+            .iter()
+            .zip(results)
+            .map(|(Delivery { task, .. }, result)| match result {
+                Ok(sent) => (
+                    *task,
+                    TaskOutcome::Done,
+                    Some(DeliveryAttempt::sent(attempted_at, sent)),
+                ),
+                Err(e) => (
+                    *task,
+                    failure_outcome(task, &e),
+                    Some(DeliveryAttempt::failed(attempted_at, &e)),
+                ),
+            })
+            .collect(),
+        // The whole request failed: so did every email in it.
+        Err(e) => deliveries
+            .iter()
+            .map(|Delivery { task, .. }| {
+                (
+                    *task,
+                    failure_outcome(task, &e),
+                    Some(DeliveryAttempt::failed(attempted_at, &e)),
+                )
+            })
+            .collect(),
     }
 }
 
+/// Record right away which emails the provider accepted, and which it certainly did not.
+///
+/// This is not critical: should it fail, the provider is asked before the emails are sent again.
+async fn settle_send_intents(
+    pool: &PgPool,
+    deliveries: &[Delivery<'_>],
+    sent: &Result<Vec<Result<SentEmail, SendEmailError>>, SendEmailError>,
+) {
+    let mut accepted_task_ids = vec![];
+    let mut message_ids = vec![];
+    let mut unsent_task_ids = vec![];
+    match sent {
+        Ok(results) => {
+            for (delivery, result) in deliveries.iter().zip(results) {
+                match result {
+                    Ok(sent) => {
+                        accepted_task_ids.push(delivery.task.task_id);
+                        message_ids.push(sent.message_id.clone());
+                    }
+                    Err(e) if !e.may_have_been_accepted() => {
+                        unsent_task_ids.push(delivery.task.task_id)
+                    }
+                    Err(_) => {}
+                }
+            }
+        }
+        Err(e) if !e.may_have_been_accepted() => {
+            unsent_task_ids.extend(deliveries.iter().map(|delivery| delivery.task.task_id))
+        }
+        Err(_) => {}
+    }
+    let settled = async {
+        record_acceptances(pool, &accepted_task_ids, &message_ids).await?;
+        forget_send_intents(pool, &unsent_task_ids).await
+    };
+    if let Err(e) = settled.await {
+        tracing::warn!(
+            error.cause_chain = ?e,
+            error.message = %e,
+            "Failed to record the outcome of the send intents"
+        );
+    }
+}
+
+fn failure_outcome(task: &DeliveryTask, error: &SendEmailError) -> TaskOutcome {
+    let outcome = if error.is_retryable() && task.n_retries + 1 < MAX_RETRIES {
+        TaskOutcome::Retry
+    } else if error.is_recipient_rejected() {
+        TaskOutcome::RecipientRejected
+    } else {
+        TaskOutcome::Done
+    };
+    task_span(task).in_scope(|| {
+        tracing::error!(
+            error.cause_chain = ?error,
+            error.message = %error,
+            error.code = error.error_code(),
+            n_retries = task.n_retries,
+            "Failed to deliver email to a confirmed subscriber. {}",
+            match outcome {
+                TaskOutcome::Retry => "Retrying later.",
+                _ => "Skipping.",
+            }
+        )
+    });
+    outcome
+}
+
+/// Record the outcome of every task of the batch, then release the batch.
 #[tracing::instrument(skip_all)]
-async fn delete_task(
+async fn complete_batch(
     mut transaction: PgTransaction,
-    issue_id: Uuid,
-    email: &str,
+    outcomes: &[TaskResult<'_>],
+    recipients: &HashMap<Uuid, Recipient>,
 ) -> Result<(), anyhow::Error> {
+    let mut done_task_ids = vec![];
+    let mut retry_task_ids = vec![];
+    let mut deferred_task_ids = vec![];
+    let mut deferred_delays = vec![];
+    for (task, outcome, attempt) in outcomes {
+        // Only tasks of recipients we know about get this far
+        let recipient = recipients.get(&task.subscriber_id);
+        if let (Some(attempt), Some(recipient)) = (attempt, recipient) {
+            log_delivery(&mut transaction, task, &recipient.email, attempt).await?;
+        }
+        match outcome {
+            TaskOutcome::Done => done_task_ids.push(task.task_id),
+            TaskOutcome::Retry => retry_task_ids.push(task.task_id),
+            TaskOutcome::Deferred(delay) => {
+                deferred_task_ids.push(task.task_id);
+                deferred_delays.push(delay.as_secs_f64());
+            }
+            TaskOutcome::RecipientRejected => {
+                if let Some(recipient) = recipient {
+                    suppress_recipient(
+                        &mut transaction,
+                        &recipient.email,
+                        SuppressionReason::ProviderRejected,
+                    )
+                    .await?;
+                }
+                done_task_ids.push(task.task_id);
+            }
+            TaskOutcome::Failed if task.n_retries + 1 < MAX_RETRIES => {
+                retry_task_ids.push(task.task_id)
+            }
+            TaskOutcome::Failed => {
+                task_span(task).in_scope(|| {
+                    tracing::error!(
+                        n_retries = task.n_retries,
+                        "Giving up on a delivery task that keeps failing. Skipping."
+                    )
+                });
+                done_task_ids.push(task.task_id);
+            }
+        }
+    }
     sqlx::query!(
         r#"
         DELETE FROM issue_delivery_queue
-        WHERE 
-            newsletter_issue_id = $1 AND
-            subscriber_email = $2 
+        WHERE task_id = ANY($1)
+        "#,
+        &done_task_ids,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    // Exponential backoff: 1, 2, 4, ... minutes.
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET
+            n_retries = n_retries + 1,
+            execute_after = now() + make_interval(mins => (2 ^ n_retries)::int)
+        WHERE task_id = ANY($1)
+        "#,
+        &retry_task_ids,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE issue_delivery_queue
+        SET execute_after = now() + make_interval(secs => deferred.delay)
+        FROM UNNEST($1::bigint[], $2::float8[]) AS deferred(task_id, delay)
+        WHERE issue_delivery_queue.task_id = deferred.task_id
         "#,
-        issue_id,
-        email
+        &deferred_task_ids,
+        &deferred_delays,
     )
     .execute(&mut *transaction)
     .await?;
@@ -136,16 +716,302 @@ async fn delete_task(
     Ok(())
 }
 
-struct NewsletterIssue {
+/// Keep a trace of `attempt` in the delivery log, it outlives the task.
+async fn log_delivery(
+    transaction: &mut PgTransaction,
+    task: &DeliveryTask,
+    subscriber_email: &str,
+    attempt: &DeliveryAttempt,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO delivery_log (
+            task_id,
+            newsletter_issue_id,
+            onboarding_step_id,
+            subscriber_email,
+            attempt,
+            attempted_at,
+            outcome,
+            message_id,
+            error_code,
+            error_message
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        "#,
+        task.task_id,
+        task.newsletter_issue_id,
+        task.onboarding_step_id,
+        subscriber_email,
+        task.n_retries + 1,
+        attempt.attempted_at,
+        attempt.outcome.as_str(),
+        attempt.message_id,
+        attempt.error_code,
+        attempt.error_message,
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(())
+}
+
+/// How long until the next task of the queue can be executed, `None` if the queue is empty.
+#[tracing::instrument(skip_all)]
+async fn next_task_due_in(pool: &PgPool) -> Result<Option<Duration>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT EXTRACT(EPOCH FROM min(execute_after) - now())::float8 AS due_in_seconds
+        FROM issue_delivery_queue
+        "#
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row
+        .due_in_seconds
+        .map(|seconds| Duration::from_secs_f64(seconds.max(0.0))))
+}
+
+/// Sample, per message class, how many tasks are queued and how long the oldest due one
+/// has been waiting.
+#[tracing::instrument(skip_all)]
+pub async fn record_queue_metrics(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            message_class,
+            count(*) AS "depth!",
+            EXTRACT(EPOCH FROM now() - min(execute_after) FILTER (WHERE execute_after <= now()))::float8
+                AS oldest_due_task_age_seconds
+        FROM issue_delivery_queue
+        GROUP BY message_class
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    // Classes without any task are reported too, or they would keep their last value
+    for message_class in MessageClass::BY_PRIORITY {
+        let row = rows
+            .iter()
+            .find(|row| row.message_class == message_class.as_str());
+        gauge!("issue_delivery_queue_depth", "message_class" => message_class.as_str())
+            .set(row.map_or(0.0, |row| row.depth as f64));
+        gauge!(
+            "issue_delivery_queue_oldest_task_age_seconds",
+            "message_class" => message_class.as_str()
+        )
+        .set(
+            row.and_then(|row| row.oldest_due_task_age_seconds)
+                .unwrap_or(0.0),
+        );
+    }
+    Ok(())
+}
+
+struct DeliveryTask {
+    task_id: i64,
+    newsletter_issue_id: Option<Uuid>,
+    onboarding_step_id: Option<Uuid>,
+    subscriber_id: Uuid,
+    message_class: String,
+    n_retries: i16,
+    // The trace context of the request that published the issue, if any
+    trace_context: Option<Json<HashMap<String, String>>>,
+}
+
+impl DeliveryTask {
+    fn content_key(&self) -> Result<ContentKey, anyhow::Error> {
+        match (self.newsletter_issue_id, self.onboarding_step_id) {
+            (Some(issue_id), _) => Ok(ContentKey::Issue(issue_id)),
+            (None, Some(step_id)) => Ok(ContentKey::OnboardingStep(step_id)),
+            (None, None) => anyhow::bail!("Delivery task {} has no content", self.task_id),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ContentKey {
+    Issue(Uuid),
+    OnboardingStep(Uuid),
+}
+
+/// What kind of email a delivery task is for. It decides how soon it is served.
+///
+/// Transactional emails (e.g. confirmation emails) don't go through the delivery queue:
+/// they are sent right away from the email outbox.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MessageClass {
+    /// Due at a given time, e.g. onboarding steps.
+    Scheduled,
+    /// Sent to many recipients at once, e.g. newsletter issues.
+    Bulk,
+}
+
+impl MessageClass {
+    /// Highest priority first.
+    const BY_PRIORITY: [MessageClass; 2] = [MessageClass::Scheduled, MessageClass::Bulk];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageClass::Scheduled => "scheduled",
+            MessageClass::Bulk => "bulk",
+        }
+    }
+
+    // The database only holds known classes, see the check on `message_class`.
+    fn parse(s: &str) -> Self {
+        match s {
+            "scheduled" => MessageClass::Scheduled,
+            _ => MessageClass::Bulk,
+        }
+    }
+
+    fn share(&self, lanes: &LaneSettings) -> usize {
+        match self {
+            MessageClass::Scheduled => usize::from(lanes.scheduled),
+            MessageClass::Bulk => usize::from(lanes.bulk),
+        }
+    }
+
+    /// How many tasks of a batch of `batch_size` are set aside for this class.
+    fn reserved_share(&self, batch_size: usize, lanes: &LaneSettings) -> usize {
+        let total: usize = Self::BY_PRIORITY
+            .iter()
+            .map(|class| class.share(lanes))
+            .sum();
+        if total == 0 {
+            return 0;
+        }
+        batch_size * self.share(lanes) / total
+    }
+
+    /// How many of the `concurrency` requests of a batch holding emails of `classes`
+    /// this class gets: at least one.
+    fn concurrency_share(
+        &self,
+        classes: &[MessageClass],
+        settings: &DeliveryWorkerSettings,
+    ) -> usize {
+        let concurrency = usize::from(settings.concurrency.max(1));
+        let total: usize = classes
+            .iter()
+            .map(|class| class.share(&settings.lanes))
+            .sum();
+        // Without any share configured, the classes split the requests evenly.
+        let share = (concurrency * self.share(&settings.lanes))
+            .checked_div(total)
+            .unwrap_or(concurrency / classes.len());
+        share.max(1)
+    }
+}
+
+/// Claim up to `batch_size` due tasks, highest priority first.
+///
+/// Every class first gets its share of the batch: bulk sends keep moving while
+/// scheduled emails are waiting. The rest of the batch goes by priority.
+#[tracing::instrument(skip_all)]
+async fn dequeue_tasks(
+    pool: &PgPool,
+    settings: &DeliveryWorkerSettings,
+) -> Result<(PgTransaction, Vec<DeliveryTask>), anyhow::Error> {
+    let batch_size = usize::from(settings.batch_size);
+    let mut tx: PgTransaction = pool.begin().await?;
+    let mut lanes: Vec<Vec<DeliveryTask>> = vec![];
+    let mut claimed_task_ids = vec![];
+    for class in MessageClass::BY_PRIORITY {
+        let share = class.reserved_share(batch_size, &settings.lanes);
+        let tasks = claim_tasks(&mut tx, class, share, &claimed_task_ids).await?;
+        claimed_task_ids.extend(tasks.iter().map(|task| task.task_id));
+        lanes.push(tasks);
+    }
+    for (class, tasks) in MessageClass::BY_PRIORITY.iter().zip(lanes.iter_mut()) {
+        let remaining = batch_size.saturating_sub(claimed_task_ids.len());
+        if remaining == 0 {
+            break;
+        }
+        let more = claim_tasks(&mut tx, *class, remaining, &claimed_task_ids).await?;
+        claimed_task_ids.extend(more.iter().map(|task| task.task_id));
+        tasks.extend(more);
+    }
+    Ok((tx, lanes.into_iter().flatten().collect()))
+}
+
+/// Lock up to `limit` due tasks of `class`, leaving out the ones we already hold.
+async fn claim_tasks(
+    tx: &mut PgTransaction,
+    class: MessageClass,
+    limit: usize,
+    claimed_task_ids: &[i64],
+) -> Result<Vec<DeliveryTask>, sqlx::Error> {
+    if limit == 0 {
+        return Ok(vec![]);
+    }
+    sqlx::query_as!(
+        DeliveryTask,
+        r#"
+        SELECT
+            task_id,
+            newsletter_issue_id,
+            onboarding_step_id,
+            subscriber_id,
+            message_class,
+            n_retries,
+            (
+                SELECT trace_context
+                FROM newsletter_issues
+                WHERE newsletter_issue_id = issue_delivery_queue.newsletter_issue_id
+            ) AS "trace_context: Json<HashMap<String, String>>"
+        FROM issue_delivery_queue
+        WHERE
+            execute_after <= now() AND
+            message_class = $1 AND
+            task_id <> ALL($2)
+        ORDER BY task_id
+        -- Not `FOR UPDATE`: send intents are written outside of this transaction
+        -- and their foreign key needs a share lock on the task.
+        FOR NO KEY UPDATE
+        SKIP LOCKED
+        LIMIT $3
+        "#,
+        class.as_str(),
+        claimed_task_ids,
+        limit as i64,
+    )
+    .fetch_all(&mut **tx)
+    .await
+}
+
+struct EmailContent {
     title: String,
     text_content: String,
     html_content: String,
 }
 
+async fn get_email_contents(
+    pool: &PgPool,
+    tasks: &[DeliveryTask],
+) -> Result<HashMap<ContentKey, EmailContent>, anyhow::Error> {
+    let mut contents = HashMap::new();
+    for task in tasks {
+        // A task without content is reported when it is executed.
+        let Ok(key) = task.content_key() else {
+            continue;
+        };
+        if contents.contains_key(&key) {
+            continue;
+        }
+        let content = match key {
+            ContentKey::Issue(issue_id) => get_issue(pool, issue_id).await?,
+            ContentKey::OnboardingStep(step_id) => get_onboarding_step(pool, step_id).await?,
+        };
+        contents.insert(key, content);
+    }
+    Ok(contents)
+}
+
 #[tracing::instrument(skip_all)]
-async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, anyhow::Error> {
+async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<EmailContent, anyhow::Error> {
     let issue = sqlx::query_as!(
-        NewsletterIssue,
+        EmailContent,
         r#"
         SELECT title, text_content, html_content
         FROM newsletter_issues
@@ -157,3 +1023,61 @@ async fn get_issue(pool: &PgPool, issue_id: Uuid) -> Result<NewsletterIssue, any
     .await?;
     Ok(issue)
 }
+
+#[tracing::instrument(skip_all)]
+async fn get_onboarding_step(pool: &PgPool, step_id: Uuid) -> Result<EmailContent, anyhow::Error> {
+    let step = sqlx::query_as!(
+        EmailContent,
+        r#"
+        SELECT title, text_content, html_content
+        FROM onboarding_steps
+        WHERE
+            onboarding_step_id = $1"#,
+        step_id
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(step)
+}
+
+/// Who the tasks are for, as of now.
+struct Recipient {
+    email: String,
+    status: String,
+    suppressed: bool,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_recipients(
+    pool: &PgPool,
+    tasks: &[DeliveryTask],
+) -> Result<HashMap<Uuid, Recipient>, anyhow::Error> {
+    let subscriber_ids: Vec<Uuid> = tasks.iter().map(|task| task.subscriber_id).collect();
+    let recipients = sqlx::query!(
+        r#"
+        SELECT
+            id,
+            email,
+            status,
+            EXISTS (
+                SELECT 1 FROM suppression_list WHERE suppression_list.email = lower(subscriptions.email)
+            ) AS "suppressed!"
+        FROM subscriptions
+        WHERE id = ANY($1)
+        "#,
+        &subscriber_ids,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        let recipient = Recipient {
+            email: r.email,
+            status: r.status,
+            suppressed: r.suppressed,
+        };
+        (r.id, recipient)
+    })
+    .collect();
+    Ok(recipients)
+}
@@ -0,0 +1,483 @@
+use crate::configuration::WorkerSettings;
+use crate::idempotency::PurgeExpiredIdempotencyKeys;
+use crate::issue_delivery_worker::{DeliverNewsletterIssue, ExecutionOutcome};
+use crate::liveness::Heartbeat;
+use crate::queue_listener::{notify_workers, QueueListener, JOBS_CHANNEL};
+use crate::send_rate_limit::PurgeRefilledSendRateBuckets;
+use crate::shutdown::ShutdownSignal;
+use crate::startup::get_connection_pool;
+use crate::telemetry::record_pool_usage;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use metrics::counter;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::future::Future;
+use std::time::Duration;
+use tracing::field::display;
+use tracing::Span;
+
+mod purge;
+pub use purge::PurgeFinishedJobs;
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+// How many jobs the admin page shows.
+const MAX_LISTED_JOBS: i64 = 100;
+// A running job not finished by then is assumed lost (e.g. its runner crashed) and runs again.
+const JOB_LEASE: Duration = Duration::from_secs(15 * 60);
+const MIN_IDLE_WAIT: Duration = Duration::from_millis(100);
+
+/// A unit of background work, stored in the `jobs` table until a runner picks it up.
+pub trait Job: Serialize + DeserializeOwned + Send + 'static {
+    /// Tells runners which handler a stored job goes to.
+    /// Don't rename it while jobs of this type may still be queued!
+    const JOB_TYPE: &'static str;
+    /// How many times the job is attempted before it is marked as failed.
+    const MAX_ATTEMPTS: i16 = 5;
+
+    /// Jobs of the same type sharing a key are never waiting or running at the same time.
+    fn unique_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Do the work. An error means the job is attempted again later.
+    ///
+    /// `transaction` is the one marking the job as done: a job that only touches
+    /// the database takes effect exactly once. The job no longer holds its unique key in there,
+    /// so it can queue its own next run.
+    fn run(
+        self,
+        transaction: &mut PgTransaction,
+    ) -> impl Future<Output = Result<(), anyhow::Error>> + Send;
+}
+
+/// A job deleting rows we no longer need, e.g. expired idempotency keys, a batch at a time.
+///
+/// It keeps itself queued, never more than once: it runs again right away after deleting a full
+/// batch, as there may be more, and after `INTERVAL` otherwise.
+pub trait RecurringPurge: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// See `Job::JOB_TYPE`.
+    const JOB_TYPE: &'static str;
+    /// Rows deleted per run: each run is a single transaction.
+    const BATCH_SIZE: i64;
+    const INTERVAL: Duration;
+    /// Counts the deleted rows.
+    const PURGED_METRIC: &'static str;
+
+    /// Delete up to `BATCH_SIZE` rows, returns how many were deleted.
+    fn purge(
+        transaction: &mut PgTransaction,
+    ) -> impl Future<Output = Result<u64, sqlx::Error>> + Send;
+}
+
+impl<P: RecurringPurge> Job for P {
+    const JOB_TYPE: &'static str = <P as RecurringPurge>::JOB_TYPE;
+
+    fn unique_key(&self) -> Option<String> {
+        Some(<P as RecurringPurge>::JOB_TYPE.into())
+    }
+
+    #[tracing::instrument(name = "Run a recurring purge", skip_all, fields(n_purged))]
+    async fn run(self, transaction: &mut PgTransaction) -> Result<(), anyhow::Error> {
+        let n_purged = P::purge(transaction).await?;
+        Span::current().record("n_purged", n_purged);
+        tracing::info!(n_purged, "Purged the rows we no longer need");
+        counter!(P::PURGED_METRIC).increment(n_purged);
+
+        if n_purged == P::BATCH_SIZE as u64 {
+            enqueue(transaction, &self).await?;
+        } else {
+            enqueue_at(transaction, &self, Utc::now() + P::INTERVAL).await?;
+        }
+        Ok(())
+    }
+}
+
+type Handler = Box<
+    dyn for<'a> Fn(
+            serde_json::Value,
+            &'a mut PgTransaction,
+        ) -> BoxFuture<'a, Result<(), anyhow::Error>>
+        + Send
+        + Sync,
+>;
+
+/// The job types a runner knows how to execute.
+#[derive(Default)]
+pub struct JobRegistry {
+    handlers: HashMap<&'static str, Handler>,
+}
+
+impl JobRegistry {
+    pub fn register<J: Job>(mut self) -> Self {
+        self.handlers.insert(J::JOB_TYPE, Box::new(handle::<J>));
+        self
+    }
+}
+
+fn handle<J: Job>(
+    payload: serde_json::Value,
+    transaction: &mut PgTransaction,
+) -> BoxFuture<'_, Result<(), anyhow::Error>> {
+    Box::pin(async move {
+        let job: J = serde_json::from_value(payload).context("Failed to deserialize the job")?;
+        job.run(transaction).await
+    })
+}
+
+/// Every job type of the application.
+pub fn registry() -> JobRegistry {
+    JobRegistry::default()
+        .register::<DeliverNewsletterIssue>()
+        .register::<PurgeExpiredIdempotencyKeys>()
+        .register::<PurgeRefilledSendRateBuckets>()
+        .register::<PurgeFinishedJobs>()
+}
+
+/// Queue the jobs that keep themselves queued, unless they already are:
+/// on the very first start, or after one of them ran out of attempts.
+pub async fn schedule_recurring_jobs(pool: &PgPool) -> Result<(), anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+    enqueue(&mut transaction, &PurgeExpiredIdempotencyKeys).await?;
+    enqueue(&mut transaction, &PurgeRefilledSendRateBuckets).await?;
+    enqueue(&mut transaction, &PurgeFinishedJobs).await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+/// Queue `job` to run as soon as possible, see `enqueue_at`.
+pub async fn enqueue<J: Job>(
+    transaction: &mut Transaction<'_, Postgres>,
+    job: &J,
+) -> Result<bool, anyhow::Error> {
+    enqueue_at(transaction, job, Utc::now()).await
+}
+
+/// Queue `job` to run once `run_at` is reached.
+///
+/// The job is only visible to runners once `transaction` commits. Returns `false` if it was
+/// not queued: a job with the same unique key is already waiting or running.
+#[tracing::instrument(skip(transaction, job), fields(job_type = J::JOB_TYPE))]
+pub async fn enqueue_at<J: Job>(
+    transaction: &mut Transaction<'_, Postgres>,
+    job: &J,
+    run_at: DateTime<Utc>,
+) -> Result<bool, anyhow::Error> {
+    let payload = serde_json::to_value(job).context("Failed to serialize the job")?;
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO jobs (
+            job_type,
+            payload,
+            unique_key,
+            state,
+            run_at,
+            max_attempts,
+            created_at
+        )
+        VALUES ($1, $2, $3, 'queued', $4, $5, now())
+        ON CONFLICT (job_type, unique_key) WHERE state IN ('queued', 'running') DO NOTHING
+        "#,
+        J::JOB_TYPE,
+        payload,
+        job.unique_key(),
+        run_at,
+        J::MAX_ATTEMPTS,
+    );
+    let result = transaction.execute(query).await?;
+    if result.rows_affected() == 0 {
+        tracing::info!("A job with the same unique key is already queued");
+        return Ok(false);
+    }
+    notify_workers(transaction, JOBS_CHANNEL).await?;
+    Ok(true)
+}
+
+pub async fn run_jobs_until_stopped(
+    configuration: WorkerSettings,
+    shutdown: ShutdownSignal,
+) -> Result<(), anyhow::Error> {
+    let connection_pool = get_connection_pool(&configuration.database);
+    let poll_interval = configuration.jobs.poll_interval();
+    if let Err(e) = schedule_recurring_jobs(&connection_pool).await {
+        tracing::error!(
+            error.cause_chain = ?e,
+            error.message = %e,
+            "Failed to schedule the recurring jobs"
+        );
+    }
+    let heartbeat = Heartbeat::register("job_runner", configuration.worker.stall_timeout());
+    runner_loop(
+        connection_pool,
+        registry(),
+        poll_interval,
+        heartbeat,
+        shutdown,
+    )
+    .await
+}
+
+async fn runner_loop(
+    pg_pool: PgPool,
+    registry: JobRegistry,
+    poll_interval: Duration,
+    heartbeat: Heartbeat,
+    mut shutdown: ShutdownSignal,
+) -> Result<(), anyhow::Error> {
+    let mut listener = QueueListener::new(&pg_pool, JOBS_CHANNEL, poll_interval).await;
+    // The job in flight is always seen through, we only stop in between two.
+    while !shutdown.is_triggered() {
+        heartbeat.beat();
+        record_pool_usage("job_runner", &pg_pool);
+        match try_execute_job(&pg_pool, &registry).await {
+            Ok(ExecutionOutcome::EmptyQueue) => {
+                // Scheduled jobs and retries come due without any notification.
+                let wait = async {
+                    match next_job_due_in(&pg_pool).await {
+                        Ok(Some(due_in)) => listener.wait_for(due_in.max(MIN_IDLE_WAIT)).await,
+                        _ => listener.wait().await,
+                    }
+                };
+                shutdown.unless_triggered(wait).await;
+            }
+            Err(_) => {
+                shutdown
+                    .unless_triggered(tokio::time::sleep(Duration::from_secs(1)))
+                    .await;
+            }
+            Ok(ExecutionOutcome::TaskCompleted) => {}
+        }
+    }
+    tracing::info!("The job runner stopped dequeuing jobs");
+    Ok(())
+}
+
+struct ClaimedJob {
+    job_id: i64,
+    job_type: String,
+    payload: serde_json::Value,
+    n_attempts: i16,
+    max_attempts: i16,
+}
+
+/// Run the next due job, if any.
+///
+/// A failed job is attempted again after an exponential backoff, until it runs out of attempts.
+#[tracing::instrument(
+    skip_all,
+    fields(job_id = tracing::field::Empty, job_type = tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_job(
+    pool: &PgPool,
+    registry: &JobRegistry,
+) -> Result<ExecutionOutcome, anyhow::Error> {
+    let Some(job) = claim_job(pool).await? else {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    };
+    Span::current()
+        .record("job_id", job.job_id)
+        .record("job_type", display(&job.job_type));
+
+    let mut transaction = pool.begin().await?;
+    // Marked first so that the job can queue a follow-up with the same unique key.
+    // It only sticks if the job succeeds.
+    mark_as_succeeded(&mut transaction, job.job_id).await?;
+    let outcome = match registry.handlers.get(job.job_type.as_str()) {
+        Some(handler) => handler(job.payload, &mut transaction).await,
+        None => Err(anyhow::anyhow!(
+            "No handler is registered for jobs of type {}",
+            job.job_type
+        )),
+    };
+    match outcome {
+        Ok(()) => {
+            transaction.commit().await?;
+        }
+        Err(e) => {
+            // Whatever the job did is rolled back.
+            drop(transaction);
+            let give_up = job.n_attempts >= job.max_attempts;
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                n_attempts = job.n_attempts,
+                "Failed to execute a job. {}",
+                if give_up { "Giving up." } else { "Retrying later." }
+            );
+            if give_up {
+                mark_as_failed(pool, job.job_id, &e).await?;
+            } else {
+                schedule_retry(pool, job.job_id, &e).await?;
+            }
+        }
+    }
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Take the next due job, for `JOB_LEASE` at most.
+#[tracing::instrument(skip_all)]
+async fn claim_job(pool: &PgPool) -> Result<Option<ClaimedJob>, sqlx::Error> {
+    sqlx::query_as!(
+        ClaimedJob,
+        r#"
+        UPDATE jobs
+        SET
+            state = 'running',
+            n_attempts = n_attempts + 1,
+            locked_until = now() + make_interval(secs => $1)
+        WHERE job_id = (
+            SELECT job_id
+            FROM jobs
+            WHERE
+                run_at <= now() AND
+                (state = 'queued' OR (state = 'running' AND locked_until < now()))
+            ORDER BY run_at
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        )
+        RETURNING job_id, job_type, payload, n_attempts, max_attempts
+        "#,
+        JOB_LEASE.as_secs_f64(),
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+async fn mark_as_succeeded(
+    transaction: &mut PgTransaction,
+    job_id: i64,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        UPDATE jobs
+        SET state = 'succeeded', locked_until = NULL, finished_at = now()
+        WHERE job_id = $1
+        "#,
+        job_id,
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+// Exponential backoff: 30 seconds, 1 minute, 2 minutes...
+async fn schedule_retry(
+    pool: &PgPool,
+    job_id: i64,
+    error: &anyhow::Error,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE jobs
+        SET
+            state = 'queued',
+            locked_until = NULL,
+            last_error = $2,
+            run_at = now() + make_interval(secs => 30 * 2 ^ (n_attempts - 1))
+        WHERE job_id = $1
+        "#,
+        job_id,
+        format!("{:#}", error),
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+async fn mark_as_failed(
+    pool: &PgPool,
+    job_id: i64,
+    error: &anyhow::Error,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE jobs
+        SET state = 'failed', locked_until = NULL, last_error = $2, finished_at = now()
+        WHERE job_id = $1
+        "#,
+        job_id,
+        format!("{:#}", error),
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// How long until the next job can be executed, `None` if there is none left.
+#[tracing::instrument(skip_all)]
+async fn next_job_due_in(pool: &PgPool) -> Result<Option<Duration>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT EXTRACT(EPOCH FROM min(
+            CASE state WHEN 'running' THEN locked_until ELSE run_at END
+        ) - now())::float8 AS due_in_seconds
+        FROM jobs
+        WHERE state IN ('queued', 'running')
+        "#
+    )
+    .fetch_one(pool)
+    .await?;
+    Ok(row
+        .due_in_seconds
+        .map(|seconds| Duration::from_secs_f64(seconds.max(0.0))))
+}
+
+/// A job as listed in the admin.
+pub struct JobSummary {
+    pub job_id: i64,
+    pub job_type: String,
+    pub unique_key: Option<String>,
+    pub state: String,
+    pub run_at: DateTime<Utc>,
+    pub n_attempts: i16,
+    pub max_attempts: i16,
+    pub last_error: Option<String>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+/// The latest jobs, most recent first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_recent_jobs(pool: &PgPool) -> Result<Vec<JobSummary>, sqlx::Error> {
+    sqlx::query_as!(
+        JobSummary,
+        r#"
+        SELECT
+            job_id,
+            job_type,
+            unique_key,
+            state,
+            run_at,
+            n_attempts,
+            max_attempts,
+            last_error,
+            finished_at
+        FROM jobs
+        ORDER BY job_id DESC
+        LIMIT $1
+        "#,
+        MAX_LISTED_JOBS,
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// How many jobs are in each state, e.g. `[("failed", 2), ("queued", 10)]`.
+#[tracing::instrument(skip(pool))]
+pub async fn count_jobs_by_state(pool: &PgPool) -> Result<Vec<(String, i64)>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT state, count(*) AS "count!"
+        FROM jobs
+        GROUP BY state
+        ORDER BY state
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(|r| (r.state, r.count)).collect())
+}
@@ -0,0 +1,39 @@
+use crate::jobs::{PgTransaction, RecurringPurge};
+use sqlx::Executor;
+use std::time::Duration;
+
+// Succeeded and failed jobs are kept this long for the admin view.
+const FINISHED_JOB_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const FINISHED_JOBS_PURGE_BATCH_SIZE: i64 = 1000;
+
+/// Delete the jobs that finished more than `FINISHED_JOB_RETENTION` ago, succeeded or failed.
+///
+/// Queued and running jobs are left alone, whatever their age.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PurgeFinishedJobs;
+
+impl RecurringPurge for PurgeFinishedJobs {
+    const JOB_TYPE: &'static str = "purge_finished_jobs";
+    const BATCH_SIZE: i64 = FINISHED_JOBS_PURGE_BATCH_SIZE;
+    const INTERVAL: Duration = Duration::from_secs(60 * 60);
+    const PURGED_METRIC: &'static str = "finished_jobs_purged_total";
+
+    async fn purge(transaction: &mut PgTransaction) -> Result<u64, sqlx::Error> {
+        let query = sqlx::query!(
+            r#"
+            DELETE FROM jobs
+            WHERE job_id IN (
+                SELECT job_id
+                FROM jobs
+                WHERE
+                    state IN ('succeeded', 'failed') AND
+                    finished_at < now() - make_interval(secs => $1)
+                LIMIT $2
+            )
+            "#,
+            FINISHED_JOB_RETENTION.as_secs_f64(),
+            FINISHED_JOBS_PURGE_BATCH_SIZE,
+        );
+        Ok(transaction.execute(query).await?.rows_affected())
+    }
+}
@@ -5,13 +5,25 @@
 pub use configuration::DatabaseSettings;
 
 pub mod authentication;
+pub mod circuit_breaker;
 pub mod configuration;
+pub mod consent;
 pub mod domain;
 pub mod email_client;
+pub mod email_events;
+pub mod email_outbox;
 pub mod idempotency;
 pub mod issue_delivery_worker;
+pub mod jobs;
+pub mod liveness;
+pub mod queue_listener;
 pub mod routes;
+pub mod send_intent;
+pub mod send_rate_limit;
 pub mod session_state;
+pub mod shutdown;
 pub mod startup;
+pub mod subscriber_attributes;
+pub mod suppression;
 pub mod telemetry;
 pub mod utils;
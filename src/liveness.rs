@@ -0,0 +1,84 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// The background loops of this process, by heartbeat id.
+static HEARTBEATS: Lazy<Mutex<HashMap<u64, LastBeat>>> = Lazy::new(Default::default);
+static NEXT_HEARTBEAT_ID: AtomicU64 = AtomicU64::new(0);
+
+struct LastBeat {
+    name: &'static str,
+    at: Instant,
+    stall_timeout: Duration,
+}
+
+/// Tells the health checks that a background loop (e.g. the delivery worker) is making progress.
+///
+/// A loop registers one when it starts and beats on every iteration. It is considered stalled
+/// once it went `stall_timeout` without beating, and it is no longer tracked once its heartbeat
+/// is dropped: a loop that returned is reported by `main`, not by the health checks.
+pub struct Heartbeat {
+    id: u64,
+}
+
+impl Heartbeat {
+    pub fn register(name: &'static str, stall_timeout: Duration) -> Self {
+        let id = NEXT_HEARTBEAT_ID.fetch_add(1, Ordering::Relaxed);
+        let last_beat = LastBeat {
+            name,
+            at: Instant::now(),
+            stall_timeout,
+        };
+        HEARTBEATS.lock().unwrap().insert(id, last_beat);
+        Self { id }
+    }
+
+    pub fn beat(&self) {
+        if let Some(last_beat) = HEARTBEATS.lock().unwrap().get_mut(&self.id) {
+            last_beat.at = Instant::now();
+        }
+    }
+}
+
+impl Drop for Heartbeat {
+    fn drop(&mut self) {
+        HEARTBEATS.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The background loops of this process that did not beat within their stall timeout.
+pub fn stalled_loops() -> Vec<&'static str> {
+    HEARTBEATS
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|last_beat| last_beat.at.elapsed() > last_beat.stall_timeout)
+        .map(|last_beat| last_beat.name)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{stalled_loops, Heartbeat};
+    use std::time::Duration;
+
+    #[test]
+    fn a_loop_is_stalled_once_it_misses_its_stall_timeout() {
+        let heartbeat = Heartbeat::register("stalled_loop", Duration::ZERO);
+        std::thread::sleep(Duration::from_millis(1));
+
+        assert!(stalled_loops().contains(&"stalled_loop"));
+        drop(heartbeat);
+        assert!(!stalled_loops().contains(&"stalled_loop"));
+    }
+
+    #[test]
+    fn a_loop_that_beats_is_not_stalled() {
+        let heartbeat = Heartbeat::register("live_loop", Duration::from_secs(60));
+        heartbeat.beat();
+
+        assert!(!stalled_loops().contains(&"live_loop"));
+    }
+}
@@ -1,23 +1,35 @@
+use std::collections::HashMap;
 use std::fmt::{Debug, Display};
-use tokio::task::JoinError;
-use zero2prod::configuration::get_configuration;
+use tokio::task::{JoinError, JoinSet};
+use zero2prod::configuration::{
+    get_configuration, get_telemetry_configuration, get_worker_configuration,
+};
+use zero2prod::email_outbox::run_dispatcher_until_stopped;
 use zero2prod::issue_delivery_worker::run_worker_until_stopped;
-use zero2prod::startup::Application;
-use zero2prod::telemetry::{get_subscriber, init_subscriber};
+use zero2prod::jobs::run_jobs_until_stopped;
+use zero2prod::shutdown::{shutdown_channel, termination_signal};
+use zero2prod::startup::{Application, WorkerApplication};
+use zero2prod::telemetry::{flush_traces, get_subscriber, init_subscriber};
 
 // this is a binary crate because it contains a main function
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let subscriber = get_subscriber("zero2prod".into(), "info".into(), std::io::stdout);
+    let telemetry = get_telemetry_configuration().expect("Failed to read configuration.");
+    let subscriber = get_subscriber(
+        "zero2prod".into(),
+        "info".into(),
+        std::io::stdout,
+        &telemetry,
+    );
     init_subscriber(subscriber);
 
-    // Panic if we can't read configuration
-    let configuration = get_configuration().expect("Failed to read configuration.");
-    let application = Application::build(configuration.clone()).await?;
-    let application_task = tokio::spawn(application.run_until_stopped());
-    let worker_task = tokio::spawn(run_worker_until_stopped(configuration));
+    // `zero2prod serve`, `zero2prod worker` or `zero2prod all` (the default)
+    let mode: Mode = match std::env::args().nth(1) {
+        Some(mode) => mode.try_into().map_err(anyhow::Error::msg)?,
+        None => Mode::All,
+    };
+    tracing::info!("Starting in `{}` mode", mode.as_str());
 
-    // tokio::select! returns as soon as one of the two tasks completes or errors out
     // There's a pitfall to be mindful of when using tokio::select! - all selected Futures are
     // polled as a single task. This has consequences, as tokio’s documentation highlights:
     //
@@ -26,21 +38,121 @@ async fn main() -> anyhow::Result<()> {
     // if one branch blocks the thread, all other expressions will be unable to continue.
     // If parallelism is required, spawn each async expression using tokio::spawn and pass the join
     // handle to select!.”
+    //
+    // Each task is spawned on its own: the `JoinSet` hands them back as they complete.
+    let mut tasks = JoinSet::new();
+    let mut task_names = HashMap::new();
+    let mut server_handles = vec![];
+    let (shutdown_trigger, shutdown) = shutdown_channel();
+
+    // Panic if we can't read configuration
+    let shutdown_timeout = match mode {
+        Mode::Serve | Mode::All => {
+            let configuration = get_configuration().expect("Failed to read configuration.");
+            let application = Application::build(configuration.clone()).await?;
+            server_handles.extend(application.handles());
+            let id = tasks
+                .spawn(async move { Ok(application.run_until_stopped().await?) })
+                .id();
+            task_names.insert(id, "API");
+            configuration.shutdown.timeout()
+        }
+        Mode::Worker => {
+            // In `all` mode the health check of the API covers the workers running alongside it
+            let configuration = get_worker_configuration().expect("Failed to read configuration.");
+            let health_check = WorkerApplication::build(&configuration)?;
+            server_handles.push(health_check.handle());
+            let id = tasks
+                .spawn(async move { Ok(health_check.run_until_stopped().await?) })
+                .id();
+            task_names.insert(id, "Worker health check");
+            configuration.shutdown.timeout()
+        }
+    };
+    if let Mode::Worker | Mode::All = mode {
+        let configuration = get_worker_configuration().expect("Failed to read configuration.");
+        let id = tasks
+            .spawn(run_worker_until_stopped(
+                configuration.clone(),
+                shutdown.clone(),
+            ))
+            .id();
+        task_names.insert(id, "Background worker");
+        let id = tasks
+            .spawn(run_dispatcher_until_stopped(
+                configuration.clone(),
+                shutdown.clone(),
+            ))
+            .id();
+        task_names.insert(id, "Email outbox dispatcher");
+        let id = tasks
+            .spawn(run_jobs_until_stopped(configuration, shutdown))
+            .id();
+        task_names.insert(id, "Job runner");
+    }
 
+    // Run until we are asked to stop, or until one of the tasks exits on its own.
     tokio::select! {
-        outcome = application_task => report_exit("API", outcome),
-        outcome = worker_task =>  report_exit("Background worker", outcome),
+        Some(outcome) = tasks.join_next_with_id() => {
+            report_exit(&mut task_names, outcome);
+            tracing::warn!("Shutting down the remaining tasks");
+        }
+        signal = termination_signal() => {
+            tracing::info!("Received {}, shutting down gracefully", signal);
+        }
     };
 
+    // Phase 1: stop taking new work. In-flight requests and batches are seen through.
+    tracing::info!(
+        timeout_seconds = shutdown_timeout.as_secs(),
+        "Servers stop accepting connections and the workers stop dequeuing"
+    );
+    shutdown_trigger.trigger();
+    let stop_servers = futures::future::join_all(
+        server_handles
+            .iter()
+            .map(|server_handle| server_handle.stop(true)),
+    );
+
+    // Phase 2: wait for everything to wind down, up to the deadline.
+    let drained = tokio::time::timeout(shutdown_timeout, async {
+        stop_servers.await;
+        tracing::info!("In-flight requests have been served");
+        while let Some(outcome) = tasks.join_next_with_id().await {
+            report_exit(&mut task_names, outcome);
+        }
+    })
+    .await;
+    match drained {
+        Ok(()) => tracing::info!("Shutdown complete"),
+        Err(_) => {
+            // Phase 3: out of time, whatever is left gets cut short.
+            for task_name in task_names.values() {
+                tracing::error!(
+                    "{} did not stop within the shutdown deadline, aborting it",
+                    task_name
+                );
+            }
+            tasks.shutdown().await;
+        }
+    }
+    flush_traces();
+
     Ok(())
 }
 
-fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>, JoinError>) {
+// Log how a task ended. It is no longer tracked in `task_names` afterwards.
+fn report_exit(
+    task_names: &mut HashMap<tokio::task::Id, &str>,
+    outcome: Result<(tokio::task::Id, Result<(), impl Debug + Display>), JoinError>,
+) {
     match outcome {
-        Ok(Ok(())) => {
+        Ok((id, Ok(()))) => {
+            let task_name = task_names.remove(&id).unwrap_or_default();
             tracing::info!("{} has exited", task_name)
         }
-        Ok(Err(e)) => {
+        Ok((id, Err(e))) => {
+            let task_name = task_names.remove(&id).unwrap_or_default();
             tracing::error!(
                 error.cause_chain = ?e,
                 error.message = %e,
@@ -49,6 +161,7 @@ fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>
             )
         }
         Err(e) => {
+            let task_name = task_names.remove(&e.id()).unwrap_or_default();
             tracing::error!(
                 error.cause_chain = ?e,
                 error.message = %e,
@@ -58,3 +171,40 @@ fn report_exit(task_name: &str, outcome: Result<Result<(), impl Debug + Display>
         }
     }
 }
+
+/// Which parts of the application this process runs.
+///
+/// The API and the workers only share the database: they can be scaled independently.
+enum Mode {
+    // The HTTP API only. Emails pile up in the queues until a worker picks them up.
+    Serve,
+    // The delivery worker and the email outbox dispatcher, without the API.
+    Worker,
+    All,
+}
+
+impl Mode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Serve => "serve",
+            Mode::Worker => "worker",
+            Mode::All => "all",
+        }
+    }
+}
+
+impl TryFrom<String> for Mode {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "serve" => Ok(Self::Serve),
+            "worker" => Ok(Self::Worker),
+            "all" => Ok(Self::All),
+            other => Err(format!(
+                "{} is not a supported mode. Use either `serve`, `worker` or `all`.",
+                other
+            )),
+        }
+    }
+}
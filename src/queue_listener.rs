@@ -0,0 +1,89 @@
+use sqlx::postgres::PgListener;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::time::Duration;
+
+/// Notified whenever tasks are added to `issue_delivery_queue`.
+pub const DELIVERY_QUEUE_CHANNEL: &str = "issue_delivery_queue";
+/// Notified whenever an email is added to `email_outbox`.
+pub const EMAIL_OUTBOX_CHANNEL: &str = "email_outbox";
+/// Notified whenever a job is added to `jobs`.
+pub const JOBS_CHANNEL: &str = "jobs";
+
+/// Let the workers listening on `channel` know that there is new work for them.
+///
+/// Postgres only delivers the notification once `transaction` commits,
+/// so workers never wake up before the new rows are visible.
+#[tracing::instrument(skip(transaction))]
+pub async fn notify_workers(
+    transaction: &mut Transaction<'_, Postgres>,
+    channel: &str,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!("SELECT pg_notify($1, '')", channel);
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+/// Lets an idle worker sleep until a producer notifies its channel.
+///
+/// Notifications are not persisted (e.g. they are lost while the connection is being
+/// re-established), so the worker also wakes up every `poll_interval` to check the queue.
+pub struct QueueListener {
+    // `None` if we failed to subscribe: we then fall back to plain polling.
+    listener: Option<PgListener>,
+    poll_interval: Duration,
+}
+
+impl QueueListener {
+    pub async fn new(pool: &PgPool, channel: &str, poll_interval: Duration) -> Self {
+        let listener = match listen(pool, channel).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to listen on {}. Falling back to polling.",
+                    channel
+                );
+                None
+            }
+        };
+        Self {
+            listener,
+            poll_interval,
+        }
+    }
+
+    /// Returns as soon as a notification is received, or once `poll_interval` has elapsed.
+    pub async fn wait(&mut self) {
+        self.wait_for(self.poll_interval).await
+    }
+
+    /// Like `wait`, but returns after `timeout` if it's shorter than `poll_interval`
+    /// (e.g. when the worker knows a deferred task is about to come due).
+    pub async fn wait_for(&mut self, timeout: Duration) {
+        let timeout = timeout.min(self.poll_interval);
+        let Some(listener) = self.listener.as_mut() else {
+            tokio::time::sleep(timeout).await;
+            return;
+        };
+        match tokio::time::timeout(timeout, listener.recv()).await {
+            // Woken up by a producer or time to poll
+            Ok(Ok(_)) | Err(_) => {}
+            Ok(Err(e)) => {
+                // The listener reconnects on the next call to `recv`: don't spin meanwhile.
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to receive a queue notification"
+                );
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn listen(pool: &PgPool, channel: &str) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(channel).await?;
+    Ok(listener)
+}
@@ -2,15 +2,19 @@ mod admin;
 mod health_check;
 mod home;
 mod login;
+mod prometheus;
 mod subscriptions;
 mod subscriptions_confirmation;
+mod webhooks;
 
 pub use admin::*;
 pub use health_check::*;
 pub use home::*;
 pub use login::*;
+pub use prometheus::*;
 pub use subscriptions::*;
 pub use subscriptions_confirmation::*;
+pub use webhooks::*;
 
 // A package is a bundle of one or more crates that provides a set of functionality
 
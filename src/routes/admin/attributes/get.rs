@@ -0,0 +1,109 @@
+use crate::subscriber_attributes::get_attribute_definitions;
+use crate::utils::e500;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use htmlescape::encode_minimal;
+use sqlx::PgPool;
+use std::fmt::Write;
+
+pub async fn attributes_form(
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", m.content()).unwrap();
+    }
+
+    let definitions = get_attribute_definitions(&pool).await.map_err(e500)?;
+    let mut attributes_html = String::new();
+    for definition in definitions {
+        let options = if definition.options.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", encode_minimal(&definition.options.join(", ")))
+        };
+        writeln!(
+            attributes_html,
+            r#"<li>{} - <code>{}</code>: {}{}{}
+            <form action="/admin/attributes/{}/delete" method="post">
+                <input type="submit" value="Delete">
+            </form>
+        </li>"#,
+            encode_minimal(&definition.label),
+            definition.key,
+            definition.kind.as_str(),
+            options,
+            if definition.required {
+                ", required"
+            } else {
+                ""
+            },
+            definition.attribute_id,
+        )
+        .unwrap();
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscriber attributes</title>
+</head>
+<body>
+    {msg_html}
+    <p>Extra fields collected on the signup form, using their key as field name:</p>
+    <ul>
+        {attributes_html}
+    </ul>
+    <form action="/admin/attributes" method="post">
+        <label>Key:<br>
+            <input
+                type="text"
+                placeholder="e.g. company_size"
+                name="key"
+            >
+        </label>
+        <br>
+        <label>Label:<br>
+            <input
+                type="text"
+                placeholder="e.g. Company size"
+                name="label"
+            >
+        </label>
+        <br>
+        <label>Kind:<br>
+            <select name="kind">
+                <option value="text">Text</option>
+                <option value="number">Number</option>
+                <option value="date">Date</option>
+                <option value="boolean">Boolean</option>
+                <option value="enum">Enum</option>
+            </select>
+        </label>
+        <br>
+        <label>Options (enum only, comma-separated):<br>
+            <input
+                type="text"
+                placeholder="e.g. small, medium, large"
+                name="options"
+            >
+        </label>
+        <br>
+        <label>
+            <input type="checkbox" name="required" value="true">
+            Required
+        </label>
+        <br>
+        <button type="submit">Add attribute</button>
+    </form>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
@@ -0,0 +1,76 @@
+use crate::domain::NewAttributeDefinition;
+use crate::subscriber_attributes::insert_attribute_definition;
+use crate::utils::{e500, see_other};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    key: String,
+    label: String,
+    kind: String,
+    #[serde(default)]
+    options: String,
+    // Unticked checkboxes are not submitted at all
+    required: Option<String>,
+}
+
+#[tracing::instrument(name = "Add a subscriber attribute", skip(form, pool))]
+pub async fn add_attribute(
+    pool: web::Data<PgPool>,
+    form: web::Form<FormData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let FormData {
+        key,
+        label,
+        kind,
+        options,
+        required,
+    } = form.0;
+    let definition =
+        match NewAttributeDefinition::parse(key, label, &kind, &options, required.is_some()) {
+            Ok(definition) => definition,
+            Err(e) => {
+                FlashMessage::error(e).send();
+                return Ok(see_other("/admin/attributes"));
+            }
+        };
+
+    let inserted = insert_attribute_definition(&pool, &definition)
+        .await
+        .context("Failed to store the subscriber attribute")
+        .map_err(e500)?;
+    if !inserted {
+        FlashMessage::error(format!(
+            "An attribute with key {} already exists.",
+            definition.key
+        ))
+        .send();
+        return Ok(see_other("/admin/attributes"));
+    }
+
+    FlashMessage::info("The attribute has been added.").send();
+    Ok(see_other("/admin/attributes"))
+}
+
+#[tracing::instrument(name = "Delete a subscriber attribute", skip(pool))]
+pub async fn delete_attribute(
+    pool: web::Data<PgPool>,
+    attribute_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // The values stored for the attribute are removed along with it (ON DELETE CASCADE)
+    sqlx::query!(
+        "DELETE FROM subscriber_attribute_definitions WHERE attribute_id = $1",
+        attribute_id.into_inner()
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to delete the subscriber attribute")
+    .map_err(e500)?;
+
+    FlashMessage::info("The attribute has been deleted.").send();
+    Ok(see_other("/admin/attributes"))
+}
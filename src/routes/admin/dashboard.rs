@@ -1,17 +1,21 @@
 use crate::authentication::UserId;
+use crate::email_client::EmailClient;
 use crate::utils::e500;
 use actix_web::http::header::ContentType;
 use actix_web::{web, HttpResponse};
 use anyhow::Context;
+use htmlescape::encode_minimal;
 use sqlx::PgPool;
 use uuid::Uuid;
 
 pub async fn admin_dashboard(
     pg_pool: web::Data<PgPool>,
+    email_client: web::Data<EmailClient>,
     user_id: web::ReqData<UserId>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let user_id = user_id.into_inner();
     let username = get_username(*user_id, &pg_pool).await.map_err(e500)?;
+    let sandbox_html = sandbox_banner(&email_client);
 
     Ok(HttpResponse::Ok()
         .content_type(ContentType::html())
@@ -23,10 +27,16 @@ pub async fn admin_dashboard(
     <title>Admin dashboard</title>
 </head>
 <body>
+    {sandbox_html}
     <p>Welcome {username}!</p>
     <p>Available actions:</p>
     <ol>
         <li><a href="/admin/password">Change password</a></li>
+        <li><a href="/admin/onboarding">Manage the onboarding sequence</a></li>
+        <li><a href="/admin/subscribers">Browse subscribers</a></li>
+        <li><a href="/admin/attributes">Manage subscriber attributes</a></li>
+        <li><a href="/admin/email-events">Review bounces and spam complaints</a></li>
+        <li><a href="/admin/jobs">Monitor background jobs</a></li>
         <li>
             <form name="logoutForm" action="/admin/logout" method="post">
                 <input type="submit" value="Logout">
@@ -38,6 +48,19 @@ pub async fn admin_dashboard(
         )))
 }
 
+/// A warning that subscribers won't get the emails we send, empty outside of sandbox mode.
+pub fn sandbox_banner(email_client: &EmailClient) -> String {
+    match email_client.sandbox() {
+        Some(sandbox) => format!(
+            r#"<p id="sandbox-banner" style="background: #ffd54f; padding: 1em; font-weight: bold">
+        Sandbox mode: {}.
+    </p>"#,
+            encode_minimal(&sandbox.describe())
+        ),
+        None => String::new(),
+    }
+}
+
 #[tracing::instrument(name = "Get username", skip(pool))]
 pub async fn get_username(user_id: Uuid, pool: &PgPool) -> Result<String, anyhow::Error> {
     let row = sqlx::query!(
@@ -0,0 +1,83 @@
+use crate::email_events::get_recent_email_events;
+use crate::suppression::get_suppression_list;
+use crate::utils::e500;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use htmlescape::encode_minimal;
+use sqlx::PgPool;
+use std::fmt::Write;
+
+/// What the email provider reported about our recipients, and who we stopped emailing.
+pub async fn email_events(pool: web::Data<PgPool>) -> Result<HttpResponse, actix_web::Error> {
+    let events = get_recent_email_events(&pool).await.map_err(e500)?;
+    let suppression_list = get_suppression_list(&pool).await.map_err(e500)?;
+
+    let mut events_html = String::new();
+    for event in events {
+        writeln!(
+            events_html,
+            r#"<tr>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+        </tr>"#,
+            event.occurred_at.to_rfc3339(),
+            encode_minimal(&event.record_type),
+            encode_minimal(&event.email),
+            encode_minimal(event.description.as_deref().unwrap_or_default()),
+            encode_minimal(event.message_id.as_deref().unwrap_or_default()),
+            if event.suppressed { "yes" } else { "no" },
+        )
+        .unwrap();
+    }
+    let mut suppressed_html = String::new();
+    for address in suppression_list {
+        writeln!(
+            suppressed_html,
+            r#"<tr>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+        </tr>"#,
+            encode_minimal(&address.email),
+            address.reason,
+            address.suppressed_at.to_rfc3339(),
+        )
+        .unwrap();
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Email events</title>
+</head>
+<body>
+    <p>Latest events reported by the email provider:</p>
+    <table>
+        <tr>
+            <th>Occurred at</th>
+            <th>Type</th>
+            <th>Email</th>
+            <th>Description</th>
+            <th>Message ID</th>
+            <th>Suppressed</th>
+        </tr>
+        {events_html}
+    </table>
+    <p>Suppressed addresses:</p>
+    <table>
+        <tr><th>Email</th><th>Reason</th><th>Suppressed at</th></tr>
+        {suppressed_html}
+    </table>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
@@ -0,0 +1,85 @@
+use crate::jobs::{count_jobs_by_state, get_recent_jobs};
+use crate::utils::e500;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use htmlescape::encode_minimal;
+use sqlx::PgPool;
+use std::fmt::Write;
+
+/// What the background jobs are up to, and why the failed ones failed.
+pub async fn jobs(pool: web::Data<PgPool>) -> Result<HttpResponse, actix_web::Error> {
+    let counts = count_jobs_by_state(&pool).await.map_err(e500)?;
+    let jobs = get_recent_jobs(&pool).await.map_err(e500)?;
+
+    let mut counts_html = String::new();
+    for (state, count) in counts {
+        writeln!(
+            counts_html,
+            "<li>{}: {}</li>",
+            encode_minimal(&state),
+            count
+        )
+        .unwrap();
+    }
+    let mut jobs_html = String::new();
+    for job in jobs {
+        writeln!(
+            jobs_html,
+            r#"<tr>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}/{}</td>
+            <td>{}</td>
+            <td>{}</td>
+        </tr>"#,
+            job.job_id,
+            encode_minimal(&job.job_type),
+            encode_minimal(job.unique_key.as_deref().unwrap_or_default()),
+            encode_minimal(&job.state),
+            job.run_at.to_rfc3339(),
+            job.n_attempts,
+            job.max_attempts,
+            encode_minimal(job.last_error.as_deref().unwrap_or_default()),
+            job.finished_at
+                .map(|finished_at| finished_at.to_rfc3339())
+                .unwrap_or_default(),
+        )
+        .unwrap();
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Background jobs</title>
+</head>
+<body>
+    <p>Jobs by state:</p>
+    <ul>
+        {counts_html}
+    </ul>
+    <p>Latest jobs:</p>
+    <table>
+        <tr>
+            <th>ID</th>
+            <th>Type</th>
+            <th>Unique key</th>
+            <th>State</th>
+            <th>Run at</th>
+            <th>Attempts</th>
+            <th>Last error</th>
+            <th>Finished at</th>
+        </tr>
+        {jobs_html}
+    </table>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
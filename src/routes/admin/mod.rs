@@ -1,8 +1,18 @@
+mod attributes;
 pub mod dashboard;
+mod email_events;
+mod jobs;
 mod logout;
 mod newsletter;
+mod onboarding;
 mod password;
+mod subscribers;
 
+pub use attributes::*;
+pub use email_events::*;
+pub use jobs::*;
 pub use logout::*;
 pub use newsletter::*;
+pub use onboarding::*;
 pub use password::*;
+pub use subscribers::*;
@@ -1,12 +1,16 @@
+use crate::email_client::EmailClient;
+use crate::routes::dashboard::sandbox_banner;
 use actix_web::http::header::ContentType;
-use actix_web::HttpResponse;
+use actix_web::{web, HttpResponse};
 use actix_web_flash_messages::IncomingFlashMessages;
 use std::fmt::Write;
 use uuid::Uuid;
 
 pub async fn publish_newsletter_form(
     flash_messages: IncomingFlashMessages,
+    email_client: web::Data<EmailClient>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    let sandbox_html = sandbox_banner(&email_client);
     let mut msg_html = String::new();
     for m in flash_messages.iter() {
         writeln!(msg_html, "<p><i>{}</i></p>", m.content()).unwrap();
@@ -24,6 +28,7 @@ pub async fn publish_newsletter_form(
     <title>Publish Newsletter Issue</title>
 </head>
 <body>
+    {sandbox_html}
     {msg_html}
     <form action="/admin/newsletters" method="post">
         <label>Title:<br>
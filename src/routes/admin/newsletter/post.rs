@@ -1,10 +1,13 @@
-use crate::authentication::UserId;
-use crate::idempotency::{save_response, try_processing, IdempotencyKey, NextAction};
-use crate::utils::{e400, e500, see_other};
+use crate::idempotency::{IdempotentTransaction, OnReplay};
+use crate::issue_delivery_worker::DeliverNewsletterIssue;
+use crate::jobs::enqueue;
+use crate::telemetry::current_trace_context;
+use crate::utils::{e500, see_other};
 use actix_web::{web, HttpResponse};
 use actix_web_flash_messages::FlashMessage;
 use anyhow::Context;
-use sqlx::{Executor, PgPool, Postgres, Transaction};
+use sqlx::types::Json;
+use sqlx::{Executor, Postgres, Transaction};
 use uuid::Uuid;
 
 #[derive(serde::Deserialize)]
@@ -12,54 +15,43 @@ pub struct FormData {
     title: String,
     text_content: String,
     html_content: String,
-    idempotency_key: String,
 }
 
-#[tracing::instrument(name = "Publish a newsletter issue", skip(form, pool, user_id))]
+// Retries are answered by `ensure_idempotency`, with the response saved for the first attempt.
+#[tracing::instrument(name = "Publish a newsletter issue", skip_all)]
 pub async fn publish_newsletter(
-    pool: web::Data<PgPool>,
     form: web::Form<FormData>,
-    user_id: web::ReqData<UserId>,
+    transaction: IdempotentTransaction,
 ) -> Result<HttpResponse, actix_web::Error> {
-    // Consume the ReqData and return its wrapped value.
-    let user_id = user_id.into_inner();
     // Destructure the form to avoid upsetting the borrow-checker
     let FormData {
         title,
         text_content,
         html_content,
-        idempotency_key,
     } = form.0;
-    let idempotency_key: IdempotencyKey = idempotency_key.try_into().map_err(e400)?;
-    let mut transaction = match try_processing(&pool, &idempotency_key, *user_id)
-        .await
-        .map_err(e500)?
-    {
-        NextAction::StartProcessing(transaction) => transaction,
-        NextAction::ReturnSavedResponse(saved_response) => {
-            success_message().send();
-            // return early if we have a save newsletter response
-            return Ok(saved_response);
-        }
-    };
+    let mut transaction = transaction.lock().await;
 
     let issue_id = insert_newsletter_issue(&mut transaction, &title, &text_content, &html_content)
         .await
         .context("Failed to store newsletter issue details")
         .map_err(e500)?;
 
-    enqueue_delivery_tasks(&mut transaction, issue_id)
-        .await
-        .context("Failed to enqueue delivery tasks")
-        .map_err(e500)?;
-
-    let response = see_other("/admin/newsletters");
-    let response = save_response(transaction, &idempotency_key, *user_id, response)
+    // Fanning the issue out to every subscriber is left to the job runner.
+    let job = DeliverNewsletterIssue {
+        newsletter_issue_id: issue_id,
+    };
+    enqueue(&mut transaction, &job)
         .await
+        .context("Failed to enqueue the delivery of the newsletter issue")
         .map_err(e500)?;
 
     success_message().send();
-    Ok(response)
+    Ok(see_other("/admin/newsletters"))
+}
+
+/// Retries answered with the saved response are told that the issue was accepted, too.
+pub fn publish_newsletter_on_replay() -> OnReplay {
+    OnReplay(|| success_message().send())
 }
 
 fn success_message() -> FlashMessage {
@@ -81,36 +73,18 @@ async fn insert_newsletter_issue(
             title, 
             text_content, 
             html_content,
-            published_at
+            published_at,
+            trace_context
         )
-        VALUES ($1, $2, $3, $4, now())
+        VALUES ($1, $2, $3, $4, now(), $5)
         "#,
         newsletter_issue_id,
         title,
         text_content,
         html_content,
+        // The spans delivering the issue link back to this request
+        Json(current_trace_context()) as _,
     );
     transaction.execute(sql_query).await?;
     Ok(newsletter_issue_id)
 }
-
-#[tracing::instrument(skip_all)]
-async fn enqueue_delivery_tasks(
-    transaction: &mut Transaction<'_, Postgres>,
-    newsletter_issue_id: Uuid,
-) -> Result<(), sqlx::Error> {
-    let sql_query = sqlx::query!(
-        r#"
-        INSERT INTO issue_delivery_queue (
-            newsletter_issue_id, 
-            subscriber_email
-        )
-        SELECT $1, email
-        FROM subscriptions
-        WHERE status = 'confirmed'
-        "#,
-        newsletter_issue_id,
-    );
-    transaction.execute(sql_query).await?;
-    Ok(())
-}
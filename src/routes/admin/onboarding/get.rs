@@ -0,0 +1,115 @@
+use crate::utils::e500;
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use anyhow::Context;
+use sqlx::PgPool;
+use std::fmt::Write;
+use uuid::Uuid;
+
+struct OnboardingStep {
+    onboarding_step_id: Uuid,
+    title: String,
+    delay_hours: i32,
+}
+
+pub async fn onboarding_form(
+    pool: web::Data<PgPool>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", m.content()).unwrap();
+    }
+
+    let steps = get_onboarding_steps(&pool).await.map_err(e500)?;
+    let mut steps_html = String::new();
+    for step in steps {
+        writeln!(
+            steps_html,
+            r#"<li>{} hour(s) after confirmation: {}
+            <form action="/admin/onboarding/{}/delete" method="post">
+                <input type="submit" value="Delete">
+            </form>
+        </li>"#,
+            step.delay_hours,
+            htmlescape::encode_minimal(&step.title),
+            step.onboarding_step_id
+        )
+        .unwrap();
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Onboarding Sequence</title>
+</head>
+<body>
+    {msg_html}
+    <p>Emails sent to every subscriber once they confirm their subscription:</p>
+    <ol>
+        {steps_html}
+    </ol>
+    <form action="/admin/onboarding" method="post">
+        <label>Title:<br>
+            <input
+                type="text"
+                placeholder="Enter the email title"
+                name="title"
+            >
+        </label>
+        <br>
+        <label>Delay after confirmation (hours):<br>
+            <input
+                type="number"
+                min="0"
+                value="0"
+                name="delay_hours"
+            >
+        </label>
+        <br>
+        <label>Plain text content:<br>
+            <textarea
+                placeholder="Enter the content in plain text"
+                name="text_content"
+                rows="20"
+                cols="50"
+            ></textarea>
+        </label>
+        <br>
+        <label>HTML content:<br>
+            <textarea
+                placeholder="Enter the content in HTML format"
+                name="html_content"
+                rows="20"
+                cols="50"
+            ></textarea>
+        </label>
+        <br>
+        <button type="submit">Add step</button>
+    </form>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_onboarding_steps(pool: &PgPool) -> Result<Vec<OnboardingStep>, anyhow::Error> {
+    let steps = sqlx::query_as!(
+        OnboardingStep,
+        r#"
+        SELECT onboarding_step_id, title, delay_hours
+        FROM onboarding_steps
+        ORDER BY delay_hours, created_at
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve the onboarding steps.")?;
+    Ok(steps)
+}
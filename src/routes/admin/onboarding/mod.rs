@@ -0,0 +1,5 @@
+mod get;
+mod post;
+
+pub use get::onboarding_form;
+pub use post::{add_onboarding_step, delete_onboarding_step};
@@ -0,0 +1,76 @@
+use crate::utils::{e500, see_other};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct FormData {
+    title: String,
+    text_content: String,
+    html_content: String,
+    delay_hours: u16,
+}
+
+#[tracing::instrument(name = "Add an onboarding step", skip(form, pool))]
+pub async fn add_onboarding_step(
+    pool: web::Data<PgPool>,
+    form: web::Form<FormData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let FormData {
+        title,
+        text_content,
+        html_content,
+        delay_hours,
+    } = form.0;
+    if title.trim().is_empty() {
+        FlashMessage::error("The onboarding step needs a title").send();
+        return Ok(see_other("/admin/onboarding"));
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO onboarding_steps (
+            onboarding_step_id,
+            title,
+            text_content,
+            html_content,
+            delay_hours,
+            created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, now())
+        "#,
+        Uuid::new_v4(),
+        title,
+        text_content,
+        html_content,
+        i32::from(delay_hours),
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to store the onboarding step")
+    .map_err(e500)?;
+
+    FlashMessage::info("The onboarding step has been added.").send();
+    Ok(see_other("/admin/onboarding"))
+}
+
+#[tracing::instrument(name = "Delete an onboarding step", skip(pool))]
+pub async fn delete_onboarding_step(
+    pool: web::Data<PgPool>,
+    step_id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Pending deliveries of the step are removed along with it (ON DELETE CASCADE)
+    sqlx::query!(
+        "DELETE FROM onboarding_steps WHERE onboarding_step_id = $1",
+        step_id.into_inner()
+    )
+    .execute(pool.get_ref())
+    .await
+    .context("Failed to delete the onboarding step")
+    .map_err(e500)?;
+
+    FlashMessage::info("The onboarding step has been deleted.").send();
+    Ok(see_other("/admin/onboarding"))
+}
@@ -0,0 +1,167 @@
+use crate::subscriber_attributes::{get_all_attribute_values, get_attribute_definitions};
+use crate::utils::e500;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse};
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const HEADER: [&str; 15] = [
+    "id",
+    "email",
+    "name",
+    "status",
+    "subscribed_at",
+    "consent_source",
+    "consent_ip_address",
+    "consent_user_agent",
+    "consent_text_version",
+    "consent_recorded_at",
+    "confirmation_source",
+    "confirmation_ip_address",
+    "confirmation_user_agent",
+    "confirmation_text_version",
+    "confirmation_recorded_at",
+];
+
+/// A subscriber alongside the consent collected when they subscribed and when they confirmed.
+struct ExportRow {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+    consent_source: Option<String>,
+    consent_ip_address: Option<String>,
+    consent_user_agent: Option<String>,
+    consent_text_version: Option<String>,
+    consent_recorded_at: Option<DateTime<Utc>>,
+    confirmation_source: Option<String>,
+    confirmation_ip_address: Option<String>,
+    confirmation_user_agent: Option<String>,
+    confirmation_text_version: Option<String>,
+    confirmation_recorded_at: Option<DateTime<Utc>>,
+}
+
+impl ExportRow {
+    fn fields(&self) -> Vec<String> {
+        let optional = |value: &Option<String>| value.clone().unwrap_or_default();
+        let timestamp =
+            |value: &Option<DateTime<Utc>>| value.map(|t| t.to_rfc3339()).unwrap_or_default();
+        [
+            self.id.to_string(),
+            self.email.clone(),
+            self.name.clone(),
+            self.status.clone(),
+            self.subscribed_at.to_rfc3339(),
+            optional(&self.consent_source),
+            optional(&self.consent_ip_address),
+            optional(&self.consent_user_agent),
+            optional(&self.consent_text_version),
+            timestamp(&self.consent_recorded_at),
+            optional(&self.confirmation_source),
+            optional(&self.confirmation_ip_address),
+            optional(&self.confirmation_user_agent),
+            optional(&self.confirmation_text_version),
+            timestamp(&self.confirmation_recorded_at),
+        ]
+        .into()
+    }
+}
+
+pub async fn export_subscribers(pool: web::Data<PgPool>) -> Result<HttpResponse, actix_web::Error> {
+    let rows = get_export_rows(&pool).await.map_err(e500)?;
+    let definitions = get_attribute_definitions(&pool).await.map_err(e500)?;
+    let mut values = get_all_attribute_values(&pool, None).await.map_err(e500)?;
+
+    // One extra column for each custom attribute, named after its key.
+    let header = HEADER
+        .iter()
+        .map(|h| h.to_string())
+        .chain(definitions.iter().map(|d| d.key.clone()));
+    let mut body = csv_line(header);
+    for row in rows {
+        let attributes = values.remove(&row.id).unwrap_or_default();
+        let attribute_fields = definitions.iter().map(|d| {
+            attributes
+                .get(&d.attribute_id)
+                .map(|value| value.to_string())
+                .unwrap_or_default()
+        });
+        body.push_str(&csv_line(row.fields().into_iter().chain(attribute_fields)));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("subscribers.csv".into())],
+        })
+        .body(body))
+}
+
+fn csv_line(fields: impl Iterator<Item = String>) -> String {
+    let mut line = fields.map(csv_field).collect::<Vec<_>>().join(",");
+    line.push_str("\r\n");
+    line
+}
+
+fn csv_field(value: String) -> String {
+    // Stop spreadsheet software from evaluating user-provided values as formulas.
+    let value = if value.starts_with(['=', '+', '-', '@']) {
+        format!("'{value}")
+    } else {
+        value
+    };
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_export_rows(pool: &PgPool) -> Result<Vec<ExportRow>, anyhow::Error> {
+    let rows = sqlx::query_as!(
+        ExportRow,
+        r#"
+        SELECT
+            s.id,
+            s.email,
+            s.name,
+            s.status,
+            s.subscribed_at,
+            subscribed.source AS "consent_source?",
+            subscribed.ip_address AS "consent_ip_address?",
+            subscribed.user_agent AS "consent_user_agent?",
+            subscribed.consent_text_version AS "consent_text_version?",
+            subscribed.recorded_at AS "consent_recorded_at?",
+            confirmed.source AS "confirmation_source?",
+            confirmed.ip_address AS "confirmation_ip_address?",
+            confirmed.user_agent AS "confirmation_user_agent?",
+            confirmed.consent_text_version AS "confirmation_text_version?",
+            confirmed.recorded_at AS "confirmation_recorded_at?"
+        FROM subscriptions s
+        LEFT JOIN LATERAL (
+            SELECT source, ip_address, user_agent, consent_text_version, recorded_at
+            FROM consent_records
+            WHERE subscriber_id = s.id AND event = 'subscribed'
+            ORDER BY recorded_at DESC
+            LIMIT 1
+        ) subscribed ON true
+        LEFT JOIN LATERAL (
+            SELECT source, ip_address, user_agent, consent_text_version, recorded_at
+            FROM consent_records
+            WHERE subscriber_id = s.id AND event = 'confirmed'
+            ORDER BY recorded_at DESC
+            LIMIT 1
+        ) confirmed ON true
+        ORDER BY s.subscribed_at
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve the subscribers to export.")?;
+    Ok(rows)
+}
@@ -0,0 +1,308 @@
+use crate::consent::{get_consent_records, ConsentRecord};
+use crate::domain::{AttributeDefinition, AttributeKind, AttributeValue};
+use crate::subscriber_attributes::{get_attribute_definitions, get_attribute_values};
+use crate::utils::{e500, wants_json};
+use actix_web::http::header::ContentType;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_flash_messages::IncomingFlashMessages;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use htmlescape::{encode_attribute, encode_minimal};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fmt::Write;
+use uuid::Uuid;
+
+struct Subscriber {
+    id: Uuid,
+    email: String,
+    name: String,
+    status: String,
+    subscribed_at: DateTime<Utc>,
+}
+
+pub async fn subscribers_list(pool: web::Data<PgPool>) -> Result<HttpResponse, actix_web::Error> {
+    let subscribers = get_subscribers(&pool).await.map_err(e500)?;
+    let mut rows_html = String::new();
+    for subscriber in subscribers {
+        writeln!(
+            rows_html,
+            r#"<tr>
+            <td><a href="/admin/subscribers/{}">{}</a></td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+        </tr>"#,
+            subscriber.id,
+            encode_minimal(&subscriber.email),
+            encode_minimal(&subscriber.name),
+            subscriber.status,
+            subscriber.subscribed_at.to_rfc3339(),
+        )
+        .unwrap();
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscribers</title>
+</head>
+<body>
+    <p><a href="/admin/subscribers/export">Export as CSV</a></p>
+    <table>
+        <tr><th>Email</th><th>Name</th><th>Status</th><th>Subscribed at</th></tr>
+        {rows_html}
+    </table>
+    <p><a href="/admin/dashboard">&lt;- Back</a></p>
+</body>
+</html>"#,
+        )))
+}
+
+/// The subscriber as an HTML page, or as JSON if the client asks for it.
+pub async fn subscriber_details(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    subscriber_id: web::Path<Uuid>,
+    flash_messages: IncomingFlashMessages,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subscriber_id = subscriber_id.into_inner();
+    let subscriber = match get_subscriber(&pool, subscriber_id).await.map_err(e500)? {
+        Some(subscriber) => subscriber,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let consent_records = get_consent_records(&pool, subscriber_id)
+        .await
+        .map_err(e500)?;
+    let definitions = get_attribute_definitions(&pool).await.map_err(e500)?;
+    let values = get_attribute_values(&pool, subscriber_id)
+        .await
+        .map_err(e500)?;
+
+    if wants_json(&request) {
+        return Ok(HttpResponse::Ok().json(subscriber_json(
+            &subscriber,
+            &definitions,
+            &values,
+            &consent_records,
+        )));
+    }
+
+    let mut msg_html = String::new();
+    for m in flash_messages.iter() {
+        writeln!(msg_html, "<p><i>{}</i></p>", m.content()).unwrap();
+    }
+    let attributes_html = attribute_inputs_html(&definitions, &values);
+    let consent_html = consent_records_html(&consent_records);
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscriber</title>
+</head>
+<body>
+    {msg_html}
+    <ul>
+        <li>Email: {email}</li>
+        <li>Name: {name}</li>
+        <li>Status: {status}</li>
+        <li>Subscribed at: {subscribed_at}</li>
+    </ul>
+    <p>Attributes:</p>
+    <form action="/admin/subscribers/{id}/attributes" method="post">
+        {attributes_html}
+        <button type="submit">Save attributes</button>
+    </form>
+    <p>Consent records:</p>
+    <table>
+        <tr>
+            <th>Event</th>
+            <th>Recorded at</th>
+            <th>Source</th>
+            <th>IP address</th>
+            <th>User agent</th>
+            <th>Consent text version</th>
+        </tr>
+        {consent_html}
+    </table>
+    <p><a href="/admin/subscribers">&lt;- Back</a></p>
+</body>
+</html>"#,
+            id = subscriber.id,
+            email = encode_minimal(&subscriber.email),
+            name = encode_minimal(&subscriber.name),
+            status = subscriber.status,
+            subscribed_at = subscriber.subscribed_at.to_rfc3339(),
+        )))
+}
+
+fn subscriber_json(
+    subscriber: &Subscriber,
+    definitions: &[AttributeDefinition],
+    values: &HashMap<Uuid, AttributeValue>,
+    consent_records: &[ConsentRecord],
+) -> serde_json::Value {
+    let attributes: serde_json::Map<String, serde_json::Value> = definitions
+        .iter()
+        .map(|definition| {
+            let value = values
+                .get(&definition.attribute_id)
+                .map(AttributeValue::to_json)
+                .unwrap_or(serde_json::Value::Null);
+            (definition.key.clone(), value)
+        })
+        .collect();
+    let consent_records: Vec<_> = consent_records
+        .iter()
+        .map(|record| {
+            serde_json::json!({
+                "event": record.event,
+                "source": record.source,
+                "ip_address": record.ip_address,
+                "user_agent": record.user_agent,
+                "consent_text_version": record.consent_text_version,
+                "recorded_at": record.recorded_at.to_rfc3339(),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "id": subscriber.id,
+        "email": subscriber.email,
+        "name": subscriber.name,
+        "status": subscriber.status,
+        "subscribed_at": subscriber.subscribed_at.to_rfc3339(),
+        "attributes": attributes,
+        "consent_records": consent_records,
+    })
+}
+
+fn attribute_inputs_html(
+    definitions: &[AttributeDefinition],
+    values: &HashMap<Uuid, AttributeValue>,
+) -> String {
+    let mut html = String::new();
+    for definition in definitions {
+        let current = values
+            .get(&definition.attribute_id)
+            .map(|value| value.to_string())
+            .unwrap_or_default();
+        let input = match definition.kind {
+            AttributeKind::Text | AttributeKind::Number | AttributeKind::Date => {
+                let input_type = match definition.kind {
+                    // `step="any"` lets the browser accept decimal numbers
+                    AttributeKind::Number => r#"type="number" step="any""#,
+                    AttributeKind::Date => r#"type="date""#,
+                    _ => r#"type="text""#,
+                };
+                format!(
+                    r#"<input {} name="{}" value="{}">"#,
+                    input_type,
+                    definition.key,
+                    encode_attribute(&current)
+                )
+            }
+            AttributeKind::Boolean | AttributeKind::Enum => {
+                let options = match definition.kind {
+                    AttributeKind::Boolean => vec!["true".to_string(), "false".to_string()],
+                    _ => definition.options.clone(),
+                };
+                let mut options_html = String::from(r#"<option value=""></option>"#);
+                for option in options {
+                    write!(
+                        options_html,
+                        r#"<option value="{0}"{1}>{0}</option>"#,
+                        encode_attribute(&option),
+                        if option == current { " selected" } else { "" },
+                    )
+                    .unwrap();
+                }
+                format!(
+                    r#"<select name="{}">{}</select>"#,
+                    definition.key, options_html
+                )
+            }
+        };
+        writeln!(
+            html,
+            "<label>{}{}:<br>{}</label><br>",
+            encode_minimal(&definition.label),
+            if definition.required {
+                " (required)"
+            } else {
+                ""
+            },
+            input,
+        )
+        .unwrap();
+    }
+    html
+}
+
+fn consent_records_html(records: &[ConsentRecord]) -> String {
+    let mut html = String::new();
+    for record in records {
+        writeln!(
+            html,
+            r#"<tr>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+        </tr>"#,
+            record.event,
+            record.recorded_at.to_rfc3339(),
+            encode_minimal(&record.source),
+            encode_minimal(record.ip_address.as_deref().unwrap_or("unknown")),
+            encode_minimal(record.user_agent.as_deref().unwrap_or("unknown")),
+            encode_minimal(&record.consent_text_version),
+        )
+        .unwrap();
+    }
+    html
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_subscribers(pool: &PgPool) -> Result<Vec<Subscriber>, anyhow::Error> {
+    let subscribers = sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT id, email, name, status, subscribed_at
+        FROM subscriptions
+        ORDER BY subscribed_at DESC
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .context("Failed to retrieve the subscribers.")?;
+    Ok(subscribers)
+}
+
+#[tracing::instrument(skip(pool))]
+async fn get_subscriber(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, anyhow::Error> {
+    let subscriber = sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT id, email, name, status, subscribed_at
+        FROM subscriptions
+        WHERE id = $1
+        "#,
+        subscriber_id,
+    )
+    .fetch_optional(pool)
+    .await
+    .context("Failed to retrieve the subscriber.")?;
+    Ok(subscriber)
+}
@@ -0,0 +1,7 @@
+mod export;
+mod get;
+mod post;
+
+pub use export::export_subscribers;
+pub use get::{subscriber_details, subscribers_list};
+pub use post::update_subscriber_attributes;
@@ -0,0 +1,58 @@
+use crate::domain::parse_attributes;
+use crate::subscriber_attributes::{get_attribute_definitions, store_attribute_values};
+use crate::utils::{e500, see_other};
+use actix_web::{web, HttpResponse};
+use actix_web_flash_messages::FlashMessage;
+use anyhow::Context;
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[tracing::instrument(name = "Update the attributes of a subscriber", skip(form, pool))]
+pub async fn update_subscriber_attributes(
+    pool: web::Data<PgPool>,
+    subscriber_id: web::Path<Uuid>,
+    form: web::Form<HashMap<String, String>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let subscriber_id = subscriber_id.into_inner();
+    let location = format!("/admin/subscribers/{}", subscriber_id);
+
+    let definitions = get_attribute_definitions(&pool).await.map_err(e500)?;
+    let values = match parse_attributes(&definitions, &form.0) {
+        Ok(values) => values,
+        Err(e) => {
+            FlashMessage::error(e).send();
+            return Ok(see_other(&location));
+        }
+    };
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")
+        .map_err(e500)?;
+    let subscriber_exists = sqlx::query!(
+        "SELECT id FROM subscriptions WHERE id = $1 FOR UPDATE",
+        subscriber_id
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to retrieve the subscriber")
+    .map_err(e500)?
+    .is_some();
+    if !subscriber_exists {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+    store_attribute_values(&mut transaction, subscriber_id, &values)
+        .await
+        .context("Failed to store the subscriber's attributes")
+        .map_err(e500)?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the subscriber's attributes")
+        .map_err(e500)?;
+
+    FlashMessage::info("The attributes have been saved.").send();
+    Ok(see_other(&location))
+}
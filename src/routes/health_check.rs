@@ -1,6 +1,35 @@
-use actix_web::HttpResponse;
+use crate::liveness::stalled_loops;
+use actix_web::{web, HttpResponse};
+use sqlx::PgPool;
+use std::time::Duration;
 
-pub async fn check_health() -> HttpResponse {
-    "checking health.....".to_string();
+// A database slower than this to answer is as good as down for a health check.
+const DATABASE_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Healthy if the database answers and none of the background loops of this process stalled.
+///
+/// An API started with `zero2prod serve` runs no background loop: only the database is checked.
+pub async fn check_health(pool: web::Data<PgPool>) -> HttpResponse {
+    let ping = sqlx::query!("SELECT 1 AS ping").fetch_one(pool.get_ref());
+    match tokio::time::timeout(DATABASE_PING_TIMEOUT, ping).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Health check failed: the database is unreachable"
+            );
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+        Err(_) => {
+            tracing::error!("Health check failed: the database did not answer in time");
+            return HttpResponse::ServiceUnavailable().finish();
+        }
+    }
+    let stalled = stalled_loops();
+    if !stalled.is_empty() {
+        tracing::error!(?stalled, "Health check failed: background loops stalled");
+        return HttpResponse::ServiceUnavailable().finish();
+    }
     HttpResponse::Ok().finish()
 }
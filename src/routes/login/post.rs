@@ -6,6 +6,7 @@ use actix_web::http::header::LOCATION;
 use actix_web::web::Form;
 use actix_web::{web, HttpResponse};
 use actix_web_flash_messages::FlashMessage;
+use metrics::counter;
 use secrecy::Secret;
 use sqlx::PgPool;
 
@@ -48,18 +49,25 @@ pub async fn login(
             tracing::Span::current().record("user_id", tracing::field::display(&user_id));
             // renew a session to prevent Fixation Session Attacks
             session.renew();
-            session
-                .insert_user_id(user_id)
-                .map_err(|e| login_redirect(LoginError::UnexpectedError(e.into())))?;
+            session.insert_user_id(user_id).map_err(|e| {
+                counter!("login_attempts_total", "outcome" => "error").increment(1);
+                login_redirect(LoginError::UnexpectedError(e.into()))
+            })?;
+            counter!("login_attempts_total", "outcome" => "success").increment(1);
             Ok(HttpResponse::SeeOther()
                 .insert_header((LOCATION, "/admin/dashboard"))
                 .finish())
         }
         Err(error) => {
-            let e = match error {
-                AuthError::InvalidCredentials(_) => LoginError::AuthError(error.into()),
-                AuthError::UnexpectedError(_) => LoginError::UnexpectedError(error.into()),
+            let (e, outcome) = match error {
+                AuthError::InvalidCredentials(_) => {
+                    (LoginError::AuthError(error.into()), "invalid_credentials")
+                }
+                AuthError::UnexpectedError(_) => {
+                    (LoginError::UnexpectedError(error.into()), "error")
+                }
             };
+            counter!("login_attempts_total", "outcome" => outcome).increment(1);
             FlashMessage::error(e.to_string()).send();
 
             let response = HttpResponse::SeeOther()
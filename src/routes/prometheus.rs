@@ -0,0 +1,18 @@
+use crate::issue_delivery_worker::record_queue_metrics;
+use crate::telemetry::record_pool_usage;
+use crate::utils::e500;
+use actix_web::{web, HttpResponse};
+use metrics_exporter_prometheus::PrometheusHandle;
+use sqlx::PgPool;
+
+/// Scraped by Prometheus. Gauges sampled from the database are refreshed on every scrape.
+pub async fn export_metrics(
+    pool: web::Data<PgPool>,
+    metrics: web::Data<PrometheusHandle>,
+) -> Result<HttpResponse, actix_web::Error> {
+    record_queue_metrics(&pool).await.map_err(e500)?;
+    record_pool_usage("http", &pool);
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics.render()))
+}
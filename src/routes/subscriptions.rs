@@ -1,19 +1,24 @@
 //! src.routes.subscriptions "//!: The double exclamation mark indicates an inner documentation comment"
 
 use actix_web::http::StatusCode;
-use actix_web::{web, HttpResponse, ResponseError};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
 use anyhow::Context;
 use chrono::Utc;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use sqlx;
 use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::collections::HashMap;
 use std::fmt::Formatter;
 use uuid::Uuid;
 
-use crate::domain::{NewSubscriber, SubscriberEmail, SubscriberName};
-use crate::email_client::EmailClient;
-use crate::startup::ApplicationBaseUrl;
+use crate::configuration::ConsentSettings;
+use crate::consent::{record_consent, ConsentEvent, ConsentRecord};
+use crate::domain::{parse_attributes, NewSubscriber, SubscriberEmail, SubscriberName};
+use crate::email_outbox::enqueue_email;
+use crate::startup::{ApplicationBaseUrl, TrustedProxies};
+use crate::subscriber_attributes::{get_attribute_definitions, store_attribute_values};
+use crate::suppression::is_suppressed;
 
 // the thiserror receives, at compile-time, the definition of SubscribeError as input and returns
 // another stream of tokens as output - it generates new Rust code, which is then compiled into the
@@ -93,6 +98,20 @@ impl std::error::Error for StoreTokenError {
 pub struct FormData {
     pub name: String,
     pub email: String,
+    // Which form the subscriber used, kept in the consent ledger.
+    pub source: Option<String>,
+    // Every other field is a candidate value for one of the custom subscriber attributes.
+    #[serde(flatten)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl FormData {
+    fn source(&self) -> &str {
+        match self.source.as_deref().map(str::trim) {
+            Some(source) if !source.is_empty() => source,
+            _ => "signup_form",
+        }
+    }
 }
 
 impl TryFrom<FormData> for NewSubscriber {
@@ -117,7 +136,7 @@ impl TryFrom<FormData> for NewSubscriber {
 // we explicitly tell tracing to ignore them using the skip directive.
 #[tracing::instrument(
     name = "Creating new subscriber",
-    skip(form, db_pool, email_client, base_url),
+    skip(form, request, db_pool, base_url, consent_settings, trusted_proxies),
     fields(
         subscriber_email = %form.email,
         subscriber_name = %form.name
@@ -125,14 +144,37 @@ impl TryFrom<FormData> for NewSubscriber {
 )]
 pub async fn subscribe(
     form: web::Form<FormData>,
+    request: HttpRequest,
     // Retrieving a connection from the application state!
     db_pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
     base_url: web::Data<ApplicationBaseUrl>,
+    consent_settings: web::Data<ConsentSettings>,
+    trusted_proxies: web::Data<TrustedProxies>,
 ) -> Result<HttpResponse, SubscribeError> {
+    let consent = ConsentRecord::from_request(
+        ConsentEvent::Subscribed,
+        form.source(),
+        &request,
+        &consent_settings,
+        &trusted_proxies,
+    );
+    let definitions = get_attribute_definitions(&db_pool)
+        .await
+        .context("Failed to retrieve the subscriber attributes")?;
+    let mut attributes = parse_attributes(&definitions, &form.attributes)?;
+    attributes.retain(|(_, value)| value.is_some());
     // `web::Form` is a wrapper around `FormData` (web::Form is a struct tuple)
     // `form.0` gives us access to the underlying `FormData`
-    let new_subscriber = form.0.try_into()?;
+    let new_subscriber: NewSubscriber = form.0.try_into()?;
+
+    // We can't email a suppressed address: don't let on, but don't store anything either.
+    if is_suppressed(&db_pool, new_subscriber.email.as_ref())
+        .await
+        .context("Failed to check the suppression list")?
+    {
+        tracing::warn!("Ignoring a subscription for an address on the suppression list");
+        return Ok(HttpResponse::Ok().finish());
+    }
 
     let mut transaction = db_pool
         .begin()
@@ -143,6 +185,14 @@ pub async fn subscribe(
         .await
         .context("Failed to insert new subscriber in the db")?;
 
+    record_consent(&mut transaction, subscriber_id, &consent)
+        .await
+        .context("Failed to record the subscriber's consent")?;
+
+    store_attribute_values(&mut transaction, subscriber_id, &attributes)
+        .await
+        .context("Failed to store the subscriber's attributes")?;
+
     // generate a confirmation token to be used to make user from pending to confirmed
     let subscription_token = generate_subscription_token();
 
@@ -153,33 +203,34 @@ pub async fn subscribe(
         .await
         .context("Failed to save a user's confirmation token")?;
 
-    transaction
-        .commit()
-        .await
-        .context("Failed to commit sql tx to commit new subscriber with their token")?;
-
-    // Email the new subscriber.
-    send_confirmation(
+    // The confirmation email goes through the outbox: it is committed together with the
+    // subscriber and sent in the background, so an email provider outage cannot fail the request.
+    enqueue_confirmation_email(
+        &mut transaction,
         &base_url.0,
-        &email_client,
-        new_subscriber,
+        &new_subscriber,
         &subscription_token,
     )
     .await
-    .context("Failed to send a confirmation email")?;
+    .context("Failed to enqueue a confirmation email")?;
+
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit sql tx to commit new subscriber with their token")?;
     Ok(HttpResponse::Ok().finish())
 }
 
 #[tracing::instrument(
-    name = "Creating new subscriber",
-    skip(email_client, new_subscriber, base_url)
+    name = "Enqueue confirmation email",
+    skip(transaction, new_subscriber, base_url, confirmation_token)
 )]
-async fn send_confirmation(
+async fn enqueue_confirmation_email(
+    transaction: &mut Transaction<'_, Postgres>,
     base_url: &str,
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
+    new_subscriber: &NewSubscriber,
     confirmation_token: &str,
-) -> Result<(), reqwest::Error> {
+) -> Result<Uuid, sqlx::Error> {
     let confirmation_link = format!(
         "{}/subscriptions/confirm?subscription_token={}",
         base_url, confirmation_token
@@ -196,9 +247,14 @@ async fn send_confirmation(
         "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
         confirmation_link
     );
-    email_client
-        .send_email(&new_subscriber.email, subject, html_body, text_content)
-        .await
+    enqueue_email(
+        transaction,
+        &new_subscriber.email,
+        subject,
+        html_body,
+        text_content,
+    )
+    .await
 }
 
 #[tracing::instrument(name = "Creating new subscriber", skip(transaction, subscriber_id))]
@@ -1,60 +0,0 @@
-use actix_web::web::Data;
-use actix_web::{web, HttpResponse};
-use sqlx::PgPool;
-use uuid::Uuid;
-
-#[derive(serde::Deserialize)]
-pub struct QueryParams {
-    pub subscription_token: String,
-}
-
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(db_pool, parameters))]
-pub async fn confirm(db_pool: Data<PgPool>, parameters: web::Query<QueryParams>) -> HttpResponse {
-    let id = match get_subscriber_id_from_token(&db_pool, &parameters.subscription_token).await {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::InternalServerError().finish(),
-    };
-    match id {
-        // Non-existing token!
-        None => HttpResponse::Unauthorized().finish(),
-        Some(subscriber_id) => {
-            if confirm_subscriber(&db_pool, subscriber_id).await.is_err() {
-                return HttpResponse::InternalServerError().finish();
-            }
-            HttpResponse::Ok().finish()
-        }
-    }
-}
-
-#[tracing::instrument(name = "Get subscriber_id from token", skip(subscription_tkn, db_pool))]
-pub async fn get_subscriber_id_from_token(
-    db_pool: &PgPool,
-    subscription_tkn: &String,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        "SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1",
-        subscription_tkn,
-    )
-    .fetch_optional(db_pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-    Ok(result.map(|r| r.subscriber_id))
-}
-
-#[tracing::instrument(name = "Mark subscriber as confirmed", skip(subscriber_id, db_pool))]
-async fn confirm_subscriber(db_pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1"#,
-        subscriber_id,
-    )
-    .execute(db_pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-    Ok(())
-}
@@ -0,0 +1,234 @@
+use crate::configuration::ConsentSettings;
+use crate::consent::{record_consent, ConsentEvent, ConsentRecord};
+use crate::issue_delivery_worker::MessageClass;
+use crate::queue_listener::{notify_workers, DELIVERY_QUEUE_CHANNEL};
+use crate::startup::TrustedProxies;
+use crate::utils::wants_json;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use actix_web::{web, HttpRequest, HttpResponse};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(serde::Deserialize)]
+pub struct QueryParams {
+    pub subscription_token: String,
+}
+
+/// What the subscriber sees after clicking on the confirmation link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConfirmationOutcome {
+    Confirmed,
+    AlreadyConfirmed,
+    InvalidLink,
+    Failed,
+}
+
+impl ConfirmationOutcome {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Confirmed | Self::AlreadyConfirmed => StatusCode::OK,
+            Self::InvalidLink => StatusCode::UNAUTHORIZED,
+            Self::Failed => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Confirmed => "confirmed",
+            Self::AlreadyConfirmed => "already_confirmed",
+            Self::InvalidLink => "invalid_link",
+            Self::Failed => "error",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            Self::Confirmed => "Your subscription has been confirmed.",
+            Self::AlreadyConfirmed => "This subscription has already been confirmed.",
+            Self::InvalidLink => "This confirmation link is invalid or has expired.",
+            Self::Failed => "Something went wrong while confirming your subscription.",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Confirmed => "Subscription confirmed",
+            Self::AlreadyConfirmed => "Already confirmed",
+            Self::InvalidLink => "Invalid link",
+            Self::Failed => "Something went wrong",
+        }
+    }
+
+    // The body of the page, the rest comes from `layout.html`
+    fn html_content(&self) -> &'static str {
+        match self {
+            Self::Confirmed => include_str!("confirmed.html"),
+            Self::AlreadyConfirmed => include_str!("already_confirmed.html"),
+            Self::InvalidLink => include_str!("invalid_link.html"),
+            Self::Failed => include_str!("error.html"),
+        }
+    }
+
+    fn html_page(&self) -> String {
+        include_str!("layout.html")
+            .replace("{title}", self.title())
+            .replace("{content}", self.html_content().trim_end())
+    }
+
+    /// Render the outcome as a landing page, or as JSON for API clients.
+    fn into_response(self, as_json: bool) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if as_json {
+            response.json(serde_json::json!({
+                "status": self.as_str(),
+                "message": self.message(),
+            }))
+        } else {
+            response
+                .content_type(ContentType::html())
+                .body(self.html_page())
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(request, db_pool, parameters, consent_settings, trusted_proxies),
+    fields(outcome = tracing::field::Empty)
+)]
+pub async fn confirm(
+    request: HttpRequest,
+    db_pool: Data<PgPool>,
+    parameters: web::Query<QueryParams>,
+    consent_settings: Data<ConsentSettings>,
+    trusted_proxies: Data<TrustedProxies>,
+) -> HttpResponse {
+    let consent = ConsentRecord::from_request(
+        ConsentEvent::Confirmed,
+        "confirmation_link",
+        &request,
+        &consent_settings,
+        &trusted_proxies,
+    );
+    let outcome = match try_confirm(&db_pool, &parameters.subscription_token, &consent).await {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            tracing::error!(
+                error.cause_chain = ?e,
+                error.message = %e,
+                "Failed to confirm a subscriber",
+            );
+            ConfirmationOutcome::Failed
+        }
+    };
+    tracing::Span::current().record("outcome", outcome.as_str());
+    outcome.into_response(wants_json(&request))
+}
+
+async fn try_confirm(
+    db_pool: &PgPool,
+    subscription_token: &String,
+    consent: &ConsentRecord,
+) -> Result<ConfirmationOutcome, sqlx::Error> {
+    let subscriber = get_subscriber_from_token(db_pool, subscription_token).await?;
+    match subscriber {
+        // Non-existing token!
+        None => Ok(ConfirmationOutcome::InvalidLink),
+        Some((_, status)) if status == "confirmed" => Ok(ConfirmationOutcome::AlreadyConfirmed),
+        Some((subscriber_id, status)) if status == "pending" => {
+            confirm_subscriber(db_pool, subscriber_id, consent).await
+        }
+        // The subscription is not pending anymore (e.g. the subscriber has left),
+        // the link cannot be used to opt back in.
+        Some(_) => Ok(ConfirmationOutcome::InvalidLink),
+    }
+}
+
+#[tracing::instrument(name = "Get subscriber from token", skip(subscription_tkn, db_pool))]
+pub async fn get_subscriber_from_token(
+    db_pool: &PgPool,
+    subscription_tkn: &String,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        SELECT subscription_tokens.subscriber_id, subscriptions.status
+        FROM subscription_tokens
+        JOIN subscriptions ON subscriptions.id = subscription_tokens.subscriber_id
+        WHERE subscription_tokens.subscription_token = $1
+        "#,
+        subscription_tkn,
+    )
+    .fetch_optional(db_pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    Ok(result.map(|r| (r.subscriber_id, r.status)))
+}
+
+#[tracing::instrument(
+    name = "Mark subscriber as confirmed",
+    skip(subscriber_id, db_pool, consent)
+)]
+async fn confirm_subscriber(
+    db_pool: &PgPool,
+    subscriber_id: Uuid,
+    consent: &ConsentRecord,
+) -> Result<ConfirmationOutcome, sqlx::Error> {
+    let mut transaction = db_pool.begin().await?;
+    let result = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'confirmed' WHERE id = $1 AND status = 'pending'"#,
+        subscriber_id,
+    )
+    .execute(&mut *transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    // Only the first confirmation starts the onboarding sequence,
+    // clicking the link again must not send the welcome email twice.
+    if result.rows_affected() == 0 {
+        return Ok(ConfirmationOutcome::AlreadyConfirmed);
+    }
+    record_consent(&mut transaction, subscriber_id, consent).await?;
+    enqueue_onboarding_steps(&mut transaction, subscriber_id).await?;
+    transaction.commit().await?;
+    Ok(ConfirmationOutcome::Confirmed)
+}
+
+#[tracing::instrument(skip_all)]
+async fn enqueue_onboarding_steps(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO issue_delivery_queue (
+            onboarding_step_id,
+            subscriber_id,
+            message_class,
+            execute_after
+        )
+        SELECT
+            onboarding_step_id,
+            $1,
+            $2,
+            now() + make_interval(hours => delay_hours)
+        FROM onboarding_steps
+        ON CONFLICT DO NOTHING
+        "#,
+        subscriber_id,
+        MessageClass::Scheduled.as_str(),
+    );
+    transaction.execute(query).await.map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+    notify_workers(transaction, DELIVERY_QUEUE_CHANNEL).await?;
+    Ok(())
+}
@@ -0,0 +1,255 @@
+use crate::configuration::EmailWebhookSettings;
+use crate::email_events::{record_email_event, EmailEvent};
+use crate::routes::error_chain_fmt;
+use crate::suppression::{lift_suppression, suppress_recipient, SuppressionReason};
+use actix_web::http::header::{HeaderMap, HeaderValue};
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use anyhow::Context;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use secrecy::ExposeSecret;
+use sqlx::PgPool;
+use subtle::ConstantTimeEq;
+
+#[derive(thiserror::Error)]
+pub enum EmailEventsError {
+    #[error("Authentication failed")]
+    AuthError(#[source] anyhow::Error),
+    #[error("{0}")]
+    ValidationError(String),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for EmailEventsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for EmailEventsError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            EmailEventsError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            EmailEventsError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            EmailEventsError::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::new(self.status_code());
+        if let EmailEventsError::AuthError(_) = self {
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static(r#"Basic realm="email-events""#),
+            );
+        }
+        response
+    }
+}
+
+/// The webhook payloads we act upon, as sent by Postmark.
+/// https://postmarkapp.com/developer/webhooks/webhooks-overview
+#[derive(serde::Deserialize)]
+#[serde(tag = "RecordType")]
+enum PostmarkEvent {
+    Bounce(BounceEvent),
+    // Spam complaints are reported with the same fields as bounces.
+    SpamComplaint(BounceEvent),
+    SubscriptionChange(SubscriptionChangeEvent),
+    // Deliveries, opens, clicks...
+    #[serde(other)]
+    Other,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BounceEvent {
+    // e.g. `HardBounce`, `SoftBounce`, `Transient`...
+    r#type: String,
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+    email: String,
+    bounced_at: Option<String>,
+    description: Option<String>,
+    // Whether Postmark stopped sending to the address
+    #[serde(default)]
+    inactive: bool,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SubscriptionChangeEvent {
+    #[serde(rename = "MessageID")]
+    message_id: Option<String>,
+    recipient: String,
+    changed_at: Option<String>,
+    suppress_sending: bool,
+    suppression_reason: Option<String>,
+}
+
+/// Bounces, spam complaints and subscription changes pushed by the email provider.
+///
+/// Addresses that can't (or don't want to) receive our emails go to the suppression list.
+#[tracing::instrument(
+    name = "Receive an email event",
+    skip(request, body, pool, settings),
+    fields(record_type = tracing::field::Empty)
+)]
+pub async fn receive_email_event(
+    request: HttpRequest,
+    body: web::Bytes,
+    pool: web::Data<PgPool>,
+    settings: web::Data<EmailWebhookSettings>,
+) -> Result<HttpResponse, EmailEventsError> {
+    authenticate(request.headers(), &settings).map_err(EmailEventsError::AuthError)?;
+    let event: PostmarkEvent = serde_json::from_slice(&body)
+        .map_err(|e| EmailEventsError::ValidationError(format!("Invalid email event: {}", e)))?;
+
+    let (event, suppression) = match event {
+        PostmarkEvent::Bounce(bounce) => {
+            let suppression = (bounce.r#type == "HardBounce" || bounce.inactive)
+                .then_some(Suppression::Add(SuppressionReason::HardBounce));
+            (
+                bounce.into_event("Bounce", suppression.is_some()),
+                suppression,
+            )
+        }
+        PostmarkEvent::SpamComplaint(complaint) => (
+            complaint.into_event("SpamComplaint", true),
+            Some(Suppression::Add(SuppressionReason::SpamComplaint)),
+        ),
+        PostmarkEvent::SubscriptionChange(change) => {
+            let suppression = if change.suppress_sending {
+                Suppression::Add(match change.suppression_reason.as_deref() {
+                    Some("HardBounce") => SuppressionReason::HardBounce,
+                    Some("SpamComplaint") => SuppressionReason::SpamComplaint,
+                    _ => SuppressionReason::ManualSuppression,
+                })
+            } else {
+                Suppression::Lift
+            };
+            (change.into_event(), Some(suppression))
+        }
+        PostmarkEvent::Other => {
+            tracing::info!("Ignoring an email event we don't act upon");
+            return Ok(HttpResponse::Ok().finish());
+        }
+    };
+    tracing::Span::current().record("record_type", &event.record_type);
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    record_email_event(&mut transaction, &event)
+        .await
+        .context("Failed to store the email event")?;
+    match suppression {
+        Some(Suppression::Add(reason)) => {
+            suppress_recipient(&mut transaction, &event.email, reason)
+                .await
+                .context("Failed to suppress the recipient")?;
+        }
+        Some(Suppression::Lift) => {
+            lift_suppression(&mut transaction, &event.email)
+                .await
+                .context("Failed to lift the suppression of the recipient")?;
+        }
+        None => {}
+    }
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit the email event")?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+enum Suppression {
+    Add(SuppressionReason),
+    Lift,
+}
+
+impl BounceEvent {
+    fn into_event(self, record_type: &str, suppressed: bool) -> EmailEvent {
+        let description = match self.description {
+            Some(description) => format!("{}: {}", self.r#type, description),
+            None => self.r#type,
+        };
+        EmailEvent {
+            record_type: record_type.into(),
+            email: self.email,
+            message_id: self.message_id,
+            description: Some(description),
+            suppressed,
+            occurred_at: parse_timestamp(self.bounced_at.as_deref()),
+            received_at: Utc::now(),
+        }
+    }
+}
+
+impl SubscriptionChangeEvent {
+    fn into_event(self) -> EmailEvent {
+        let description = if self.suppress_sending {
+            format!(
+                "Sending suppressed ({})",
+                self.suppression_reason
+                    .as_deref()
+                    .unwrap_or("unknown reason")
+            )
+        } else {
+            "Sending reactivated".to_string()
+        };
+        EmailEvent {
+            record_type: "SubscriptionChange".into(),
+            email: self.recipient,
+            message_id: self.message_id,
+            description: Some(description),
+            suppressed: self.suppress_sending,
+            occurred_at: parse_timestamp(self.changed_at.as_deref()),
+            received_at: Utc::now(),
+        }
+    }
+}
+
+// Fall back to the time we heard about the event if the provider's timestamp is unusable.
+fn parse_timestamp(timestamp: Option<&str>) -> DateTime<Utc> {
+    timestamp
+        .and_then(|timestamp| DateTime::parse_from_rfc3339(timestamp).ok())
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now)
+}
+
+/// Check the basic auth credentials the provider sends with every webhook.
+fn authenticate(headers: &HeaderMap, settings: &EmailWebhookSettings) -> Result<(), anyhow::Error> {
+    // The header value, if present, must be a valid UTF8 string
+    let header_value = headers
+        .get(header::AUTHORIZATION)
+        .context("The 'Authorization' header was missing")?
+        .to_str()
+        .context("The 'Authorization' header was not a valid UTF8 string.")?;
+    let base64encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .context("The authorization scheme was not 'Basic'.")?;
+    let decoded_bytes = base64::engine::general_purpose::STANDARD
+        .decode(base64encoded_segment)
+        .context("Failed to base64-decode 'Basic' credentials.")?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .context("The decoded credential string is not valid UTF8.")?;
+
+    // Split into two segments, using ':' as delimiter
+    let (username, password) = decoded_credentials
+        .split_once(':')
+        .context("The 'Basic' credentials are not of the form 'username:password'.")?;
+    // Both are compared in full, in constant time: the response time must not tell how close
+    // a guess was.
+    let username_matches = username.as_bytes().ct_eq(settings.username.as_bytes());
+    let password_matches = password
+        .as_bytes()
+        .ct_eq(settings.password.expose_secret().as_bytes());
+    if !bool::from(username_matches & password_matches) {
+        anyhow::bail!("Invalid webhook credentials.");
+    }
+    Ok(())
+}
@@ -0,0 +1,156 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// What we know about the delivery of a task to the email provider.
+///
+/// Intents are committed on their own, outside of the batch transaction: they survive a crash
+/// of the worker, while the batch (and the removal of its tasks from the queue) doesn't.
+pub struct SendIntent {
+    pub task_id: i64,
+    // Sent along with the email, to find it again on the provider's side
+    pub delivery_key: Uuid,
+    pub attempted_at: DateTime<Utc>,
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub message_id: Option<String>,
+    // Whether the email may already have been handed to the provider
+    pub previously_attempted: bool,
+    // Searches of the provider that came back empty since `attempted_at`
+    pub n_searches: i16,
+    pub searched_at: Option<DateTime<Utc>>,
+}
+
+/// The send intent of every task of `task_ids`, recording one for the tasks that have none yet.
+#[tracing::instrument(skip_all, fields(n_tasks = task_ids.len()))]
+pub async fn record_send_intents(
+    pool: &PgPool,
+    task_ids: &[i64],
+) -> Result<HashMap<i64, SendIntent>, sqlx::Error> {
+    let mut intents: HashMap<_, _> = sqlx::query!(
+        r#"
+        SELECT task_id, delivery_key, attempted_at, accepted_at, message_id, n_searches, searched_at
+        FROM send_intents
+        WHERE task_id = ANY($1)
+        "#,
+        task_ids,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|r| {
+        let intent = SendIntent {
+            task_id: r.task_id,
+            delivery_key: r.delivery_key,
+            attempted_at: r.attempted_at,
+            accepted_at: r.accepted_at,
+            message_id: r.message_id,
+            previously_attempted: true,
+            n_searches: r.n_searches,
+            searched_at: r.searched_at,
+        };
+        (r.task_id, intent)
+    })
+    .collect();
+
+    let new_task_ids: Vec<i64> = task_ids
+        .iter()
+        .filter(|task_id| !intents.contains_key(task_id))
+        .copied()
+        .collect();
+    let delivery_keys: Vec<Uuid> = new_task_ids.iter().map(|_| Uuid::new_v4()).collect();
+    let attempted_at = Utc::now();
+    sqlx::query!(
+        r#"
+        INSERT INTO send_intents (task_id, delivery_key, attempted_at)
+        SELECT task_id, delivery_key, $3
+        FROM UNNEST($1::bigint[], $2::uuid[]) AS new_intents(task_id, delivery_key)
+        "#,
+        &new_task_ids,
+        &delivery_keys,
+        attempted_at,
+    )
+    .execute(pool)
+    .await?;
+    for (task_id, delivery_key) in new_task_ids.into_iter().zip(delivery_keys) {
+        let intent = SendIntent {
+            task_id,
+            delivery_key,
+            attempted_at,
+            accepted_at: None,
+            message_id: None,
+            previously_attempted: false,
+            n_searches: 0,
+            searched_at: None,
+        };
+        intents.insert(task_id, intent);
+    }
+    Ok(intents)
+}
+
+/// We are about to hand the email of `task_id` to the provider (again).
+pub async fn record_attempt(pool: &PgPool, task_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE send_intents
+        SET attempted_at = now(), n_searches = 0, searched_at = NULL
+        WHERE task_id = $1
+        "#,
+        task_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The provider was searched for the email of `task_id`, it did not find it (yet).
+pub async fn record_empty_search(pool: &PgPool, task_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE send_intents
+        SET n_searches = n_searches + 1, searched_at = now()
+        WHERE task_id = $1
+        "#,
+        task_id,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The provider accepted the emails of `task_ids`: they must never be sent again.
+#[tracing::instrument(skip_all, fields(n_tasks = task_ids.len()))]
+pub async fn record_acceptances(
+    pool: &PgPool,
+    task_ids: &[i64],
+    message_ids: &[Option<String>],
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE send_intents
+        SET accepted_at = now(), message_id = accepted.message_id
+        FROM UNNEST($1::bigint[], $2::text[]) AS accepted(task_id, message_id)
+        WHERE send_intents.task_id = accepted.task_id
+        "#,
+        task_ids,
+        message_ids as &[Option<String>],
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// The provider certainly did not accept the emails of `task_ids`: sending them again is safe.
+#[tracing::instrument(skip_all, fields(n_tasks = task_ids.len()))]
+pub async fn forget_send_intents(pool: &PgPool, task_ids: &[i64]) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        DELETE FROM send_intents
+        WHERE task_id = ANY($1)
+        "#,
+        task_ids,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use crate::configuration::SendRateSettings;
+use crate::domain::SubscriberEmail;
+use crate::jobs::RecurringPurge;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::time::Duration;
+
+// The bucket of the global limit: it can't be mistaken for a domain.
+const GLOBAL_BUCKET: &str = "*";
+const SEND_RATE_BUCKETS_PURGE_BATCH_SIZE: i64 = 1000;
+
+/// Decide which of `recipients` may be emailed right away.
+///
+/// Every limit is a token bucket stored in Postgres, shared by all the workers and refilled
+/// at `messages_per_second` (holding one second worth of emails at most).
+/// Returns, for each recipient, `None` if they can be emailed now or how long to wait otherwise.
+#[tracing::instrument(skip_all, fields(n_recipients = recipients.len()))]
+pub async fn admit_recipients(
+    pool: &PgPool,
+    settings: &SendRateSettings,
+    recipients: &[&SubscriberEmail],
+) -> Result<Vec<Option<Duration>>, sqlx::Error> {
+    let mut admissions = vec![None; recipients.len()];
+
+    let mut by_domain: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, recipient) in recipients.iter().enumerate() {
+        by_domain.entry(recipient.domain()).or_default().push(i);
+    }
+    for (domain, indices) in &by_domain {
+        let Some(limit) = settings.domain_limit(domain) else {
+            continue;
+        };
+        let granted = acquire(pool, domain, limit, indices.len()).await?;
+        defer(&mut admissions, &indices[granted..], limit);
+    }
+
+    if let Some(limit) = settings.messages_per_second {
+        let admitted: Vec<usize> = (0..recipients.len())
+            .filter(|i| admissions[*i].is_none())
+            .collect();
+        let granted = acquire(pool, GLOBAL_BUCKET, limit, admitted.len()).await?;
+        let throttled = &admitted[granted..];
+        defer(&mut admissions, throttled, limit);
+        // These recipients won't be emailed now: give their domain tokens back.
+        let mut refunds: HashMap<String, usize> = HashMap::new();
+        for i in throttled {
+            *refunds.entry(recipients[*i].domain()).or_default() += 1;
+        }
+        for (domain, n) in refunds {
+            if let Some(limit) = settings.domain_limit(&domain) {
+                release(pool, &domain, limit, n).await?;
+            }
+        }
+    }
+
+    let n_throttled = admissions.iter().filter(|a| a.is_some()).count();
+    if n_throttled > 0 {
+        tracing::info!(n_throttled, "Deferring emails over the send rate limits");
+    }
+    Ok(admissions)
+}
+
+// Spread the deferred recipients over the next seconds, at the pace the bucket refills.
+fn defer(admissions: &mut [Option<Duration>], throttled: &[usize], limit: u32) {
+    for (position, i) in throttled.iter().enumerate() {
+        let delay = Duration::from_secs_f64((position + 1) as f64 / f64::from(limit.max(1)));
+        // The most restrictive limit wins.
+        admissions[*i] = Some(admissions[*i].map_or(delay, |d| d.max(delay)));
+    }
+}
+
+/// Take up to `requested` tokens from `bucket`, returns how many we got.
+async fn acquire(
+    pool: &PgPool,
+    bucket: &str,
+    limit: u32,
+    requested: usize,
+) -> Result<usize, sqlx::Error> {
+    let capacity = f64::from(limit);
+    let mut transaction = pool.begin().await?;
+    // A missing bucket (new, or purged) is created full, an existing one is locked by the no-op
+    // update until we commit.
+    // `clock_timestamp()` rather than `now()`: we may have waited for another worker's lock.
+    let row = sqlx::query!(
+        r#"
+        INSERT INTO send_rate_limits (bucket, tokens, refilled_at)
+        VALUES ($1, $2, clock_timestamp())
+        ON CONFLICT (bucket) DO UPDATE SET bucket = EXCLUDED.bucket
+        RETURNING
+            tokens,
+            EXTRACT(EPOCH FROM clock_timestamp() - refilled_at)::float8 AS "elapsed_seconds!"
+        "#,
+        bucket,
+        capacity,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    let available = (row.tokens + row.elapsed_seconds.max(0.0) * capacity).min(capacity);
+    let granted = (available.floor() as usize).min(requested);
+    sqlx::query!(
+        r#"
+        UPDATE send_rate_limits
+        SET tokens = $2, refilled_at = clock_timestamp()
+        WHERE bucket = $1
+        "#,
+        bucket,
+        available - granted as f64,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(granted)
+}
+
+/// Put back `n` tokens we took but did not use.
+async fn release(pool: &PgPool, bucket: &str, limit: u32, n: usize) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE send_rate_limits
+        SET tokens = LEAST($2, tokens + $3)
+        WHERE bucket = $1
+        "#,
+        bucket,
+        f64::from(limit),
+        n as f64,
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Delete the buckets that refilled completely.
+///
+/// Every recipient domain gets a bucket, the table would keep growing otherwise.
+/// Buckets hold one second worth of emails: one left untouched for longer is full,
+/// just like the one `acquire` creates when it's missing.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PurgeRefilledSendRateBuckets;
+
+impl RecurringPurge for PurgeRefilledSendRateBuckets {
+    const JOB_TYPE: &'static str = "purge_refilled_send_rate_buckets";
+    const BATCH_SIZE: i64 = SEND_RATE_BUCKETS_PURGE_BATCH_SIZE;
+    const INTERVAL: Duration = Duration::from_secs(10 * 60);
+    const PURGED_METRIC: &'static str = "send_rate_buckets_purged_total";
+
+    async fn purge(transaction: &mut Transaction<'static, Postgres>) -> Result<u64, sqlx::Error> {
+        // Buckets being drawn from right now are skipped, they are not full anyway
+        let query = sqlx::query!(
+            r#"
+            DELETE FROM send_rate_limits
+            WHERE bucket IN (
+                SELECT bucket
+                FROM send_rate_limits
+                WHERE refilled_at < now() - interval '1 second'
+                FOR UPDATE
+                SKIP LOCKED
+                LIMIT $1
+            )
+            "#,
+            SEND_RATE_BUCKETS_PURGE_BATCH_SIZE,
+        );
+        Ok(transaction.execute(query).await?.rows_affected())
+    }
+}
@@ -0,0 +1,72 @@
+use std::future::Future;
+use tokio::sync::watch;
+
+/// A way to tell every long-running task (API, workers...) that it's time to stop.
+pub fn shutdown_channel() -> (ShutdownTrigger, ShutdownSignal) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger(sender), ShutdownSignal(receiver))
+}
+
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        // Nobody is listening anymore: there is nothing left to stop.
+        let _ = self.0.send(true);
+    }
+}
+
+/// Held by the tasks that must stop once the shutdown is triggered.
+#[derive(Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Returns once the shutdown is triggered (or its trigger is gone).
+    pub async fn recv(&mut self) {
+        let _ = self.0.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Drive `future` to completion, unless the shutdown is triggered first.
+    ///
+    /// Only use it for work that is safe to drop halfway through (e.g. waiting for new tasks).
+    pub async fn unless_triggered<F: Future>(&mut self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.recv() => None,
+        }
+    }
+}
+
+/// Wait for the process to be asked to stop: SIGTERM (e.g. during a deploy) or Ctrl+C.
+pub async fn termination_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => "SIGTERM",
+                    _ = tokio::signal::ctrl_c() => "SIGINT",
+                }
+            }
+            Err(e) => {
+                tracing::warn!(
+                    error.cause_chain = ?e,
+                    error.message = %e,
+                    "Failed to listen for SIGTERM. Only Ctrl+C will shut down gracefully."
+                );
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl+C"
+    }
+}
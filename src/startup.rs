@@ -1,15 +1,23 @@
 use crate::authentication::reject_anonymous_users;
-use crate::configuration::{DatabaseSettings, Settings};
+use crate::configuration::{
+    ConsentSettings, DatabaseSettings, EmailWebhookSettings, IdempotencySettings, Settings,
+    WorkerSettings,
+};
 use crate::email_client::EmailClient;
+use crate::idempotency::ensure_idempotency;
 use crate::routes::dashboard::admin_dashboard;
 use crate::routes::{
-    change_password, change_password_form, check_health, confirm, home, login, login_form, logout,
-    publish_newsletter, publish_newsletter_form, subscribe,
+    add_attribute, add_onboarding_step, attributes_form, change_password, change_password_form,
+    check_health, confirm, delete_attribute, delete_onboarding_step, email_events, export_metrics,
+    export_subscribers, home, jobs, login, login_form, logout, onboarding_form, publish_newsletter,
+    publish_newsletter_form, publish_newsletter_on_replay, receive_email_event, subscribe,
+    subscriber_details, subscribers_list, update_subscriber_attributes,
 };
+use crate::telemetry::{metrics_handle, record_http_metrics};
 use actix_session::storage::RedisSessionStore;
 use actix_session::SessionMiddleware;
 use actix_web::cookie::Key;
-use actix_web::dev::Server;
+use actix_web::dev::{Server, ServerHandle};
 use actix_web::middleware::from_fn;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpServer};
@@ -18,7 +26,8 @@ use actix_web_flash_messages::FlashMessagesFramework;
 use secrecy::{ExposeSecret, Secret};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
-use std::net::TcpListener;
+use std::net::{IpAddr, TcpListener};
+use std::time::Duration;
 use tracing_actix_web::TracingLogger;
 
 /// Read-more about the endpoints
@@ -38,10 +47,11 @@ use tracing_actix_web::TracingLogger;
 ///     retrieve the subscriber id associated with subscription_token from the subscription_tokens table;
 ///     update the subscriber status from pending_confirmation to active in the subscriptions table;
 ///     return a 200 OK.
-
 pub struct Application {
     port: u16,
     server: Server,
+    metrics_port: u16,
+    metrics_server: Server,
 }
 
 #[derive(Clone)]
@@ -62,9 +72,22 @@ impl Application {
         let listener = TcpListener::bind(address)?;
 
         let port = listener.local_addr()?.port();
+        // Metrics are served apart: only the API's port is exposed to the internet
+        let metrics_address = format!(
+            "{}:{}",
+            configuration.application.host, configuration.application.metrics_port
+        );
+        let metrics_listener = TcpListener::bind(metrics_address)?;
+        let metrics_port = metrics_listener.local_addr()?.port();
+        let metrics_server = run_metrics(
+            metrics_listener,
+            connection_pool.clone(),
+            configuration.shutdown.timeout(),
+        )?;
         let redis_url = configuration.redis_url;
         let base_url = configuration.application.base_url;
         let hmac_secret = configuration.application.hmac_secret;
+        let trusted_proxies = configuration.application.trusted_proxies;
         let server = run(
             base_url,
             listener,
@@ -72,19 +95,88 @@ impl Application {
             email_client,
             redis_url,
             hmac_secret,
+            trusted_proxies,
+            configuration.consent,
+            configuration.email_webhooks,
+            configuration.idempotency,
+            configuration.shutdown.timeout(),
         )
         .await?;
 
-        // We "save" the bound port in one of `Application`'s fields
-        Ok(Self { port, server })
+        // We "save" the bound ports in `Application`'s fields
+        Ok(Self {
+            port,
+            server,
+            metrics_port,
+            metrics_server,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    pub fn metrics_port(&self) -> u16 {
+        self.metrics_port
+    }
+
+    /// Lets the caller stop the servers while `run_until_stopped` is running.
+    pub fn handles(&self) -> Vec<ServerHandle> {
+        vec![self.server.handle(), self.metrics_server.handle()]
+    }
+
     // A more expressive name that makes it clear that
     // this function only returns when the application is stopped.
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        futures::future::try_join(self.server, self.metrics_server).await?;
+        Ok(())
+    }
+}
+
+/// The HTTP side of a standalone worker process: nothing but a health check.
+///
+/// It needs neither Redis nor sessions, unlike `Application`.
+pub struct WorkerApplication {
+    port: u16,
+    server: Server,
+}
+
+impl WorkerApplication {
+    pub fn build(configuration: &WorkerSettings) -> Result<Self, anyhow::Error> {
+        let address = format!(
+            "{}:{}",
+            configuration.worker.host, configuration.worker.port
+        );
+        let listener = TcpListener::bind(address)?;
+        let port = listener.local_addr()?.port();
+        // Pinged by the health check, and used to sample the queues when metrics are scraped
+        let db_pool = Data::new(get_connection_pool(&configuration.database));
+        let metrics = Data::new(metrics_handle());
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(TracingLogger::default())
+                .wrap(from_fn(record_http_metrics))
+                .route("/health_check", web::get().to(check_health))
+                .route("/metrics", web::get().to(export_metrics))
+                .app_data(db_pool.clone())
+                .app_data(metrics.clone())
+        })
+        .listen(listener)?
+        // `main` decides when to stop, in step with the workers
+        .disable_signals()
+        .shutdown_timeout(configuration.shutdown.timeout_seconds)
+        .run();
+        Ok(Self { port, server })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.server.handle()
+    }
+
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
         self.server.await
     }
@@ -96,6 +188,9 @@ impl Application {
 // a raw `String` would expose us to conflicts.
 pub struct ApplicationBaseUrl(pub String);
 
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run(
     base_url: String,
     listener: TcpListener,
@@ -103,6 +198,11 @@ pub async fn run(
     email_client: EmailClient,
     redis_url: Secret<String>,
     hmac_secret: Secret<String>,
+    trusted_proxies: Vec<IpAddr>,
+    consent_settings: ConsentSettings,
+    email_webhooks: EmailWebhookSettings,
+    idempotency_settings: IdempotencySettings,
+    shutdown_timeout: Duration,
 ) -> Result<Server, anyhow::Error> {
     // Wrap the connection in a smart pointer (an ARC) https://doc.rust-lang.org/std/sync/struct.Arc.html
     // Wrap the pool using web::Data, which boils down to an Arc smart pointer
@@ -111,6 +211,10 @@ pub async fn run(
     // app_data every time we need to build an App - like we are doing with PgPool
     let email_client = Data::new(email_client);
     let base_url = Data::new(ApplicationBaseUrl(base_url));
+    let trusted_proxies = Data::new(TrustedProxies(trusted_proxies));
+    let consent_settings = Data::new(consent_settings);
+    let email_webhooks = Data::new(email_webhooks);
+    let idempotency_settings = Data::new(idempotency_settings);
 
     let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
     let message_store =
@@ -128,6 +232,7 @@ pub async fn run(
                 redis_store.clone(),
                 secret_key.clone(),
             ))
+            .wrap(from_fn(record_http_metrics))
             .route("/", web::get().to(home))
             // introduce a scope for /admin routes
             .service(
@@ -138,8 +243,41 @@ pub async fn run(
                     .route("/dashboard", web::get().to(admin_dashboard))
                     .route("/password", web::post().to(change_password))
                     .route("/password", web::get().to(change_password_form))
-                    .route("/newsletters", web::post().to(publish_newsletter))
-                    .route("/newsletters", web::get().to(publish_newsletter_form)),
+                    .service(
+                        web::resource("/newsletters")
+                            .app_data(publish_newsletter_on_replay())
+                            .route(
+                                web::post()
+                                    .to(publish_newsletter)
+                                    .wrap(from_fn(ensure_idempotency)),
+                            )
+                            .route(web::get().to(publish_newsletter_form)),
+                    )
+                    .route("/onboarding", web::post().to(add_onboarding_step))
+                    .route("/onboarding", web::get().to(onboarding_form))
+                    .route(
+                        "/onboarding/{step_id}/delete",
+                        web::post().to(delete_onboarding_step),
+                    )
+                    .route("/subscribers", web::get().to(subscribers_list))
+                    // Registered before `/subscribers/{subscriber_id}`, which would match it too
+                    .route("/subscribers/export", web::get().to(export_subscribers))
+                    .route(
+                        "/subscribers/{subscriber_id}",
+                        web::get().to(subscriber_details),
+                    )
+                    .route(
+                        "/subscribers/{subscriber_id}/attributes",
+                        web::post().to(update_subscriber_attributes),
+                    )
+                    .route("/attributes", web::post().to(add_attribute))
+                    .route("/attributes", web::get().to(attributes_form))
+                    .route(
+                        "/attributes/{attribute_id}/delete",
+                        web::post().to(delete_attribute),
+                    )
+                    .route("/email-events", web::get().to(email_events))
+                    .route("/jobs", web::get().to(jobs)),
             )
             .route("/login", web::post().to(login))
             .route("/login", web::get().to(login_form))
@@ -147,12 +285,47 @@ pub async fn run(
             .route("/health_check", web::get().to(check_health))
             .route("/subscriptions/confirm", web::get().to(confirm))
             .route("/newsletters", web::post().to(publish_newsletter))
+            .route(
+                "/webhooks/email-events",
+                web::post().to(receive_email_event),
+            )
             .app_data(db_pool.clone())
             .app_data(base_url.clone())
             .app_data(email_client.clone())
+            .app_data(trusted_proxies.clone())
+            .app_data(consent_settings.clone())
+            .app_data(email_webhooks.clone())
+            .app_data(idempotency_settings.clone())
             .app_data(Data::new(HmacSecret(hmac_secret.clone())))
     })
     .listen(listener)?
+    // `main` decides when to stop, in step with the background workers
+    .disable_signals()
+    // How long in-flight requests get to complete once we stop
+    .shutdown_timeout(shutdown_timeout.as_secs())
+    .run();
+    Ok(server)
+}
+
+/// Serve `GET /metrics` for the API, on a port of its own that stays internal.
+fn run_metrics(
+    listener: TcpListener,
+    connection_pool: PgPool,
+    shutdown_timeout: Duration,
+) -> Result<Server, anyhow::Error> {
+    // Used to sample the queues when metrics are scraped
+    let db_pool = Data::new(connection_pool);
+    let metrics = Data::new(metrics_handle());
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(TracingLogger::default())
+            .route("/metrics", web::get().to(export_metrics))
+            .app_data(db_pool.clone())
+            .app_data(metrics.clone())
+    })
+    .listen(listener)?
+    .disable_signals()
+    .shutdown_timeout(shutdown_timeout.as_secs())
     .run();
     Ok(server)
 }
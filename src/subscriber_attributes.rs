@@ -0,0 +1,201 @@
+use crate::domain::{AttributeDefinition, AttributeKind, AttributeValue, NewAttributeDefinition};
+use chrono::NaiveDate;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+#[tracing::instrument(skip_all)]
+pub async fn get_attribute_definitions(
+    pool: &PgPool,
+) -> Result<Vec<AttributeDefinition>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT attribute_id, key, label, kind, options, required
+        FROM subscriber_attribute_definitions
+        ORDER BY created_at
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|r| {
+            Ok(AttributeDefinition {
+                attribute_id: r.attribute_id,
+                key: r.key,
+                label: r.label,
+                kind: AttributeKind::parse(&r.kind).map_err(|e| sqlx::Error::Decode(e.into()))?,
+                options: r.options,
+                required: r.required,
+            })
+        })
+        .collect()
+}
+
+/// Returns `false` if an attribute with the same key already exists.
+#[tracing::instrument(skip_all, fields(key = %definition.key))]
+pub async fn insert_attribute_definition(
+    pool: &PgPool,
+    definition: &NewAttributeDefinition,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO subscriber_attribute_definitions (
+            attribute_id,
+            key,
+            label,
+            kind,
+            options,
+            required,
+            created_at
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, now())
+        ON CONFLICT (key) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        definition.key,
+        definition.label,
+        definition.kind.as_str(),
+        &definition.options,
+        definition.required,
+    )
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Store the attributes of a subscriber. A `None` value removes the attribute.
+#[tracing::instrument(skip(transaction, values))]
+pub async fn store_attribute_values(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    values: &[(Uuid, Option<AttributeValue>)],
+) -> Result<(), sqlx::Error> {
+    for (attribute_id, value) in values {
+        let Some(value) = value else {
+            let query = sqlx::query!(
+                r#"
+                DELETE FROM subscriber_attribute_values
+                WHERE subscriber_id = $1 AND attribute_id = $2
+                "#,
+                subscriber_id,
+                attribute_id,
+            );
+            transaction.execute(query).await?;
+            continue;
+        };
+        let (text, number, date, boolean) = match value {
+            AttributeValue::Text(s) | AttributeValue::Enum(s) => {
+                (Some(s.as_str()), None, None, None)
+            }
+            AttributeValue::Number(n) => (None, Some(*n), None, None),
+            AttributeValue::Date(d) => (None, None, Some(*d), None),
+            AttributeValue::Boolean(b) => (None, None, None, Some(*b)),
+        };
+        let query = sqlx::query!(
+            r#"
+            INSERT INTO subscriber_attribute_values (
+                subscriber_id,
+                attribute_id,
+                text_value,
+                number_value,
+                date_value,
+                boolean_value,
+                updated_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, now())
+            ON CONFLICT (subscriber_id, attribute_id) DO UPDATE
+            SET
+                text_value = EXCLUDED.text_value,
+                number_value = EXCLUDED.number_value,
+                date_value = EXCLUDED.date_value,
+                boolean_value = EXCLUDED.boolean_value,
+                updated_at = EXCLUDED.updated_at
+            "#,
+            subscriber_id,
+            attribute_id,
+            text,
+            number,
+            date,
+            boolean,
+        );
+        transaction.execute(query).await?;
+    }
+    Ok(())
+}
+
+/// The attributes of a subscriber, keyed by attribute id.
+#[tracing::instrument(skip(pool))]
+pub async fn get_attribute_values(
+    pool: &PgPool,
+    subscriber_id: Uuid,
+) -> Result<HashMap<Uuid, AttributeValue>, sqlx::Error> {
+    let values = get_all_attribute_values(pool, Some(subscriber_id)).await?;
+    Ok(values.into_values().next().unwrap_or_default())
+}
+
+/// The attributes of every subscriber (or of a single one), keyed by subscriber id
+/// and then by attribute id.
+#[tracing::instrument(skip(pool))]
+pub async fn get_all_attribute_values(
+    pool: &PgPool,
+    subscriber_id: Option<Uuid>,
+) -> Result<HashMap<Uuid, HashMap<Uuid, AttributeValue>>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            v.subscriber_id,
+            v.attribute_id,
+            d.kind,
+            v.text_value,
+            v.number_value,
+            v.date_value,
+            v.boolean_value
+        FROM subscriber_attribute_values v
+        JOIN subscriber_attribute_definitions d ON d.attribute_id = v.attribute_id
+        WHERE $1::uuid IS NULL OR v.subscriber_id = $1
+        "#,
+        subscriber_id,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut values: HashMap<Uuid, HashMap<Uuid, AttributeValue>> = HashMap::new();
+    for r in rows {
+        let value = attribute_value(
+            &r.kind,
+            r.text_value,
+            r.number_value,
+            r.date_value,
+            r.boolean_value,
+        );
+        match value {
+            Some(value) => {
+                values
+                    .entry(r.subscriber_id)
+                    .or_default()
+                    .insert(r.attribute_id, value);
+            }
+            None => tracing::warn!(
+                attribute_id = %r.attribute_id,
+                "Stored attribute value does not match the kind of its attribute"
+            ),
+        }
+    }
+    Ok(values)
+}
+
+fn attribute_value(
+    kind: &str,
+    text: Option<String>,
+    number: Option<f64>,
+    date: Option<NaiveDate>,
+    boolean: Option<bool>,
+) -> Option<AttributeValue> {
+    match AttributeKind::parse(kind).ok()? {
+        AttributeKind::Text => text.map(AttributeValue::Text),
+        AttributeKind::Enum => text.map(AttributeValue::Enum),
+        AttributeKind::Number => number.map(AttributeValue::Number),
+        AttributeKind::Date => date.map(AttributeValue::Date),
+        AttributeKind::Boolean => boolean.map(AttributeValue::Boolean),
+    }
+}
@@ -0,0 +1,106 @@
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+
+/// Why an address ended up on the suppression list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SuppressionReason {
+    /// The mailbox does not exist (or no longer does).
+    HardBounce,
+    /// The recipient marked one of our emails as spam.
+    SpamComplaint,
+    /// The address was suppressed by hand on the provider's side (e.g. an unsubscribe).
+    ManualSuppression,
+    /// The provider refused to send to the address.
+    ProviderRejected,
+}
+
+impl SuppressionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuppressionReason::HardBounce => "hard_bounce",
+            SuppressionReason::SpamComplaint => "spam_complaint",
+            SuppressionReason::ManualSuppression => "manual_suppression",
+            SuppressionReason::ProviderRejected => "provider_rejected",
+        }
+    }
+}
+
+/// Stop emailing `email`: the email provider told us it cannot receive emails.
+///
+/// The address is added, lowercased, to the suppression list, which newsletter issues and new
+/// subscriptions check. Suppressed subscribers no longer get newsletter issues nor
+/// onboarding steps, and their confirmation link stops working.
+#[tracing::instrument(skip(transaction))]
+pub async fn suppress_recipient(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+    reason: SuppressionReason,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"
+        INSERT INTO suppression_list (email, reason, suppressed_at)
+        VALUES (lower($1), $2, now())
+        ON CONFLICT (email) DO NOTHING
+        "#,
+        email,
+        reason.as_str(),
+    );
+    transaction.execute(query).await?;
+    let query = sqlx::query!(
+        r#"UPDATE subscriptions SET status = 'suppressed' WHERE lower(email) = lower($1)"#,
+        email,
+    );
+    transaction.execute(query).await?;
+    tracing::warn!(
+        reason = reason.as_str(),
+        "Suppressing a recipient. They will not be emailed again."
+    );
+    Ok(())
+}
+
+/// Take `email` off the suppression list, e.g. once the provider reactivated it.
+///
+/// Subscribers keep their `suppressed` status: they have to subscribe again.
+#[tracing::instrument(skip(transaction))]
+pub async fn lift_suppression(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    let query = sqlx::query!(
+        r#"DELETE FROM suppression_list WHERE email = lower($1)"#,
+        email
+    );
+    transaction.execute(query).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip(pool))]
+pub async fn is_suppressed(pool: &PgPool, email: &str) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT email FROM suppression_list WHERE email = lower($1)"#,
+        email
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
+pub struct SuppressedAddress {
+    pub email: String,
+    pub reason: String,
+    pub suppressed_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// The suppression list, most recent entry first.
+#[tracing::instrument(skip(pool))]
+pub async fn get_suppression_list(pool: &PgPool) -> Result<Vec<SuppressedAddress>, sqlx::Error> {
+    sqlx::query_as!(
+        SuppressedAddress,
+        r#"
+        SELECT email, reason, suppressed_at
+        FROM suppression_list
+        ORDER BY suppressed_at DESC
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+}
@@ -1,8 +1,25 @@
+use crate::configuration::TelemetrySettings;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::{Lazy, OnceCell};
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 use tokio::task::JoinHandle;
 use tracing::subscriber::set_global_default;
-use tracing::Subscriber;
+use tracing::{Span, Subscriber};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_log::LogTracer;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{EnvFilter, Registry};
@@ -17,10 +34,14 @@ use tracing_subscriber::{EnvFilter, Registry};
 /// We need to explicitly call out that the returned subscriber is
 /// `Send` and `Sync` to make it possible to pass it to `init_subscriber`
 /// later on.
+///
+/// Spans are turned into OpenTelemetry spans even when they are not exported:
+/// the trace context of incoming requests is still passed on to the email provider.
 pub fn get_subscriber<S>(
     name: String,
     log_filter_level: String,
     sink: S, // Sink
+    settings: &TelemetrySettings,
 ) -> impl Sync + Send + Subscriber
 where
     // This "weird" syntax is a higher-ranked trait bound (HRTB)
@@ -35,16 +56,44 @@ where
     let env_filter =
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(log_filter_level));
 
+    let tracer_provider = get_tracer_provider(name.clone(), settings);
+    let opentelemetry_layer =
+        tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("zero2prod"));
+    // Kept to flush the spans left on shutdown. There is a single subscriber per process.
+    let _ = TRACER_PROVIDER.set(tracer_provider);
+
     let formatting_layer = BunyanFormattingLayer::new(name, sink);
 
     // The `with` method is provided by `SubscriberExt`, an extension
     // trait for `Subscriber` exposed by `tracing_subscriber`
     Registry::default()
         .with(env_filter)
+        .with(opentelemetry_layer)
         .with(JsonStorageLayer)
         .with(formatting_layer)
 }
 
+static TRACER_PROVIDER: OnceCell<TracerProvider> = OnceCell::new();
+
+fn get_tracer_provider(service_name: String, settings: &TelemetrySettings) -> TracerProvider {
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        settings.sampling_ratio,
+    )));
+    let mut builder = TracerProvider::builder()
+        .with_sampler(sampler)
+        .with_resource(Resource::new([KeyValue::new("service.name", service_name)]));
+    if let Some(endpoint) = &settings.otlp_endpoint {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .expect("Failed to build the OTLP span exporter");
+        // Spans are exported in batches from a dedicated thread, away from the request handlers
+        builder = builder.with_batch_exporter(exporter, runtime::TokioCurrentThread);
+    }
+    builder.build()
+}
+
 /// Register a subscriber as global default to process span data.
 ///
 /// It should only be called once!
@@ -52,6 +101,45 @@ pub fn init_subscriber(subscriber: impl Subscriber + Send + Sync) {
     // Redirect all `log`'s events to our subscriber
     LogTracer::init().expect("Failed to set logger");
     set_global_default(subscriber).expect("Failed to set subscriber");
+    // W3C `traceparent` headers are read by `TracingLogger` and sent to the email provider
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+}
+
+/// Export the spans that are still buffered. It blocks until they are sent.
+pub fn flush_traces() {
+    if let Some(tracer_provider) = TRACER_PROVIDER.get() {
+        for result in tracer_provider.force_flush() {
+            if let Err(e) = result {
+                tracing::error!(error.message = %e, "Failed to export spans");
+            }
+        }
+    }
+}
+
+/// The W3C trace context (`traceparent`, `tracestate`) of the current span.
+///
+/// Stored along work that is picked up later on, to link it back to the request it came from.
+pub fn current_trace_context() -> HashMap<String, String> {
+    let mut trace_context = HashMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut trace_context)
+    });
+    trace_context
+}
+
+/// The headers carrying the current trace context to the services we call.
+pub fn trace_context_headers() -> reqwest::header::HeaderMap {
+    (&current_trace_context()).try_into().unwrap_or_default()
+}
+
+/// Record on `span` that it follows from the work `trace_context` was captured in.
+///
+/// It is a link rather than a parent: the work is done in a trace of its own, maybe long after.
+pub fn link_to_trace_context(span: &Span, trace_context: &HashMap<String, String>) {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(trace_context)
+    });
+    span.add_link(context.span().span_context().clone());
 }
 
 // use every time we need to offload some CPU-intensive computation to a dedicated thread pool
@@ -66,3 +154,114 @@ where
     // and explicitly executes all our computation current scope.
     tokio::task::spawn_blocking(move || current_spawn.in_scope(f))
 }
+
+// Upper bounds of the buckets of latency histograms, in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+// Histogram samples are buffered until they are aggregated: don't wait for a scrape.
+const METRICS_UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+static METRICS: Lazy<PrometheusHandle> = Lazy::new(|| {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("duration_seconds".into()), LATENCY_BUCKETS)
+        .expect("Latency buckets cannot be empty")
+        .install_recorder()
+        .expect("Failed to install the metrics recorder");
+    let upkeep = handle.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(METRICS_UPKEEP_INTERVAL);
+        upkeep.run_upkeep();
+    });
+    describe_metrics();
+    handle
+});
+
+/// Renders every metric recorded in the process, in Prometheus' text format.
+///
+/// The recorder is installed on the first call: metrics recorded before then are lost.
+pub fn metrics_handle() -> PrometheusHandle {
+    METRICS.clone()
+}
+
+fn describe_metrics() {
+    describe_counter!("http_requests_total", "HTTP requests served");
+    describe_histogram!(
+        "http_request_duration_seconds",
+        metrics::Unit::Seconds,
+        "How long HTTP requests took to serve"
+    );
+    describe_gauge!(
+        "issue_delivery_queue_depth",
+        "Tasks waiting in the delivery queue, due or not"
+    );
+    describe_gauge!(
+        "issue_delivery_queue_oldest_task_age_seconds",
+        metrics::Unit::Seconds,
+        "How long the oldest due task of the delivery queue has been waiting"
+    );
+    describe_counter!("emails_sent_total", "Emails accepted by the email provider");
+    describe_counter!(
+        "email_send_failures_total",
+        "Emails the email provider did not accept, by class of error"
+    );
+    describe_gauge!(
+        "db_pool_connections",
+        "Connections of a Postgres pool, idle or in use"
+    );
+    describe_gauge!(
+        "db_pool_max_connections",
+        "How many connections a Postgres pool may open"
+    );
+    describe_counter!("login_attempts_total", "Login attempts, by outcome");
+    describe_counter!(
+        "idempotency_keys_purged_total",
+        "Expired idempotency keys deleted"
+    );
+    describe_counter!(
+        "send_rate_buckets_purged_total",
+        "Refilled send rate buckets deleted"
+    );
+    describe_counter!(
+        "finished_jobs_purged_total",
+        "Succeeded and failed jobs deleted once past their retention"
+    );
+}
+
+/// Count HTTP requests and time them, per route and status.
+pub async fn record_http_metrics(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let started_at = Instant::now();
+    // The route's pattern (e.g. `/admin/subscribers/{subscriber_id}`) rather than its path:
+    // a label taking arbitrary values would create a new time series for each of them.
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".into());
+    let method = req.method().to_string();
+
+    let result = next.call(req).await;
+
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", status.as_u16().to_string()),
+    ];
+    counter!("http_requests_total", &labels).increment(1);
+    histogram!("http_request_duration_seconds", &labels).record(started_at.elapsed().as_secs_f64());
+    result
+}
+
+/// Sample how many connections of `pool` are busy.
+pub fn record_pool_usage(pool_name: &'static str, pool: &PgPool) {
+    let size = pool.size();
+    let idle = pool.num_idle() as u32;
+    gauge!("db_pool_connections", "pool" => pool_name, "state" => "idle").set(idle);
+    gauge!("db_pool_connections", "pool" => pool_name, "state" => "in_use")
+        .set(size.saturating_sub(idle));
+    gauge!("db_pool_max_connections", "pool" => pool_name)
+        .set(pool.options().get_max_connections());
+}
@@ -1,5 +1,6 @@
-use actix_web::http::header::LOCATION;
-use actix_web::HttpResponse;
+use actix_web::http::header::{HeaderMap, ACCEPT, LOCATION};
+use actix_web::{HttpRequest, HttpResponse};
+use std::net::IpAddr;
 
 // Return an opaque 500 while preserving the error root's cause for logging.
 pub fn e500<T>(e: T) -> actix_web::Error
@@ -21,3 +22,91 @@ pub fn see_other(location: &str) -> HttpResponse {
         .insert_header((LOCATION, location))
         .finish()
 }
+
+// Whether the client asked for a JSON representation rather than an HTML page.
+pub fn wants_json(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains("application/json"))
+}
+
+/// The address of the client that sent `request`.
+///
+/// `X-Forwarded-For` is only honoured when the peer is one of `trusted_proxies`: we walk the
+/// header from the right and stop at the first hop that is not a trusted proxy, since anything
+/// to the left of it could have been forged by the client.
+pub fn client_ip(request: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = request.peer_addr()?.ip();
+    Some(resolve_client_ip(peer, request.headers(), trusted_proxies))
+}
+
+fn resolve_client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut client = peer;
+    let hops = headers
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in hops.into_iter().rev() {
+        if !trusted_proxies.contains(&client) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(ip) => client = ip,
+            // A malformed entry: we can't tell who is behind it.
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_client_ip;
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use std::net::IpAddr;
+
+    fn forwarded_for(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-forwarded-for"),
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_when_the_peer_is_not_trusted() {
+        let headers = forwarded_for("1.1.1.1");
+        let client = resolve_client_ip(ip("10.0.0.1"), &headers, &[]);
+        assert_eq!(client, ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_for_is_honoured_when_the_peer_is_trusted() {
+        let headers = forwarded_for("1.1.1.1");
+        let client = resolve_client_ip(ip("10.0.0.1"), &headers, &[ip("10.0.0.1")]);
+        assert_eq!(client, ip("1.1.1.1"));
+    }
+
+    #[test]
+    fn spoofed_hops_left_of_an_untrusted_one_are_ignored() {
+        let headers = forwarded_for("6.6.6.6, 1.1.1.1, 10.0.0.2");
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let client = resolve_client_ip(ip("10.0.0.1"), &headers, &trusted);
+        assert_eq!(client, ip("1.1.1.1"));
+    }
+
+    #[test]
+    fn a_malformed_hop_stops_the_walk() {
+        let headers = forwarded_for("1.1.1.1, not-an-ip");
+        let client = resolve_client_ip(ip("10.0.0.1"), &headers, &[ip("10.0.0.1")]);
+        assert_eq!(client, ip("10.0.0.1"));
+    }
+}
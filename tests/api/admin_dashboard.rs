@@ -1,5 +1,6 @@
-use crate::helpers::spawn_app;
+use crate::helpers::{spawn_app, spawn_app_with};
 use crate::login::assert_is_redirect_to;
+use zero2prod::configuration::{SandboxMode, SandboxSettings};
 
 #[tokio::test]
 async fn must_be_logged_in_to_access_admin_dashboard() {
@@ -40,3 +41,38 @@ async fn logout_clears_session_state() {
     let response = app.get_admin_dashboard().await;
     assert_is_redirect_to(&response, "/login");
 }
+
+#[tokio::test]
+async fn the_dashboard_warns_that_emails_are_sandboxed() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.email_client.sandbox = Some(SandboxSettings {
+            mode: SandboxMode::Redirect,
+            allowlist: vec!["@example.com".into()],
+            catch_all: Some("catch-all@example.com".into()),
+        })
+    })
+    .await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let html_page = app.get_admin_dashboard_html().await;
+
+    // Assert
+    assert!(html_page.contains(
+        "Sandbox mode: only @example.com get their emails, everyone else&#x27;s go to catch-all@example.com."
+    ));
+}
+
+#[tokio::test]
+async fn the_dashboard_has_no_sandbox_warning_when_emails_are_sent_for_real() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let html_page = app.get_admin_dashboard_html().await;
+
+    // Assert
+    assert!(!html_page.contains("sandbox-banner"));
+}
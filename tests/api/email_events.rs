@@ -0,0 +1,306 @@
+use crate::helpers::{spawn_app, TestApp};
+use crate::login::assert_is_redirect_to;
+use crate::newsletter::{create_confirmed_subscriber, publish_newsletter, when_delivering_a_batch};
+use wiremock::matchers::any;
+use wiremock::{Mock, ResponseTemplate};
+
+fn bounce(email: &str, bounce_type: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "ID": 4323372036854775807_i64,
+        "Type": bounce_type,
+        "TypeCode": 1,
+        "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+        "Description": "The server was unable to deliver your message (ex: unknown user, mailbox not found).",
+        "Details": "Test bounce details",
+        "Email": email,
+        "BouncedAt": "2019-11-05T16:33:54.9070259Z",
+        "Inactive": bounce_type == "HardBounce",
+    })
+}
+
+async fn subscriber_email(app: &TestApp) -> String {
+    sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .email
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .status
+}
+
+#[tokio::test]
+async fn email_events_without_valid_credentials_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .api_client
+        .post(format!("{}/webhooks/email-events", &app.address))
+        .basic_auth(&app.email_webhooks.username, Some("not-the-password"))
+        .json(&bounce("ursula@example.com", "HardBounce"))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    assert_eq!(
+        response.headers()["WWW-Authenticate"],
+        r#"Basic realm="email-events""#
+    );
+    let events = sqlx::query!("SELECT email_event_id FROM email_events")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(events.is_empty());
+}
+
+#[tokio::test]
+async fn hard_bounces_suppress_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    app.test_user.login(&app).await;
+
+    // Act - Part 1 - Postmark reports a hard bounce
+    let response = app.post_email_event(&bounce(&email, "HardBounce")).await;
+
+    // Assert - Part 1
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "suppressed");
+    let suppressed = sqlx::query!("SELECT email, reason FROM suppression_list")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The address should be suppressed");
+    assert_eq!(suppressed.email, email);
+    assert_eq!(suppressed.reason, "hard_bounce");
+
+    // Act - Part 2 - They are left out of the next issue
+    when_delivering_a_batch()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we did not email them
+}
+
+#[tokio::test]
+async fn suppressed_addresses_are_matched_regardless_of_case() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+    app.test_user.login(&app).await;
+
+    // Act - Part 1 - Postmark reports a hard bounce for the address in upper case
+    let response = app
+        .post_email_event(&bounce(&email.to_uppercase(), "HardBounce"))
+        .await;
+
+    // Assert - Part 1
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "suppressed");
+    let suppressed = sqlx::query!("SELECT email FROM suppression_list")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The address should be suppressed");
+    assert_eq!(suppressed.email, email.to_lowercase());
+
+    // Act - Part 2 - They are left out of the next issue
+    when_delivering_a_batch()
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we did not email them
+}
+
+#[tokio::test]
+async fn spam_complaints_suppress_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    // Act
+    let response = app
+        .post_email_event(&serde_json::json!({
+            "RecordType": "SpamComplaint",
+            "ID": 42,
+            "Type": "SpamComplaint",
+            "TypeCode": 512,
+            "MessageID": "00000000-0000-0000-0000-000000000000",
+            "Email": email,
+            "BouncedAt": "2019-11-05T16:33:54.9070259Z",
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "suppressed");
+    let suppressed = sqlx::query!("SELECT reason FROM suppression_list")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The address should be suppressed");
+    assert_eq!(suppressed.reason, "spam_complaint");
+}
+
+#[tokio::test]
+async fn soft_bounces_are_recorded_without_suppressing_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    let email = subscriber_email(&app).await;
+
+    // Act
+    let response = app.post_email_event(&bounce(&email, "SoftBounce")).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+    let event = sqlx::query!("SELECT record_type, message_id, suppressed FROM email_events")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The event should be recorded");
+    assert_eq!(event.record_type, "Bounce");
+    assert_eq!(
+        event.message_id.as_deref(),
+        Some("883953f4-6105-42a2-a16a-77a8eac79483")
+    );
+    assert!(!event.suppressed);
+}
+
+#[tokio::test]
+async fn reactivated_addresses_are_taken_off_the_suppression_list() {
+    // Arrange
+    let app = spawn_app().await;
+    let email = "ursula@example.com";
+    app.post_email_event(&bounce(email, "HardBounce")).await;
+
+    // Act
+    let response = app
+        .post_email_event(&serde_json::json!({
+            "RecordType": "SubscriptionChange",
+            "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+            "ChangedAt": "2020-02-01T10:53:34.416071Z",
+            "Recipient": email,
+            "Origin": "Recipient",
+            "SuppressSending": false,
+            "SuppressionReason": null,
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let suppressed = sqlx::query!("SELECT email FROM suppression_list")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(suppressed.is_empty());
+}
+
+#[tokio::test]
+async fn events_we_do_not_act_upon_are_acknowledged() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_email_event(&serde_json::json!({
+            "RecordType": "Open",
+            "MessageID": "883953f4-6105-42a2-a16a-77a8eac79483",
+            "Recipient": "ursula@example.com",
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[tokio::test]
+async fn malformed_events_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_email_event(&serde_json::json!({"RecordType": "Bounce"}))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+}
+
+#[tokio::test]
+async fn suppressed_addresses_do_not_get_a_confirmation_email() {
+    // Arrange
+    let app = spawn_app().await;
+    app.post_email_event(&bounce("ursula_le_guin@gmail.com", "HardBounce"))
+        .await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_outbox_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscribers = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(subscribers.is_empty());
+}
+
+#[tokio::test]
+async fn email_events_are_listed_in_the_admin() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    app.post_email_event(&bounce("ursula@example.com", "HardBounce"))
+        .await;
+
+    // Act
+    let html_page = app.get_email_events_html().await;
+
+    // Assert
+    assert!(html_page.contains("ursula@example.com"));
+    assert!(html_page.contains("883953f4-6105-42a2-a16a-77a8eac79483"));
+    assert!(html_page.contains("hard_bounce"));
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_the_email_events() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .api_client
+        .get(format!("{}/admin/email-events", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
@@ -1,4 +1,5 @@
 use crate::helpers::spawn_app;
+use zero2prod::startup::WorkerApplication;
 
 // `tokio::test` is the testing equivalent of `tokio::main`.
 // It also spares you from having to specify the `#[test]` attribute.
@@ -16,7 +17,7 @@ async fn health_check_works() {
 
     // Act
     let response = client
-        .get(&format!("{}/health_check", &app.address))
+        .get(format!("{}/health_check", &app.address))
         .send()
         .await
         .expect("Failed to execute request");
@@ -25,3 +26,47 @@ async fn health_check_works() {
     assert!(response.status().is_success());
     assert_eq!(Some(0), response.content_length());
 }
+
+#[tokio::test]
+async fn worker_health_check_works() {
+    // Arrange
+    let app = spawn_app().await;
+    let worker = WorkerApplication::build(&app.worker_configuration)
+        .expect("Failed to build the worker health check");
+    let address = format!("http://127.0.0.1:{}", worker.port());
+    tokio::spawn(worker.run_until_stopped());
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/health_check", address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert!(response.status().is_success());
+    assert_eq!(Some(0), response.content_length());
+}
+
+#[tokio::test]
+async fn worker_health_check_fails_when_the_database_is_unreachable() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut configuration = app.worker_configuration.clone();
+    // Nothing listens there
+    configuration.database.port = 1;
+    let worker =
+        WorkerApplication::build(&configuration).expect("Failed to build the worker health check");
+    let address = format!("http://127.0.0.1:{}", worker.port());
+    tokio::spawn(worker.run_until_stopped());
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/health_check", address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 503);
+}
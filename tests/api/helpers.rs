@@ -1,29 +1,68 @@
 use argon2::password_hash::SaltString;
 use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
 use once_cell::sync::Lazy;
+use secrecy::ExposeSecret;
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use uuid::Uuid;
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zero2prod::configuration::{
+    get_configuration, get_worker_configuration, DatabaseSettings, DeliveryWorkerSettings,
+    EmailWebhookSettings, IdempotencySettings, Settings, TelemetrySettings, WorkerSettings,
+};
 use zero2prod::email_client::EmailClient;
-use zero2prod::issue_delivery_worker::{try_execute_task, ExecutionOutcome};
+use zero2prod::email_outbox::try_dispatch_email;
+use zero2prod::issue_delivery_worker::{try_execute_batch, ExecutionOutcome};
+use zero2prod::jobs::{registry, try_execute_job};
 use zero2prod::startup::{get_connection_pool, Application};
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
+// Stands in for an OpenTelemetry collector: the spans of every test are exported to it.
+// It is started on a thread of its own to outlive the runtime of the test that needed it first.
+pub static COLLECTOR: Lazy<MockServer> = Lazy::new(|| {
+    std::thread::spawn(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Failed to build the collector's runtime");
+        runtime.block_on(async {
+            let collector = MockServer::builder().start().await;
+            Mock::given(method("POST"))
+                .and(path("/v1/traces"))
+                .respond_with(ResponseTemplate::new(200))
+                .mount(&collector)
+                .await;
+            collector
+        })
+    })
+    .join()
+    .unwrap()
+});
+
 // Ensure that the `tracing` stack is only initialised once using `once_cell`
 static TRACING: Lazy<()> = Lazy::new(|| {
     let subscriber_name = "test".into();
     let filter_log_level = "debug".into();
+    let telemetry = TelemetrySettings {
+        otlp_endpoint: Some(format!("{}/v1/traces", COLLECTOR.uri())),
+        sampling_ratio: 1.0,
+    };
     // We cannot assign the output of `get_subscriber` to a variable based on the
     // value TEST_LOG` because the sink is part of the type returned by
     // `get_subscriber`, therefore they are not the same type. We could work around
     // it, but this is the most straight-forward way of moving forward.
 
     if std::env::var("TEST_LOG").is_ok() {
-        let subscriber = get_subscriber(subscriber_name, filter_log_level, std::io::stdout);
+        let subscriber = get_subscriber(
+            subscriber_name,
+            filter_log_level,
+            std::io::stdout,
+            &telemetry,
+        );
         init_subscriber(subscriber);
     } else {
-        let subscriber = get_subscriber(subscriber_name, filter_log_level, std::io::sink);
+        let subscriber =
+            get_subscriber(subscriber_name, filter_log_level, std::io::sink, &telemetry);
         init_subscriber(subscriber);
     };
 });
@@ -36,14 +75,26 @@ pub struct ConfirmationLinks {
 pub struct TestApp {
     pub port: u16,
     pub address: String,
+    // Where the API serves its metrics, apart from the public routes
+    pub metrics_address: String,
     pub db_pool: PgPool,
     pub test_user: TestUser,
     pub email_server: MockServer,
     pub email_client: EmailClient,
+    pub delivery_worker: DeliveryWorkerSettings,
+    pub email_webhooks: EmailWebhookSettings,
+    pub idempotency: IdempotencySettings,
     pub api_client: reqwest::Client,
+    // What a standalone worker process would run with
+    pub worker_configuration: WorkerSettings,
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+/// Spawn the app with a configuration tweaked by `configure`.
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     // The first time `initialize` is invoked the code in `TRACING` is executed.
     // All other invocations will instead skip execution.
     Lazy::force(&TRACING);
@@ -60,19 +111,35 @@ pub async fn spawn_app() -> TestApp {
         // Port 0 is special-cased at the OS level: trying to bind port 0 will trigger an OS scan
         // for an available port which will then be bound to the application
         config.application.port = 0;
+        config.application.metrics_port = 0;
         config.email_client.base_url = email_server.uri();
+        // Tests pretend to sit behind a local reverse proxy to exercise `X-Forwarded-For`
+        config.application.trusted_proxies = vec!["127.0.0.1".parse().unwrap()];
+        // Tests check what reaches the email provider
+        config.email_client.sandbox = None;
+        configure(&mut config);
         config
     };
 
     configure_database(&configuration.database).await;
 
+    // The workers are not started: tests run them on demand.
+    let worker_configuration = {
+        let mut config = get_worker_configuration().expect("failed to read configuration");
+        config.database = configuration.database.clone();
+        config.email_client = configuration.email_client.clone();
+        config.worker.port = 0;
+        config
+    };
+
     // Launch the application as a background task.
     // Notice the .clone!
     let application = Application::build(configuration.clone())
         .await
         .expect("failed to build application");
     let application_port = application.port();
-    let _ = tokio::spawn(application.run_until_stopped());
+    let metrics_port = application.metrics_port();
+    tokio::spawn(application.run_until_stopped());
 
     let test_user = TestUser::generate();
 
@@ -89,8 +156,13 @@ pub async fn spawn_app() -> TestApp {
         api_client: client,
         port: application_port,
         email_client: configuration.email_client.client(),
+        delivery_worker: configuration.delivery_worker.clone(),
+        email_webhooks: configuration.email_webhooks.clone(),
+        idempotency: configuration.idempotency.clone(),
         db_pool: get_connection_pool(&configuration.database),
         address: format!("http://127.0.0.1:{}", application_port),
+        metrics_address: format!("http://127.0.0.1:{}", metrics_port),
+        worker_configuration,
     };
 
     test_app.test_user.store(&test_app.db_pool).await;
@@ -100,7 +172,7 @@ pub async fn spawn_app() -> TestApp {
 impl TestApp {
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
             .body(body)
             .send()
@@ -113,7 +185,7 @@ impl TestApp {
         Body: serde::Serialize,
     {
         self.api_client
-            .post(&format!("{}/login", &self.address))
+            .post(format!("{}/login", &self.address))
             // This `reqwest` method makes sure that the body is URL-encoded
             // and the `Content-Type` header is set accordingly.
             .form(&body)
@@ -123,7 +195,7 @@ impl TestApp {
     }
     pub async fn get_admin_dashboard(&self) -> reqwest::Response {
         self.api_client
-            .get(&format!("{}/admin/dashboard", &self.address))
+            .get(format!("{}/admin/dashboard", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -137,7 +209,7 @@ impl TestApp {
     // we do not expose the underlying reqwest::Response
     pub async fn get_login_html(&self) -> String {
         self.api_client
-            .get(&format!("{}/login", &self.address))
+            .get(format!("{}/login", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -148,7 +220,7 @@ impl TestApp {
 
     pub async fn get_change_password(&self) -> reqwest::Response {
         self.api_client
-            .get(&format!("{}/admin/password", &self.address))
+            .get(format!("{}/admin/password", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -163,7 +235,7 @@ impl TestApp {
         Body: serde::Serialize,
     {
         self.api_client
-            .post(&format!("{}/admin/password", &self.address))
+            .post(format!("{}/admin/password", &self.address))
             .form(body)
             .send()
             .await
@@ -172,7 +244,7 @@ impl TestApp {
 
     pub async fn post_logout(&self) -> reqwest::Response {
         self.api_client
-            .post(&format!("{}/admin/logout", &self.address))
+            .post(format!("{}/admin/logout", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -180,7 +252,7 @@ impl TestApp {
 
     pub async fn get_publish_newsletter(&self) -> reqwest::Response {
         self.api_client
-            .get(&format!("{}/admin/newsletters", &self.address))
+            .get(format!("{}/admin/newsletters", &self.address))
             .send()
             .await
             .expect("Failed to execute request.")
@@ -195,13 +267,157 @@ impl TestApp {
         Body: serde::Serialize,
     {
         self.api_client
-            .post(&format!("{}/admin/newsletters", &self.address))
+            .post(format!("{}/admin/newsletters", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_onboarding(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/onboarding", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_onboarding_html(&self) -> String {
+        self.get_onboarding().await.text().await.unwrap()
+    }
+
+    pub async fn post_onboarding_step<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/onboarding", &self.address))
             .form(body)
             .send()
             .await
             .expect("Failed to execute request.")
     }
 
+    pub async fn get_subscribers(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/subscribers", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_subscriber_details(&self, subscriber_id: Uuid) -> reqwest::Response {
+        self.api_client
+            .get(format!(
+                "{}/admin/subscribers/{}",
+                &self.address, subscriber_id
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_subscribers_export(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/subscribers/export", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_attributes(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/attributes", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_attributes_html(&self) -> String {
+        self.get_attributes().await.text().await.unwrap()
+    }
+
+    pub async fn post_attribute<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/admin/attributes", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_subscriber_attributes<Body>(
+        &self,
+        subscriber_id: Uuid,
+        body: &Body,
+    ) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!(
+                "{}/admin/subscribers/{}/attributes",
+                &self.address, subscriber_id
+            ))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    /// Push an event to the email provider webhook, authenticated as the provider.
+    pub async fn post_email_event(&self, event: &serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/webhooks/email-events", &self.address))
+            .basic_auth(
+                &self.email_webhooks.username,
+                Some(self.email_webhooks.password.expose_secret()),
+            )
+            .json(event)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_email_events_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/admin/email-events", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_jobs(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/jobs", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn get_jobs_html(&self) -> String {
+        self.get_jobs().await.text().await.unwrap()
+    }
+
+    pub async fn get_metrics(&self) -> String {
+        self.api_client
+            .get(format!("{}/metrics", &self.metrics_address))
+            .send()
+            .await
+            .expect("Failed to execute request.")
+            .error_for_status()
+            .unwrap()
+            .text()
+            .await
+            .unwrap()
+    }
+
     /// Extract the confirmation links embedded in the request to the email API.
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         // Parse the body as JSON, starting from raw bytes
@@ -222,16 +438,43 @@ impl TestApp {
             confirmation_link
         };
 
-        let html = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(&body["TextBody"].as_str().unwrap());
+        let html = get_link(body["HtmlBody"].as_str().unwrap());
+        let plain_text = get_link(body["TextBody"].as_str().unwrap());
         ConfirmationLinks { html, plain_text }
     }
 
     // a helper to consume all enqueued tasks
     pub async fn dispatch_all_pending_emails(&self) {
+        // Publishing a newsletter only enqueues its tasks once its job has run
+        self.run_pending_jobs().await;
         loop {
             if let ExecutionOutcome::EmptyQueue =
-                try_execute_task(&self.db_pool, &self.email_client)
+                try_execute_batch(&self.db_pool, &self.email_client, &self.delivery_worker)
+                    .await
+                    .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    // a helper to run all the background jobs that are due
+    pub async fn run_pending_jobs(&self) {
+        let registry = registry();
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_execute_job(&self.db_pool, &registry).await.unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    // a helper to send all the emails waiting in the outbox (e.g. confirmation emails)
+    pub async fn dispatch_all_outbox_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_dispatch_email(&self.db_pool, &self.email_client)
                     .await
                     .unwrap()
             {
@@ -316,3 +559,19 @@ async fn configure_database(config: &DatabaseSettings) -> PgPool {
 
     connection_pool
 }
+
+/// Accept every message of a request to Postmark's batch API.
+pub fn accept_batch(request: &wiremock::Request) -> ResponseTemplate {
+    let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+    let results: Vec<_> = messages
+        .iter()
+        .map(|_| {
+            serde_json::json!({
+                "ErrorCode": 0,
+                "Message": "OK",
+                "MessageID": uuid::Uuid::new_v4().to_string()
+            })
+        })
+        .collect();
+    ResponseTemplate::new(200).set_body_json(results)
+}
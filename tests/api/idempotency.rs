@@ -0,0 +1,355 @@
+use crate::helpers::{spawn_app, TestApp};
+use crate::login::assert_is_redirect_to;
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use zero2prod::idempotency::{
+    save_response, try_processing, IdempotencyKey, IdempotencyScope, NextAction,
+    PurgeExpiredIdempotencyKeys,
+};
+use zero2prod::jobs::{registry, schedule_recurring_jobs, try_execute_job, RecurringPurge};
+
+fn idempotency_key() -> IdempotencyKey {
+    uuid::Uuid::new_v4().to_string().try_into().unwrap()
+}
+
+fn scope(app: &TestApp) -> IdempotencyScope {
+    IdempotencyScope::User(app.test_user.user_id)
+}
+
+async fn process(app: &TestApp, key: &IdempotencyKey) -> NextAction {
+    try_processing(
+        &app.db_pool,
+        &scope(app),
+        key,
+        b"fingerprint",
+        &app.idempotency,
+    )
+    .await
+    .unwrap()
+}
+
+async fn save(
+    app: &TestApp,
+    key: &IdempotencyKey,
+    response: HttpResponse,
+) -> Result<HttpResponse, anyhow::Error> {
+    let NextAction::StartProcessing(transaction) = process(app, key).await else {
+        panic!("The request was processed already");
+    };
+    save_response(transaction, &scope(app), key, response, &app.idempotency).await
+}
+
+async fn replay(app: &TestApp, key: &IdempotencyKey) -> HttpResponse {
+    match process(app, key).await {
+        NextAction::ReturnSavedResponse(response) => response,
+        NextAction::StartProcessing(_) => panic!("No response was saved"),
+    }
+}
+
+#[tokio::test]
+async fn saved_responses_are_replayed_with_their_status_headers_and_body() {
+    // Arrange
+    let app = spawn_app().await;
+    let key = idempotency_key();
+    let response = HttpResponse::Created()
+        .insert_header(("X-Resource-Id", "42"))
+        .content_type("application/json")
+        .body(r#"{"id":42}"#);
+
+    // Act
+    let response = save(&app, &key, response).await.unwrap();
+    let replayed = replay(&app, &key).await;
+
+    // Assert
+    assert_eq!(replayed.status(), StatusCode::CREATED);
+    assert_eq!(replayed.headers().get("X-Resource-Id").unwrap(), "42");
+    assert_eq!(
+        replayed.headers().get("Content-Type").unwrap(),
+        "application/json"
+    );
+    let body = to_bytes(replayed.into_body()).await.unwrap();
+    assert_eq!(body, r#"{"id":42}"#);
+    // The response to the original request is left untouched
+    let original_body = to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(original_body, body);
+}
+
+#[tokio::test]
+async fn streaming_response_bodies_are_saved_whole() {
+    // Arrange
+    let app = spawn_app().await;
+    let key = idempotency_key();
+    let chunks = ["first chunk, ", "second chunk"]
+        .map(|chunk| Ok::<_, std::io::Error>(Bytes::from_static(chunk.as_bytes())));
+    let response = HttpResponse::Ok().streaming(futures::stream::iter(chunks));
+
+    // Act
+    save(&app, &key, response).await.unwrap();
+    let replayed = replay(&app, &key).await;
+
+    // Assert
+    assert_eq!(replayed.status(), StatusCode::OK);
+    assert!(replayed.headers().get("Transfer-Encoding").is_none());
+    let body = to_bytes(replayed.into_body()).await.unwrap();
+    assert_eq!(body, "first chunk, second chunk");
+}
+
+#[tokio::test]
+async fn responses_with_a_body_over_the_limit_are_not_saved() {
+    // Arrange
+    let app = spawn_app().await;
+    let key = idempotency_key();
+    let body = "a".repeat(app.idempotency.max_response_body_bytes + 1);
+
+    // Act
+    let outcome = save(&app, &key, HttpResponse::Ok().body(body)).await;
+
+    // Assert
+    assert!(outcome.is_err());
+    let saved = sqlx::query!(r#"SELECT count(*) AS "count!" FROM idempotency"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.count, 0);
+    // The request can be processed again
+    assert!(matches!(
+        process(&app, &key).await,
+        NextAction::StartProcessing(_)
+    ));
+}
+
+#[tokio::test]
+async fn keys_are_scoped_to_whoever_sent_the_request() {
+    // Arrange
+    let app = spawn_app().await;
+    let key = idempotency_key();
+    save(&app, &key, HttpResponse::Ok().finish()).await.unwrap();
+
+    // Act
+    let api_client = IdempotencyScope::ApiClient("postmark".into());
+    let next_action = try_processing(
+        &app.db_pool,
+        &api_client,
+        &key,
+        b"fingerprint",
+        &app.idempotency,
+    )
+    .await
+    .unwrap();
+
+    // Assert
+    assert!(matches!(next_action, NextAction::StartProcessing(_)));
+}
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+        "idempotency_key": uuid::Uuid::new_v4().to_string(),
+    })
+}
+
+async fn count_newsletter_issues(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT count(*) AS "count!" FROM newsletter_issues"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn the_idempotency_key_can_be_sent_in_a_header() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let mut body = newsletter_request_body();
+    body.as_object_mut().unwrap().remove("idempotency_key");
+    let key = uuid::Uuid::new_v4().to_string();
+
+    for _ in 0..2 {
+        // Act
+        let response = app
+            .api_client
+            .post(format!("{}/admin/newsletters", &app.address))
+            .header("Idempotency-Key", &key)
+            .form(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.");
+
+        // Assert
+        assert_is_redirect_to(&response, "/admin/newsletters");
+    }
+    assert_eq!(count_newsletter_issues(&app).await, 1);
+}
+
+#[tokio::test]
+async fn requests_without_an_idempotency_key_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let mut body = newsletter_request_body();
+    body.as_object_mut().unwrap().remove("idempotency_key");
+
+    // Act
+    let response = app.post_publish_newsletter(&body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(count_newsletter_issues(&app).await, 0);
+}
+
+#[tokio::test]
+async fn reusing_a_key_for_a_different_request_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let body = newsletter_request_body();
+    let response = app.post_publish_newsletter(&body).await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+
+    // Act
+    let mut other_body = body.clone();
+    other_body["title"] = "Another title".into();
+    let response = app.post_publish_newsletter(&other_body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 422);
+    assert_eq!(count_newsletter_issues(&app).await, 1);
+}
+
+#[tokio::test]
+async fn requests_arriving_while_the_first_one_is_processed_get_a_409() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let body = newsletter_request_body();
+    let key = body["idempotency_key"]
+        .as_str()
+        .unwrap()
+        .to_owned()
+        .try_into()
+        .unwrap();
+    // The first request is still being processed
+    let first_request = process(&app, &key).await;
+
+    // Act - Part 1
+    let response = app.post_publish_newsletter(&body).await;
+
+    // Assert - Part 1
+    assert_eq!(response.status().as_u16(), 409);
+
+    // Act - Part 2 - The first request failed, the key can be used again
+    drop(first_request);
+    let response = app.post_publish_newsletter(&body).await;
+
+    // Assert - Part 2
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    assert_eq!(count_newsletter_issues(&app).await, 1);
+}
+
+#[tokio::test]
+async fn error_responses_are_not_saved() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    let body = newsletter_request_body();
+    let mut invalid_body = body.clone();
+    invalid_body.as_object_mut().unwrap().remove("title");
+    let response = app.post_publish_newsletter(&invalid_body).await;
+    assert_eq!(response.status().as_u16(), 400);
+
+    // Act
+    let response = app.post_publish_newsletter(&body).await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    assert_eq!(count_newsletter_issues(&app).await, 1);
+}
+
+#[tokio::test]
+async fn expired_keys_are_processed_as_new_requests() {
+    // Arrange
+    let app = spawn_app().await;
+    let key = idempotency_key();
+    save(&app, &key, HttpResponse::Ok().finish()).await.unwrap();
+    sqlx::query!("UPDATE idempotency SET expires_at = now() - interval '1 second'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let next_action = process(&app, &key).await;
+
+    // Assert
+    assert!(matches!(next_action, NextAction::StartProcessing(_)));
+}
+
+async fn purge_queue(app: &TestApp) -> Vec<bool> {
+    sqlx::query!(
+        r#"
+        SELECT run_at <= now() AS "due!"
+        FROM jobs
+        WHERE job_type = 'purge_expired_idempotency_keys' AND state = 'queued'
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|job| job.due)
+    .collect()
+}
+
+async fn count_idempotency_keys(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT count(*) AS "count!" FROM idempotency"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn expired_keys_are_purged_in_batches() {
+    // Arrange
+    let app = spawn_app().await;
+    save(&app, &idempotency_key(), HttpResponse::Ok().finish())
+        .await
+        .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO idempotency (scope, idempotency_key, created_at, expires_at)
+        SELECT 'user:' || gen_random_uuid(), 'key', now(), now() - interval '1 second'
+        FROM generate_series(1, $1)
+        "#,
+        PurgeExpiredIdempotencyKeys::BATCH_SIZE as i32 + 1,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    schedule_recurring_jobs(&app.db_pool).await.unwrap();
+    schedule_recurring_jobs(&app.db_pool).await.unwrap();
+    assert_eq!(purge_queue(&app).await, vec![true]);
+    // Leave the purge alone in the queue, each run below executes it
+    sqlx::query!("DELETE FROM jobs WHERE job_type <> 'purge_expired_idempotency_keys'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act - Part 1
+    try_execute_job(&app.db_pool, &registry()).await.unwrap();
+
+    // Assert - Part 1 - A full batch was purged, the next one is due right away
+    assert_eq!(count_idempotency_keys(&app).await, 2);
+    assert_eq!(purge_queue(&app).await, vec![true]);
+
+    // Act - Part 2
+    try_execute_job(&app.db_pool, &registry()).await.unwrap();
+
+    // Assert - Part 2 - Keys that did not expire are kept, the next purge is due later
+    assert_eq!(count_idempotency_keys(&app).await, 1);
+    assert_eq!(purge_queue(&app).await, vec![false]);
+}
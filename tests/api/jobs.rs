@@ -0,0 +1,265 @@
+use crate::helpers::{spawn_app, TestApp};
+use crate::login::assert_is_redirect_to;
+use crate::newsletter::{create_confirmed_subscriber, publish_newsletter};
+use chrono::{Duration, Utc};
+use sqlx::{Postgres, Transaction};
+use zero2prod::issue_delivery_worker::ExecutionOutcome;
+use zero2prod::jobs::{
+    enqueue, enqueue_at, schedule_recurring_jobs, try_execute_job, Job, JobRegistry,
+};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct FailingJob {
+    key: String,
+}
+
+impl Job for FailingJob {
+    const JOB_TYPE: &'static str = "failing_job";
+    const MAX_ATTEMPTS: i16 = 2;
+
+    fn unique_key(&self) -> Option<String> {
+        Some(self.key.clone())
+    }
+
+    async fn run(self, _: &mut Transaction<'static, Postgres>) -> Result<(), anyhow::Error> {
+        Err(anyhow::anyhow!("Something went wrong"))
+    }
+}
+
+fn failing_job() -> FailingJob {
+    FailingJob {
+        key: uuid::Uuid::new_v4().to_string(),
+    }
+}
+
+async fn enqueue_job(app: &TestApp, job: &FailingJob) -> bool {
+    let mut transaction = app.db_pool.begin().await.unwrap();
+    let queued = enqueue(&mut transaction, job).await.unwrap();
+    transaction.commit().await.unwrap();
+    queued
+}
+
+async fn execute_job(app: &TestApp) -> ExecutionOutcome {
+    let registry = JobRegistry::default().register::<FailingJob>();
+    try_execute_job(&app.db_pool, &registry).await.unwrap()
+}
+
+#[tokio::test]
+async fn failing_jobs_are_retried_later_then_marked_as_failed() {
+    // Arrange
+    let app = spawn_app().await;
+    enqueue_job(&app, &failing_job()).await;
+
+    // Act - Part 1 - The first attempt fails
+    assert!(matches!(
+        execute_job(&app).await,
+        ExecutionOutcome::TaskCompleted
+    ));
+
+    // Assert - Part 1 - The job waits before its next attempt
+    let job = sqlx::query!(
+        r#"SELECT state, n_attempts, last_error, run_at > now() AS "delayed!" FROM jobs"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(job.state, "queued");
+    assert_eq!(job.n_attempts, 1);
+    assert_eq!(job.last_error.as_deref(), Some("Something went wrong"));
+    assert!(job.delayed);
+    assert!(matches!(
+        execute_job(&app).await,
+        ExecutionOutcome::EmptyQueue
+    ));
+
+    // Act - Part 2 - The last attempt fails too
+    sqlx::query!("UPDATE jobs SET run_at = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    execute_job(&app).await;
+
+    // Assert - Part 2
+    let job = sqlx::query!("SELECT state, n_attempts, finished_at FROM jobs")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(job.state, "failed");
+    assert_eq!(job.n_attempts, 2);
+    assert!(job.finished_at.is_some());
+    assert!(matches!(
+        execute_job(&app).await,
+        ExecutionOutcome::EmptyQueue
+    ));
+}
+
+#[tokio::test]
+async fn jobs_sharing_a_unique_key_are_only_queued_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let job = failing_job();
+
+    // Act
+    let first = enqueue_job(&app, &job).await;
+    let second = enqueue_job(&app, &job).await;
+    let other = enqueue_job(&app, &failing_job()).await;
+
+    // Assert
+    assert!(first);
+    assert!(!second);
+    assert!(other);
+    let queued = sqlx::query!(r#"SELECT count(*) AS "count!" FROM jobs"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 2);
+}
+
+#[tokio::test]
+async fn a_unique_key_can_be_reused_once_its_job_is_finished() {
+    // Arrange
+    let app = spawn_app().await;
+    let job = failing_job();
+    enqueue_job(&app, &job).await;
+    sqlx::query!("UPDATE jobs SET state = 'failed', finished_at = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let queued = enqueue_job(&app, &job).await;
+
+    // Assert
+    assert!(queued);
+}
+
+#[tokio::test]
+async fn finished_jobs_are_purged_once_past_their_retention() {
+    // Arrange
+    let app = spawn_app().await;
+    for _ in 0..3 {
+        enqueue_job(&app, &failing_job()).await;
+    }
+    sqlx::query!(
+        r#"
+        UPDATE jobs
+        SET
+            state = CASE WHEN job_id % 2 = 0 THEN 'succeeded' ELSE 'failed' END,
+            finished_at = now() - interval '8 days'
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    let recent_job = failing_job();
+    enqueue_job(&app, &recent_job).await;
+    sqlx::query!(
+        "UPDATE jobs SET state = 'failed', finished_at = now() WHERE unique_key = $1",
+        recent_job.key,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    schedule_recurring_jobs(&app.db_pool).await.unwrap();
+
+    // Act
+    app.run_pending_jobs().await;
+
+    // Assert
+    let jobs = sqlx::query!("SELECT unique_key FROM jobs WHERE job_type = 'failing_job'")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].unique_key.as_deref(), Some(recent_job.key.as_str()));
+}
+
+#[tokio::test]
+async fn scheduled_jobs_do_not_run_before_their_time() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut transaction = app.db_pool.begin().await.unwrap();
+    enqueue_at(
+        &mut transaction,
+        &failing_job(),
+        Utc::now() + Duration::hours(1),
+    )
+    .await
+    .unwrap();
+    transaction.commit().await.unwrap();
+
+    // Act
+    let outcome = execute_job(&app).await;
+
+    // Assert
+    assert!(matches!(outcome, ExecutionOutcome::EmptyQueue));
+    let job = sqlx::query!("SELECT state, n_attempts FROM jobs")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(job.state, "queued");
+    assert_eq!(job.n_attempts, 0);
+}
+
+#[tokio::test]
+async fn jobs_without_a_registered_handler_are_retried_later() {
+    // Arrange
+    let app = spawn_app().await;
+    enqueue_job(&app, &failing_job()).await;
+
+    // Act
+    try_execute_job(&app.db_pool, &JobRegistry::default())
+        .await
+        .unwrap();
+
+    // Assert
+    let job = sqlx::query!("SELECT state, last_error FROM jobs")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(job.state, "queued");
+    assert_eq!(
+        job.last_error.as_deref(),
+        Some("No handler is registered for jobs of type failing_job")
+    );
+}
+
+#[tokio::test]
+async fn publishing_a_newsletter_fans_it_out_in_a_background_job() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+
+    // Act
+    publish_newsletter(&app).await;
+
+    // Assert
+    let job = sqlx::query!("SELECT job_type, state FROM jobs")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(job.job_type, "deliver_newsletter_issue");
+    assert_eq!(job.state, "succeeded");
+    let queued = sqlx::query!(r#"SELECT count(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 1);
+
+    let html_page = app.get_jobs_html().await;
+    assert!(html_page.contains("deliver_newsletter_issue"));
+    assert!(html_page.contains("<li>succeeded: 1</li>"));
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_the_jobs() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.get_jobs().await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
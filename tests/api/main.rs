@@ -1,8 +1,19 @@
 mod admin_dashboard;
 mod change_password;
+mod email_events;
 mod health_check;
 mod helpers;
+mod idempotency;
+mod jobs;
 mod login;
+mod metrics;
 mod newsletter;
+mod onboarding;
+mod queue_listener;
+mod send_intents;
+mod shutdown;
+mod subscriber_attributes;
+mod subscribers;
 mod subscriptions;
 mod subscriptions_confirmation;
+mod traces;
@@ -0,0 +1,176 @@
+use crate::helpers::spawn_app;
+use crate::newsletter::{create_confirmed_subscriber, publish_newsletter};
+use zero2prod::startup::WorkerApplication;
+
+// Metrics are shared by every test of the process: counters are compared with their value
+// before the test, and may have been bumped by other tests in the meantime.
+
+/// The value of the sample of `name` with all of `labels`, if it was recorded.
+fn sample(metrics: &str, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+    metrics
+        .lines()
+        .filter(|line| line.starts_with(&format!("{name}{{")))
+        .find(|line| {
+            labels
+                .iter()
+                .all(|(key, value)| line.contains(&format!(r#"{key}="{value}""#)))
+        })
+        .and_then(|line| line.rsplit(' ').next())
+        .map(|value| value.parse().unwrap())
+}
+
+#[tokio::test]
+async fn http_requests_are_counted_and_timed_per_route_and_status() {
+    // Arrange
+    let app = spawn_app().await;
+    let labels = [
+        ("method", "GET"),
+        ("route", "/admin/subscribers/{subscriber_id}"),
+        ("status", "303"),
+    ];
+    let before = sample(&app.get_metrics().await, "http_requests_total", &labels);
+
+    // Act - Anonymous users are redirected to the login page
+    app.api_client
+        .get(format!(
+            "{}/admin/subscribers/{}",
+            &app.address,
+            uuid::Uuid::new_v4()
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    let metrics = app.get_metrics().await;
+    let after = sample(&metrics, "http_requests_total", &labels).unwrap();
+    assert!(after >= before.unwrap_or(0.0) + 1.0);
+    let latency = sample(
+        &metrics,
+        "http_request_duration_seconds_bucket",
+        &[labels.as_slice(), &[("le", "+Inf")]].concat(),
+    );
+    assert!(latency.unwrap() >= 1.0);
+}
+
+#[tokio::test]
+async fn login_outcomes_are_counted() {
+    // Arrange
+    let app = spawn_app().await;
+    let metrics = app.get_metrics().await;
+    let failed_before = sample(
+        &metrics,
+        "login_attempts_total",
+        &[("outcome", "invalid_credentials")],
+    );
+    let succeeded_before = sample(&metrics, "login_attempts_total", &[("outcome", "success")]);
+
+    // Act
+    app.post_login(&serde_json::json!({
+        "username": "random-username",
+        "password": "random-password"
+    }))
+    .await;
+    app.test_user.login(&app).await;
+
+    // Assert
+    let metrics = app.get_metrics().await;
+    let failed_after = sample(
+        &metrics,
+        "login_attempts_total",
+        &[("outcome", "invalid_credentials")],
+    );
+    let succeeded_after = sample(&metrics, "login_attempts_total", &[("outcome", "success")]);
+    assert!(failed_after.unwrap() >= failed_before.unwrap_or(0.0) + 1.0);
+    assert!(succeeded_after.unwrap() >= succeeded_before.unwrap_or(0.0) + 1.0);
+}
+
+#[tokio::test]
+async fn emails_accepted_by_the_provider_are_counted() {
+    // Arrange
+    let app = spawn_app().await;
+    let labels = [("provider", "primary")];
+    let before = sample(&app.get_metrics().await, "emails_sent_total", &labels);
+
+    // Act - A confirmation email is sent
+    create_confirmed_subscriber(&app).await;
+
+    // Assert
+    let after = sample(&app.get_metrics().await, "emails_sent_total", &labels);
+    assert!(after.unwrap() >= before.unwrap_or(0.0) + 1.0);
+}
+
+#[tokio::test]
+async fn queues_and_connection_pools_are_sampled_on_every_scrape() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    publish_newsletter(&app).await;
+
+    // Act
+    let metrics = app.get_metrics().await;
+
+    // Assert - Values are overwritten by the scrapes of concurrent tests
+    for message_class in ["scheduled", "bulk"] {
+        let labels = [("message_class", message_class)];
+        assert!(sample(&metrics, "issue_delivery_queue_depth", &labels).is_some());
+        assert!(sample(
+            &metrics,
+            "issue_delivery_queue_oldest_task_age_seconds",
+            &labels
+        )
+        .is_some());
+    }
+    assert!(sample(
+        &metrics,
+        "db_pool_connections",
+        &[("pool", "http"), ("state", "in_use")]
+    )
+    .is_some());
+    assert!(sample(&metrics, "db_pool_max_connections", &[("pool", "http")]).is_some());
+}
+
+#[tokio::test]
+async fn metrics_are_not_served_on_the_public_port() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .api_client
+        .get(format!("{}/metrics", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn standalone_workers_expose_metrics_too() {
+    // Arrange
+    let app = spawn_app().await;
+    let worker = WorkerApplication::build(&app.worker_configuration)
+        .expect("Failed to build the worker health check");
+    let address = format!("http://127.0.0.1:{}", worker.port());
+    tokio::spawn(worker.run_until_stopped());
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/metrics", address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert!(response.status().is_success());
+    let metrics = response.text().await.unwrap();
+    assert!(sample(
+        &metrics,
+        "issue_delivery_queue_depth",
+        &[("message_class", "bulk")]
+    )
+    .is_some());
+}
@@ -1,11 +1,16 @@
-use crate::helpers::{spawn_app, ConfirmationLinks, TestApp};
+use crate::helpers::{accept_batch, spawn_app, spawn_app_with, ConfirmationLinks, TestApp};
 use crate::login::assert_is_redirect_to;
+use crate::onboarding::add_onboarding_step;
 use fake::faker::internet::en::SafeEmail;
 use fake::faker::name::en::Name;
 use fake::Fake;
-use std::time::Duration;
 use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, MockBuilder, ResponseTemplate};
+use zero2prod::configuration::{
+    DomainRateSettings, LaneSettings, SandboxMode, SandboxSettings, SendRateSettings,
+};
+use zero2prod::issue_delivery_worker::try_execute_batch;
+use zero2prod::jobs::schedule_recurring_jobs;
 
 #[tokio::test]
 async fn newsletters_are_not_delivered_to_unconfirmed_subscribers() {
@@ -76,8 +81,8 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
     create_confirmed_subscriber(&app).await;
     app.test_user.login(&app).await;
 
-    when_sending_an_email()
-        .respond_with(ResponseTemplate::new(200))
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
         .expect(1)
         .mount(&app.email_server)
         .await;
@@ -102,6 +107,600 @@ async fn newsletters_are_delivered_to_confirmed_subscribers() {
     // Mock verifies on Drop that we have sent the newsletter email
 }
 
+pub async fn publish_newsletter(app: &TestApp) {
+    let response = app
+        .post_publish_newsletter(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "idempotency_key": uuid::Uuid::new_v4().to_string(),
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/newsletters");
+    app.run_pending_jobs().await;
+}
+
+#[tokio::test]
+async fn transient_delivery_failures_are_retried_later() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(ResponseTemplate::new(503))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let task = sqlx::query!(
+        r#"SELECT n_retries, execute_after > now() AS "delayed!" FROM issue_delivery_queue"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("The delivery should still be queued");
+    assert_eq!(task.n_retries, 1);
+    assert!(task.delayed);
+}
+
+#[tokio::test]
+async fn deliveries_are_deferred_while_the_email_provider_is_unavailable() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut email_client_settings = app.worker_configuration.email_client.clone();
+    email_client_settings.circuit_breaker.failure_threshold = 1;
+    let email_client = email_client_settings.client();
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(ResponseTemplate::new(503))
+        // The provider is not called again while its circuit is open
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+    try_execute_batch(&app.db_pool, &email_client, &app.delivery_worker)
+        .await
+        .unwrap();
+
+    // Act
+    sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE send_intents SET attempted_at = now() - interval '1 hour'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    try_execute_batch(&app.db_pool, &email_client, &app.delivery_worker)
+        .await
+        .unwrap();
+
+    // Assert
+    let task = sqlx::query!(
+        r#"SELECT n_retries, execute_after > now() AS "deferred!" FROM issue_delivery_queue"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("The delivery should still be queued");
+    assert_eq!(task.n_retries, 1);
+    assert!(task.deferred);
+}
+
+#[tokio::test]
+async fn sent_emails_are_recorded_in_the_delivery_log() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "ErrorCode": 0,
+                "Message": "OK",
+                "MessageID": "b7bc2f4a-e38e-4336-af7d-e6c392c2f817"
+            }])),
+        )
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let entry = sqlx::query!(
+        r#"
+        SELECT newsletter_issue_id, attempt, outcome, message_id, error_code, error_message
+        FROM delivery_log
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("The delivery should be logged");
+    assert!(entry.newsletter_issue_id.is_some());
+    assert_eq!(entry.attempt, 1);
+    assert_eq!(entry.outcome, "sent");
+    assert_eq!(
+        entry.message_id.as_deref(),
+        Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+    );
+    assert_eq!(entry.error_code, None);
+    assert_eq!(entry.error_message, None);
+}
+
+#[tokio::test]
+async fn failed_delivery_attempts_are_recorded_in_the_delivery_log() {
+    // Arrange
+    let mut app = spawn_app().await;
+    // A single empty search is enough to conclude the provider never received the email
+    app.delivery_worker.send_intents.search_attempts = 1;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    when_delivering_a_batch()
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "ErrorCode": 406,
+                "Message": "You tried to send to recipient(s) that have been marked as inactive."
+            }])),
+        )
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act - Part 1 - The provider is unavailable
+    app.dispatch_all_pending_emails().await;
+    // Act - Part 2 - The retry is due, the provider rejects the recipient
+    sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    // The email may have gone through despite the 503: the provider is asked first.
+    sqlx::query!("UPDATE send_intents SET attempted_at = now() - interval '1 hour'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    when_searching_sent_emails()
+        .respond_with(no_sent_emails())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let entries = sqlx::query!(
+        r#"
+        SELECT attempt, outcome, message_id, error_code, error_message
+        FROM delivery_log
+        ORDER BY attempt
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].attempt, 1);
+    assert_eq!(entries[0].outcome, "transient_failure");
+    assert!(entries[0].error_message.is_some());
+    assert_eq!(entries[1].attempt, 2);
+    assert_eq!(entries[1].outcome, "permanent_failure");
+    assert_eq!(entries[1].error_code, Some(406));
+    assert_eq!(entries[1].message_id, None);
+}
+
+#[tokio::test]
+async fn rejected_recipients_are_suppressed() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(serde_json::json!([{
+                "ErrorCode": 406,
+                "Message": "You tried to send to recipient(s) that have been marked as inactive."
+            }])),
+        )
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act - Part 1 - The provider rejects the recipient
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - Part 1
+    let queued = sqlx::query!(r#"SELECT count(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+    let subscriber = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscriber.status, "suppressed");
+
+    // Act - Part 2 - They are left out of the next issue
+    publish_newsletter(&app).await;
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that we did not email them again
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_in_batches() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.delivery_worker.batch_size = 2;
+    app.delivery_worker.concurrency = 2;
+    for _ in 0..5 {
+        create_confirmed_subscriber(&app).await;
+    }
+    app.test_user.login(&app).await;
+
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(5)
+        .mount(&app.email_server)
+        .await;
+    let newsletter_request_body = serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+        "idempotency_key": uuid::Uuid::new_v4().to_string(),
+    });
+    app.post_publish_newsletter(&newsletter_request_body).await;
+    app.run_pending_jobs().await;
+
+    // Act - Part 1 - A single batch
+    try_execute_batch(&app.db_pool, &app.email_client, &app.delivery_worker)
+        .await
+        .unwrap();
+
+    // Assert - Part 1
+    let remaining = sqlx::query!(r#"SELECT count(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(remaining.count, 3);
+
+    // Act - Part 2 - Drain the queue
+    app.dispatch_all_pending_emails().await;
+    // Mock verifies on Drop that every subscriber got the newsletter exactly once
+}
+
+#[tokio::test]
+async fn deliveries_over_the_send_rate_limit_are_deferred() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.delivery_worker.rate_limit = SendRateSettings {
+        messages_per_second: Some(2),
+        ..Default::default()
+    };
+    for _ in 0..5 {
+        create_confirmed_subscriber(&app).await;
+    }
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let sent = sqlx::query!(r#"SELECT count(*) AS "count!" FROM delivery_log"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(sent.count, 2);
+    let deferred = sqlx::query!(
+        r#"SELECT n_retries, execute_after > now() AS "deferred!" FROM issue_delivery_queue"#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(deferred.len(), 3);
+    assert!(deferred.iter().all(|task| task.deferred));
+    // Being throttled is not a failure
+    assert!(deferred.iter().all(|task| task.n_retries == 0));
+}
+
+#[tokio::test]
+async fn deliveries_over_a_domain_send_rate_limit_are_deferred() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.delivery_worker.rate_limit = SendRateSettings {
+        domains: vec![DomainRateSettings {
+            domain: "gmail.com".into(),
+            messages_per_second: 1,
+        }],
+        ..Default::default()
+    };
+    create_confirmed_subscriber_with_email(&app, "ursula@gmail.com").await;
+    create_confirmed_subscriber_with_email(&app, "le_guin@gmail.com").await;
+    create_confirmed_subscriber_with_email(&app, "ursula@example.com").await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let sent = sqlx::query!("SELECT subscriber_email FROM delivery_log")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(sent.len(), 2);
+    assert!(sent
+        .iter()
+        .any(|entry| entry.subscriber_email == "ursula@example.com"));
+    let deferred = sqlx::query!(
+        r#"
+        SELECT email
+        FROM issue_delivery_queue
+        JOIN subscriptions ON subscriptions.id = issue_delivery_queue.subscriber_id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("One gmail.com delivery should be deferred");
+    assert!(deferred.email.ends_with("@gmail.com"));
+}
+
+#[tokio::test]
+async fn refilled_send_rate_buckets_are_purged() {
+    // Arrange
+    let app = spawn_app().await;
+    sqlx::query!(
+        r#"
+        INSERT INTO send_rate_limits (bucket, tokens, refilled_at)
+        VALUES ('idle.com', 20, now() - interval '1 minute'), ('busy.com', 0, now())
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    schedule_recurring_jobs(&app.db_pool).await.unwrap();
+
+    // Act
+    app.run_pending_jobs().await;
+
+    // Assert
+    let buckets = sqlx::query!("SELECT bucket FROM send_rate_limits")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(buckets.len(), 1);
+    assert_eq!(buckets[0].bucket, "busy.com");
+}
+
+#[tokio::test]
+async fn newsletters_are_delivered_to_the_current_address_of_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act - The subscriber changes their address before the worker gets to them
+    sqlx::query!("UPDATE subscriptions SET email = 'ursula@example.com'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let requests = app.email_server.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    assert_eq!(body[0]["To"], "ursula@example.com");
+}
+
+#[tokio::test]
+async fn subscribers_who_are_no_longer_eligible_are_skipped_at_send_time() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber_with_email(&app, "ursula@example.com").await;
+    create_confirmed_subscriber_with_email(&app, "le_guin@example.com").await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act - One gets suppressed, the other is no longer confirmed
+    sqlx::query!(
+        r#"
+        INSERT INTO suppression_list (email, reason, suppressed_at)
+        VALUES ('ursula@example.com', 'manual_suppression', now())
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    sqlx::query!("UPDATE subscriptions SET status = 'pending' WHERE email = 'le_guin@example.com'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let queued = sqlx::query!(r#"SELECT count(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+}
+
+// The subjects of the emails sent by a single run of the delivery worker
+async fn execute_one_batch(app: &TestApp) -> Vec<String> {
+    let n_requests = app.email_server.received_requests().await.unwrap().len();
+    try_execute_batch(&app.db_pool, &app.email_client, &app.delivery_worker)
+        .await
+        .unwrap();
+    app.email_server.received_requests().await.unwrap()[n_requests..]
+        .iter()
+        .flat_map(|request| {
+            let body: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+            body.into_iter()
+                .map(|email| email["Subject"].as_str().unwrap().to_owned())
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn scheduled_emails_are_not_held_up_by_bulk_sends() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.delivery_worker.batch_size = 2;
+    for _ in 0..3 {
+        create_confirmed_subscriber(&app).await;
+    }
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+    // Enqueued after the newsletter issue
+    add_onboarding_step(&app, "Welcome aboard", 0).await;
+    create_confirmed_subscriber(&app).await;
+
+    // Act
+    let subjects = execute_one_batch(&app).await;
+
+    // Assert
+    assert_eq!(subjects.len(), 2);
+    assert!(subjects.contains(&"Welcome aboard".to_string()));
+}
+
+#[tokio::test]
+async fn bulk_sends_get_their_share_of_every_batch() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.delivery_worker.batch_size = 5;
+    app.delivery_worker.lanes = LaneSettings {
+        scheduled: 80,
+        bulk: 20,
+    };
+    app.test_user.login(&app).await;
+    add_onboarding_step(&app, "Welcome aboard", 0).await;
+    for _ in 0..6 {
+        create_confirmed_subscriber(&app).await;
+    }
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    let subjects = execute_one_batch(&app).await;
+
+    // Assert
+    assert_eq!(subjects.len(), 5);
+    let n_bulk = subjects
+        .iter()
+        .filter(|subject| *subject == "Newsletter title")
+        .count();
+    assert_eq!(n_bulk, 1);
+}
+
+#[tokio::test]
+async fn every_class_gets_its_share_of_the_concurrent_requests() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.delivery_worker.concurrency = 2;
+    app.delivery_worker.lanes = LaneSettings {
+        scheduled: 50,
+        bulk: 50,
+    };
+    app.test_user.login(&app).await;
+    for _ in 0..4 {
+        create_confirmed_subscriber(&app).await;
+    }
+    // Only the last subscriber gets the onboarding step
+    add_onboarding_step(&app, "Welcome aboard", 0).await;
+    create_confirmed_subscriber(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(2)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - One request per class: the newsletter issue did not get both
+    let requests = app.email_server.received_requests().await.unwrap();
+    let mut batch_sizes: Vec<_> = requests
+        .iter()
+        .filter(|request| request.url.path() == "/email/batch")
+        .map(|request| {
+            serde_json::from_slice::<Vec<serde_json::Value>>(&request.body)
+                .unwrap()
+                .len()
+        })
+        .collect();
+    batch_sizes.sort();
+    assert_eq!(batch_sizes, vec![1, 5]);
+}
+
+#[tokio::test]
+async fn deliveries_of_a_batch_share_a_single_request() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.delivery_worker.concurrency = 1;
+    for _ in 0..3 {
+        create_confirmed_subscriber(&app).await;
+    }
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let requests = app.email_server.received_requests().await.unwrap();
+    let batch: Vec<serde_json::Value> =
+        serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    assert_eq!(batch.len(), 3);
+    assert!(batch
+        .iter()
+        .all(|email| email["Subject"] == "Newsletter title"));
+}
+
 #[tokio::test]
 async fn newsletter_creation_is_idempotent() {
     //Arrange
@@ -109,8 +708,8 @@ async fn newsletter_creation_is_idempotent() {
     create_confirmed_subscriber(&app).await;
     app.test_user.login(&app).await;
 
-    when_sending_an_email()
-        .respond_with(ResponseTemplate::new(200))
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
         .expect(1)
         .mount(&app.email_server)
         .await;
@@ -135,6 +734,7 @@ async fn newsletter_creation_is_idempotent() {
 
     // Act - Part 3 - Submit newsletter form **again**
     let response = app.post_publish_newsletter(&newsletter_request_body).await;
+    // The saved response is replayed
     assert_is_redirect_to(&response, "/admin/newsletters");
 
     // Act - Part 4 - Follow the redirect
@@ -154,10 +754,8 @@ async fn concurrent_form_submission_is_handled_gracefully() {
     create_confirmed_subscriber(&app).await;
     app.test_user.login(&app).await;
 
-    when_sending_an_email()
-        // Setting a long delay to ensure that the second request
-        // arrives before the first one completes
-        .respond_with(ResponseTemplate::new(200).set_delay(Duration::from_secs(2)))
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
         .expect(1)
         .mount(&app.email_server)
         .await;
@@ -174,11 +772,11 @@ async fn concurrent_form_submission_is_handled_gracefully() {
     let response2 = app.post_publish_newsletter(&newsletter_request_body);
     let (response1, response2) = tokio::join!(response1, response2);
 
-    assert_eq!(response1.status(), response2.status());
-    assert_eq!(
-        response1.text().await.unwrap(),
-        response2.text().await.unwrap()
-    );
+    // One of them is processed. Depending on timing, the other one gets the saved response
+    // or is told that the first one is still being processed.
+    let mut statuses = [response1.status().as_u16(), response2.status().as_u16()];
+    statuses.sort();
+    assert!(statuses == [303, 303] || statuses == [303, 409]);
 
     app.dispatch_all_pending_emails().await;
     // Mock verifies on Drop that we have sent the newsletter email **once**
@@ -186,12 +784,19 @@ async fn concurrent_form_submission_is_handled_gracefully() {
 
 /// Use the public API of the application under test to create
 /// an unconfirmed subscriber.
-async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
+pub async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
     // We are working with multiple subscribers now,
     // their details must be randomised to avoid conflicts!
-    let name: String = Name().fake();
     let email: String = SafeEmail().fake();
-    let body = serde_urlencoded::to_string(&serde_json::json!({
+    create_unconfirmed_subscriber_with_email(app, &email).await
+}
+
+pub async fn create_unconfirmed_subscriber_with_email(
+    app: &TestApp,
+    email: &str,
+) -> ConfirmationLinks {
+    let name: String = Name().fake();
+    let body = serde_urlencoded::to_string(serde_json::json!({
         "name": name,
         "email": email
     }))
@@ -206,18 +811,18 @@ async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
     // the underlying MockServer to stop honouring the specified mock behaviour. In other words,
     // we stop returning 200 to POST /email at the end of create_unconfirmed_subscriber.
     // The mock behaviour needed for our test helper stays local to the test helper itself.
-    let _mock_guard = Mock::given(path("/email"))
-        .and(method("POST"))
+    let _mock_guard = when_sending_an_email()
         .respond_with(ResponseTemplate::new(200))
         .named("Create unconfirmed subscriber")
         .expect(1)
         .mount_as_scoped(&app.email_server)
         .await;
 
-    app.post_subscriptions(body.into())
+    app.post_subscriptions(body)
         .await
         .error_for_status()
         .unwrap();
+    app.dispatch_all_outbox_emails().await;
 
     // Inspect the request to get confirmation links received from the mock
     let email_request = &app
@@ -231,8 +836,17 @@ async fn create_unconfirmed_subscriber(app: &TestApp) -> ConfirmationLinks {
     app.get_confirmation_links(email_request)
 }
 
-async fn create_confirmed_subscriber(app: &TestApp) {
+pub async fn create_confirmed_subscriber(app: &TestApp) {
     let confirmation_link = create_unconfirmed_subscriber(app).await;
+    confirm_subscriber(confirmation_link).await;
+}
+
+pub async fn create_confirmed_subscriber_with_email(app: &TestApp, email: &str) {
+    let confirmation_link = create_unconfirmed_subscriber_with_email(app, email).await;
+    confirm_subscriber(confirmation_link).await;
+}
+
+async fn confirm_subscriber(confirmation_link: ConfirmationLinks) {
     reqwest::get(confirmation_link.html)
         .await
         .unwrap()
@@ -240,6 +854,74 @@ async fn create_confirmed_subscriber(app: &TestApp) {
         .unwrap();
 }
 
-fn when_sending_an_email() -> MockBuilder {
+pub fn when_sending_an_email() -> MockBuilder {
     Mock::given(path("/email")).and(method("POST"))
 }
+
+// Newsletter issues and onboarding steps go out through the batch API.
+pub fn when_delivering_a_batch() -> MockBuilder {
+    Mock::given(path("/email/batch")).and(method("POST"))
+}
+
+pub fn when_searching_sent_emails() -> MockBuilder {
+    Mock::given(path("/messages/outbound")).and(method("GET"))
+}
+
+pub fn no_sent_emails() -> ResponseTemplate {
+    ResponseTemplate::new(200).set_body_json(serde_json::json!({"TotalCount": 0, "Messages": []}))
+}
+
+#[tokio::test]
+async fn newsletters_are_redirected_to_the_catch_all_address_in_sandbox_mode() {
+    // Arrange
+    let app = spawn_app_with(|c| {
+        c.email_client.sandbox = Some(SandboxSettings {
+            mode: SandboxMode::Redirect,
+            allowlist: vec!["le_guin@example.com".into()],
+            catch_all: Some("catch-all@example.com".into()),
+        })
+    })
+    .await;
+    create_confirmed_subscriber_with_email(&app, "ursula@example.com").await;
+    create_confirmed_subscriber_with_email(&app, "le_guin@example.com").await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(1..)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    // The deliveries may be spread over several batch requests.
+    let mut recipients = vec![];
+    for request in app.email_server.received_requests().await.unwrap() {
+        if request.url.path() != "/email/batch" {
+            continue;
+        }
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        for email in body.as_array().unwrap() {
+            recipients.push((
+                email["To"].as_str().unwrap().to_owned(),
+                email["Subject"].as_str().unwrap().to_owned(),
+            ));
+        }
+    }
+    recipients.sort();
+    assert_eq!(
+        recipients,
+        vec![
+            (
+                "catch-all@example.com".to_owned(),
+                "[Sandbox: to ursula@example.com] Newsletter title".to_owned()
+            ),
+            (
+                "le_guin@example.com".to_owned(),
+                "Newsletter title".to_owned()
+            ),
+        ]
+    );
+}
@@ -0,0 +1,172 @@
+use crate::helpers::{accept_batch, spawn_app, TestApp};
+use crate::login::assert_is_redirect_to;
+use crate::newsletter::{
+    create_confirmed_subscriber, create_unconfirmed_subscriber, when_delivering_a_batch,
+};
+use wiremock::matchers::body_partial_json;
+
+pub async fn add_onboarding_step(app: &TestApp, title: &str, delay_hours: u16) {
+    let response = app
+        .post_onboarding_step(&serde_json::json!({
+            "title": title,
+            "text_content": "Onboarding body as plain text",
+            "html_content": "<p>Onboarding body as HTML</p>",
+            "delay_hours": delay_hours,
+        }))
+        .await;
+    assert_is_redirect_to(&response, "/admin/onboarding");
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_see_the_onboarding_sequence() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.get_onboarding().await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_add_an_onboarding_step() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_onboarding_step(&serde_json::json!({
+            "title": "Welcome",
+            "text_content": "Onboarding body as plain text",
+            "html_content": "<p>Onboarding body as HTML</p>",
+            "delay_hours": 0,
+        }))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[tokio::test]
+async fn added_onboarding_steps_are_listed() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // Act
+    add_onboarding_step(&app, "Welcome aboard", 0).await;
+
+    // Assert
+    let html_page = app.get_onboarding_html().await;
+    assert!(html_page.contains("<p><i>The onboarding step has been added.</i></p>"));
+    assert!(html_page.contains("0 hour(s) after confirmation: Welcome aboard"));
+}
+
+#[tokio::test]
+async fn a_welcome_email_is_sent_after_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_onboarding_step(&app, "Welcome aboard", 0).await;
+    create_confirmed_subscriber(&app).await;
+
+    when_delivering_a_batch()
+        .and(body_partial_json(
+            serde_json::json!([{"Subject": "Welcome aboard"}]),
+        ))
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Mock verifies on Drop that the welcome email has been sent
+}
+
+#[tokio::test]
+async fn follow_ups_are_not_sent_before_their_delay() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_onboarding_step(&app, "How is it going?", 24).await;
+    create_confirmed_subscriber(&app).await;
+
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let scheduled = sqlx::query!(
+        "SELECT execute_after > now() + interval '23 hours' AS \"in_the_future!\" \
+        FROM issue_delivery_queue"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the scheduled follow-up");
+    assert!(scheduled.in_the_future);
+}
+
+#[tokio::test]
+async fn onboarding_stops_when_the_subscriber_is_no_longer_confirmed() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_onboarding_step(&app, "How is it going?", 24).await;
+    create_confirmed_subscriber(&app).await;
+
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // The subscriber leaves, then the follow-up comes due
+    sqlx::query!("UPDATE subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Mock verifies on Drop that the follow-up has not been sent
+}
+
+#[tokio::test]
+async fn confirming_twice_does_not_restart_the_onboarding_sequence() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_onboarding_step(&app, "Welcome aboard", 0).await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    for _ in 0..2 {
+        reqwest::get(confirmation_links.html.clone())
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+    app.dispatch_all_pending_emails().await;
+
+    // Mock verifies on Drop that the welcome email has been sent **once**
+}
@@ -0,0 +1,82 @@
+use crate::helpers::{accept_batch, spawn_app};
+use crate::newsletter::{create_confirmed_subscriber, when_delivering_a_batch};
+use std::time::Duration;
+use zero2prod::queue_listener::{
+    QueueListener, DELIVERY_QUEUE_CHANNEL, EMAIL_OUTBOX_CHANNEL, JOBS_CHANNEL,
+};
+
+// Far longer than any of the waits below: returning early means we were notified.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[tokio::test]
+async fn subscribing_wakes_up_the_outbox_dispatcher() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut listener = QueueListener::new(&app.db_pool, EMAIL_OUTBOX_CHANNEL, POLL_INTERVAL).await;
+
+    // Act
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    tokio::time::timeout(Duration::from_secs(5), listener.wait())
+        .await
+        .expect("The outbox dispatcher was not notified");
+}
+
+#[tokio::test]
+async fn publishing_a_newsletter_wakes_up_the_job_runner_then_the_delivery_worker() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .mount(&app.email_server)
+        .await;
+    let mut job_listener = QueueListener::new(&app.db_pool, JOBS_CHANNEL, POLL_INTERVAL).await;
+    let mut delivery_listener =
+        QueueListener::new(&app.db_pool, DELIVERY_QUEUE_CHANNEL, POLL_INTERVAL).await;
+
+    // Act - Part 1 - Publish
+    app.post_publish_newsletter(&serde_json::json!({
+        "title": "Newsletter title",
+        "text_content": "Newsletter body as plain text",
+        "html_content": "<p>Newsletter body as HTML</p>",
+        "idempotency_key": uuid::Uuid::new_v4().to_string(),
+    }))
+    .await;
+
+    // Assert - Part 1
+    tokio::time::timeout(Duration::from_secs(5), job_listener.wait())
+        .await
+        .expect("The job runner was not notified");
+
+    // Act - Part 2 - Fan the issue out
+    app.run_pending_jobs().await;
+
+    // Assert - Part 2
+    tokio::time::timeout(Duration::from_secs(5), delivery_listener.wait())
+        .await
+        .expect("The delivery worker was not notified");
+}
+
+#[tokio::test]
+async fn an_idle_worker_still_polls_its_queue() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut listener = QueueListener::new(
+        &app.db_pool,
+        DELIVERY_QUEUE_CHANNEL,
+        Duration::from_millis(100),
+    )
+    .await;
+
+    // Act - no producer is running
+    let outcome = tokio::time::timeout(Duration::from_secs(5), listener.wait()).await;
+
+    // Assert
+    assert!(outcome.is_ok(), "The worker did not wake up to poll");
+}
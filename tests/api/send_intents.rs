@@ -0,0 +1,288 @@
+use crate::helpers::{accept_batch, spawn_app, TestApp};
+use crate::newsletter::{
+    create_confirmed_subscriber, no_sent_emails, publish_newsletter, when_delivering_a_batch,
+    when_searching_sent_emails,
+};
+use wiremock::matchers::query_param;
+use wiremock::ResponseTemplate;
+use zero2prod::configuration::SendIntentSettings;
+
+// A newsletter issue waiting to be delivered to a single subscriber
+async fn queued_delivery(app: &TestApp) -> i64 {
+    create_confirmed_subscriber(app).await;
+    app.test_user.login(app).await;
+    publish_newsletter(app).await;
+    sqlx::query!("SELECT task_id FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .task_id
+}
+
+// What a worker leaves behind if it crashes after handing the email to the provider
+async fn insert_send_intent(
+    app: &TestApp,
+    task_id: i64,
+    minutes_ago: i32,
+    message_id: Option<&str>,
+) -> uuid::Uuid {
+    let delivery_key = uuid::Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO send_intents (task_id, delivery_key, attempted_at, accepted_at, message_id)
+        VALUES (
+            $1,
+            $2,
+            now() - make_interval(mins => $3),
+            CASE WHEN $4::text IS NULL THEN NULL ELSE now() END,
+            $4
+        )
+        "#,
+        task_id,
+        delivery_key,
+        minutes_ago,
+        message_id,
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+    delivery_key
+}
+
+async fn logged_message_id(app: &TestApp) -> Option<String> {
+    sqlx::query!("SELECT outcome, message_id FROM delivery_log")
+        .fetch_one(&app.db_pool)
+        .await
+        .map(|entry| {
+            assert_eq!(entry.outcome, "sent");
+            entry.message_id
+        })
+        .expect("The delivery should be logged")
+}
+
+// Whether the task was deferred, and how many times it was retried
+async fn queued_task(app: &TestApp) -> (bool, i16) {
+    let task = sqlx::query!(
+        r#"SELECT n_retries, execute_after > now() AS "deferred!" FROM issue_delivery_queue"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("The task should still be queued");
+    (task.deferred, task.n_retries)
+}
+
+async fn n_queued_tasks(app: &TestApp) -> i64 {
+    sqlx::query!(r#"SELECT count(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .count
+}
+
+#[tokio::test]
+async fn emails_are_sent_with_a_delivery_key() {
+    // Arrange
+    let app = spawn_app().await;
+    queued_delivery(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let requests = app.email_server.received_requests().await.unwrap();
+    let batch = requests.last().unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&batch.body).unwrap();
+    let delivery_key = body[0]["Metadata"]["delivery_key"].as_str().unwrap();
+    assert!(uuid::Uuid::parse_str(delivery_key).is_ok());
+    // The task is done: its intent went with it
+    let intents = sqlx::query!("SELECT task_id FROM send_intents")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(intents.is_empty());
+}
+
+#[tokio::test]
+async fn emails_accepted_before_a_crash_are_not_sent_again() {
+    // Arrange
+    let app = spawn_app().await;
+    let task_id = queued_delivery(&app).await;
+    insert_send_intent(
+        &app,
+        task_id,
+        1,
+        Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817"),
+    )
+    .await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    when_searching_sent_emails()
+        .respond_with(no_sent_emails())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(n_queued_tasks(&app).await, 0);
+    assert_eq!(
+        logged_message_id(&app).await.as_deref(),
+        Some("b7bc2f4a-e38e-4336-af7d-e6c392c2f817")
+    );
+}
+
+#[tokio::test]
+async fn the_provider_is_asked_about_emails_of_unknown_fate_before_sending_them_again() {
+    // Arrange
+    let app = spawn_app().await;
+    let task_id = queued_delivery(&app).await;
+    let delivery_key = insert_send_intent(&app, task_id, 10, None).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    when_searching_sent_emails()
+        .and(query_param(
+            "metadata_delivery_key",
+            delivery_key.to_string(),
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+            "TotalCount": 1,
+            "Messages": [{"MessageID": "0a129aee-e1cd-480d-b08d-4f48548ff48d"}]
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(n_queued_tasks(&app).await, 0);
+    assert_eq!(
+        logged_message_id(&app).await.as_deref(),
+        Some("0a129aee-e1cd-480d-b08d-4f48548ff48d")
+    );
+}
+
+#[tokio::test]
+async fn emails_the_provider_never_received_are_sent_again() {
+    // Arrange
+    let mut app = spawn_app().await;
+    app.delivery_worker.send_intents = SendIntentSettings {
+        search_delay_seconds: 0,
+        search_attempts: 3,
+    };
+    let task_id = queued_delivery(&app).await;
+    let delivery_key = insert_send_intent(&app, task_id, 10, None).await;
+    when_searching_sent_emails()
+        .respond_with(no_sent_emails())
+        .expect(3)
+        .mount(&app.email_server)
+        .await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - with the same delivery key
+    let requests = app.email_server.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    assert_eq!(
+        body[0]["Metadata"]["delivery_key"],
+        delivery_key.to_string()
+    );
+    assert_eq!(n_queued_tasks(&app).await, 0);
+}
+
+#[tokio::test]
+async fn emails_of_unknown_fate_wait_until_the_provider_can_be_asked() {
+    // Arrange
+    let app = spawn_app().await;
+    let task_id = queued_delivery(&app).await;
+    insert_send_intent(&app, task_id, 0, None).await;
+    when_searching_sent_emails()
+        .respond_with(no_sent_emails())
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(queued_task(&app).await, (true, 0));
+}
+
+#[tokio::test]
+async fn the_provider_is_searched_again_before_concluding_it_never_received_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let task_id = queued_delivery(&app).await;
+    insert_send_intent(&app, task_id, 10, None).await;
+    when_searching_sent_emails()
+        .respond_with(no_sent_emails())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - the next search is due later
+    assert_eq!(queued_task(&app).await, (true, 0));
+    let intent = sqlx::query!("SELECT n_searches FROM send_intents")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(intent.n_searches, 1);
+}
+
+#[tokio::test]
+async fn emails_of_unknown_fate_are_deferred_if_the_provider_cannot_be_searched() {
+    // Arrange
+    let app = spawn_app().await;
+    let task_id = queued_delivery(&app).await;
+    insert_send_intent(&app, task_id, 10, None).await;
+    when_searching_sent_emails()
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - nothing was sent, it is not a failed delivery
+    assert_eq!(queued_task(&app).await, (true, 0));
+}
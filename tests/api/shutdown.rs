@@ -0,0 +1,84 @@
+use crate::helpers::{accept_batch, spawn_app};
+use crate::newsletter::{create_confirmed_subscriber, publish_newsletter, when_delivering_a_batch};
+use std::time::Duration;
+use zero2prod::email_outbox::run_dispatcher_until_stopped;
+use zero2prod::issue_delivery_worker::run_worker_until_stopped;
+use zero2prod::shutdown::shutdown_channel;
+
+#[tokio::test]
+async fn idle_workers_stop_as_soon_as_the_shutdown_is_triggered() {
+    // Arrange
+    let app = spawn_app().await;
+    let (trigger, shutdown) = shutdown_channel();
+    let worker = tokio::spawn(run_worker_until_stopped(
+        app.worker_configuration.clone(),
+        shutdown.clone(),
+    ));
+    let dispatcher = tokio::spawn(run_dispatcher_until_stopped(
+        app.worker_configuration.clone(),
+        shutdown,
+    ));
+    // Let them find out that there is nothing to do
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    // Act
+    trigger.trigger();
+
+    // Assert - both wait for new tasks for far longer than this
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .expect("The delivery worker did not stop")
+        .unwrap()
+        .unwrap();
+    tokio::time::timeout(Duration::from_secs(5), dispatcher)
+        .await
+        .expect("The outbox dispatcher did not stop")
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn the_batch_in_flight_is_completed_before_the_worker_stops() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(|request: &wiremock::Request| {
+            accept_batch(request).set_delay(Duration::from_millis(500))
+        })
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    publish_newsletter(&app).await;
+    // The confirmation email went out earlier
+    let n_requests = app.email_server.received_requests().await.unwrap().len();
+    let (trigger, shutdown) = shutdown_channel();
+    let worker = tokio::spawn(run_worker_until_stopped(
+        app.worker_configuration.clone(),
+        shutdown,
+    ));
+
+    // Act - shut down while Postmark is still processing the batch
+    while app.email_server.received_requests().await.unwrap().len() == n_requests {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    trigger.trigger();
+
+    // Assert
+    tokio::time::timeout(Duration::from_secs(5), worker)
+        .await
+        .expect("The delivery worker did not stop")
+        .unwrap()
+        .unwrap();
+    let queued = sqlx::query!(r#"SELECT count(*) AS "count!" FROM issue_delivery_queue"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(queued.count, 0);
+    let sent = sqlx::query!(r#"SELECT count(*) AS "count!" FROM delivery_log"#)
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(sent.count, 1);
+}
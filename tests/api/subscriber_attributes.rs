@@ -0,0 +1,287 @@
+use crate::helpers::{spawn_app, TestApp};
+use crate::login::assert_is_redirect_to;
+use uuid::Uuid;
+
+async fn add_attribute(app: &TestApp, key: &str, kind: &str, options: &str, required: bool) {
+    let mut body = serde_json::json!({
+        "key": key,
+        "label": key.replace('_', " "),
+        "kind": kind,
+        "options": options,
+    });
+    if required {
+        body["required"] = "true".into();
+    }
+    let response = app.post_attribute(&body).await;
+    assert_is_redirect_to(&response, "/admin/attributes");
+}
+
+async fn add_all_kinds_of_attributes(app: &TestApp) {
+    add_attribute(app, "company", "text", "", false).await;
+    add_attribute(app, "employees", "number", "", false).await;
+    add_attribute(app, "birthday", "date", "", false).await;
+    add_attribute(app, "beta_tester", "boolean", "", false).await;
+    add_attribute(app, "plan", "enum", "free, pro", true).await;
+}
+
+async fn get_subscriber_id(app: &TestApp) -> Uuid {
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the subscriber.")
+        .id
+}
+
+const SIGNUP: &str = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_manage_subscriber_attributes() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let form = app.get_attributes().await;
+    let add = app
+        .post_attribute(&serde_json::json!({
+            "key": "company",
+            "label": "Company",
+            "kind": "text",
+        }))
+        .await;
+    let update = app
+        .post_subscriber_attributes(Uuid::new_v4(), &serde_json::json!({"company": "Acme"}))
+        .await;
+
+    // Assert
+    assert_is_redirect_to(&form, "/login");
+    assert_is_redirect_to(&add, "/login");
+    assert_is_redirect_to(&update, "/login");
+}
+
+#[tokio::test]
+async fn added_attributes_are_listed() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // Act
+    add_attribute(&app, "plan", "enum", "free, pro", true).await;
+
+    // Assert
+    let html = app.get_attributes_html().await;
+    assert!(html.contains("The attribute has been added."));
+    assert!(html.contains("<code>plan</code>: enum (free, pro), required"));
+}
+
+#[tokio::test]
+async fn invalid_attribute_definitions_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_attribute(&app, "plan", "text", "", false).await;
+    let test_cases = vec![
+        (("email", "text", ""), "email is not a valid attribute key."),
+        (
+            ("Plan Name", "text", ""),
+            "Plan Name is not a valid attribute key.",
+        ),
+        (
+            ("size", "colour", ""),
+            "colour is not a supported attribute kind.",
+        ),
+        (
+            ("size", "enum", ""),
+            "An enum attribute needs at least one option.",
+        ),
+        (
+            ("plan", "text", ""),
+            "An attribute with key plan already exists.",
+        ),
+    ];
+
+    for ((key, kind, options), error_message) in test_cases {
+        // Act
+        add_attribute(&app, key, kind, options, false).await;
+
+        // Assert
+        let html = app.get_attributes_html().await;
+        assert!(
+            html.contains(error_message),
+            "The form did not report `{}`",
+            error_message
+        );
+    }
+}
+
+#[tokio::test]
+async fn attributes_collected_on_signup_are_stored_as_typed_values() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_all_kinds_of_attributes(&app).await;
+    let body = format!(
+        "{}&company=Acme&employees=42.5&birthday=1929-10-21&beta_tester=on&plan=pro&unknown=x",
+        SIGNUP
+    );
+
+    // Act
+    let response = app.post_subscriptions(body).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let values = sqlx::query!(
+        r#"
+        SELECT d.key, v.text_value, v.number_value, v.date_value::text, v.boolean_value
+        FROM subscriber_attribute_values v
+        JOIN subscriber_attribute_definitions d USING (attribute_id)
+        ORDER BY d.key
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .expect("Failed to fetch the attribute values.");
+    assert_eq!(values.len(), 5);
+    assert_eq!(values[0].key, "beta_tester");
+    assert_eq!(values[0].boolean_value, Some(true));
+    assert_eq!(values[1].key, "birthday");
+    assert_eq!(values[1].date_value.as_deref(), Some("1929-10-21"));
+    assert_eq!(values[2].key, "company");
+    assert_eq!(values[2].text_value.as_deref(), Some("Acme"));
+    assert_eq!(values[3].key, "employees");
+    assert_eq!(values[3].number_value, Some(42.5));
+    assert_eq!(values[4].key, "plan");
+    assert_eq!(values[4].text_value.as_deref(), Some("pro"));
+}
+
+#[tokio::test]
+async fn subscribe_returns_a_400_when_attributes_are_invalid() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_all_kinds_of_attributes(&app).await;
+    let test_cases = vec![
+        ("", "missing required enum"),
+        ("&plan=enterprise", "unknown enum option"),
+        ("&plan=pro&employees=many", "invalid number"),
+        ("&plan=pro&birthday=21%2F10%2F1929", "invalid date"),
+        ("&plan=pro&beta_tester=maybe", "invalid boolean"),
+    ];
+
+    for (attributes, description) in test_cases {
+        // Act
+        let response = app
+            .post_subscriptions(format!("{}{}", SIGNUP, attributes))
+            .await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not return a 400 Bad Request when the payload had an {}.",
+            description
+        );
+    }
+    let saved = sqlx::query!("SELECT count(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.count, 0);
+}
+
+#[tokio::test]
+async fn admins_can_edit_the_attributes_of_a_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_all_kinds_of_attributes(&app).await;
+    app.post_subscriptions(format!("{}&plan=free&company=Acme", SIGNUP))
+        .await
+        .error_for_status()
+        .unwrap();
+    let subscriber_id = get_subscriber_id(&app).await;
+
+    // Act - Part 1 - Update
+    let response = app
+        .post_subscriber_attributes(
+            subscriber_id,
+            &serde_json::json!({"plan": "pro", "company": "", "employees": "7"}),
+        )
+        .await;
+
+    // Assert - Part 1
+    assert_is_redirect_to(&response, &format!("/admin/subscribers/{}", subscriber_id));
+    let html = app
+        .get_subscriber_details(subscriber_id)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("The attributes have been saved."));
+    assert!(html.contains(r#"<option value="pro" selected>pro</option>"#));
+    assert!(html.contains(r#"<input type="number" step="any" name="employees" value="7">"#));
+    // Clearing a field removes the value
+    assert!(html.contains(r#"<input type="text" name="company" value="">"#));
+
+    // Act - Part 2 - Invalid update
+    app.post_subscriber_attributes(subscriber_id, &serde_json::json!({"plan": ""}))
+        .await;
+
+    // Assert - Part 2
+    let html = app
+        .get_subscriber_details(subscriber_id)
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert!(html.contains("plan is required."));
+    assert!(html.contains(r#"<option value="pro" selected>pro</option>"#));
+}
+
+#[tokio::test]
+async fn attributes_are_included_in_the_json_representation_and_the_export() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+    add_all_kinds_of_attributes(&app).await;
+    app.post_subscriptions(format!(
+        "{}&plan=pro&employees=42&birthday=1929-10-21&beta_tester=false",
+        SIGNUP
+    ))
+    .await
+    .error_for_status()
+    .unwrap();
+    let subscriber_id = get_subscriber_id(&app).await;
+
+    // Act
+    let json: serde_json::Value = app
+        .api_client
+        .get(format!(
+            "{}/admin/subscribers/{}",
+            &app.address, subscriber_id
+        ))
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let csv = app.get_subscribers_export().await.text().await.unwrap();
+
+    // Assert
+    assert_eq!(json["email"], "ursula_le_guin@gmail.com");
+    assert_eq!(
+        json["attributes"],
+        serde_json::json!({
+            "company": null,
+            "employees": 42.0,
+            "birthday": "1929-10-21",
+            "beta_tester": false,
+            "plan": "pro",
+        })
+    );
+    assert_eq!(json["consent_records"][0]["event"], "subscribed");
+    let lines: Vec<_> = csv.lines().collect();
+    assert!(lines[0].ends_with(",company,employees,birthday,beta_tester,plan"));
+    assert!(lines[1].ends_with(",,42,1929-10-21,false,pro"));
+}
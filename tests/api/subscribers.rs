@@ -0,0 +1,164 @@
+use crate::helpers::{spawn_app, TestApp};
+use crate::login::assert_is_redirect_to;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn subscribe_through_a_proxy(app: &TestApp, body: &str) -> reqwest::Response {
+    app.api_client
+        .post(format!("{}/subscriptions", &app.address))
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("User-Agent", "consent-test-agent/1.0")
+        .header("X-Forwarded-For", "203.0.113.7")
+        .body(body.to_owned())
+        .send()
+        .await
+        .expect("Failed to execute request.")
+}
+
+async fn get_subscriber_id(app: &TestApp) -> uuid::Uuid {
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the subscriber.")
+        .id
+}
+
+#[tokio::test]
+async fn you_must_be_logged_in_to_browse_subscribers() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let list = app.get_subscribers().await;
+    let export = app.get_subscribers_export().await;
+
+    // Assert
+    assert_is_redirect_to(&list, "/login");
+    assert_is_redirect_to(&export, "/login");
+}
+
+#[tokio::test]
+async fn subscribing_records_the_consent_given() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&source=footer_form";
+
+    // Act
+    subscribe_through_a_proxy(&app, body)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let record = sqlx::query!(
+        "SELECT event, source, ip_address, user_agent, consent_text_version FROM consent_records"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch the consent record.");
+    assert_eq!(record.event, "subscribed");
+    assert_eq!(record.source, "footer_form");
+    // The test server trusts the local proxy, so the forwarded address is recorded.
+    assert_eq!(record.ip_address.as_deref(), Some("203.0.113.7"));
+    assert_eq!(record.user_agent.as_deref(), Some("consent-test-agent/1.0"));
+    assert_eq!(record.consent_text_version, "2024-11-01");
+}
+
+#[tokio::test]
+async fn confirming_a_subscription_records_the_consent_given() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    // Act
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    // Clicking twice must not add a second confirmation to the ledger.
+    reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    let events = sqlx::query!("SELECT event, source FROM consent_records ORDER BY recorded_at")
+        .fetch_all(&app.db_pool)
+        .await
+        .expect("Failed to fetch the consent records.");
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].event, "subscribed");
+    assert_eq!(events[0].source, "signup_form");
+    assert_eq!(events[1].event, "confirmed");
+    assert_eq!(events[1].source, "confirmation_link");
+}
+
+#[tokio::test]
+async fn subscriber_details_show_the_consent_records() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&source=footer_form";
+    subscribe_through_a_proxy(&app, body).await;
+    let subscriber_id = get_subscriber_id(&app).await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let list_html = app.get_subscribers().await.text().await.unwrap();
+    let response = app.get_subscriber_details(subscriber_id).await;
+
+    // Assert
+    assert!(list_html.contains(&format!("/admin/subscribers/{}", subscriber_id)));
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains("ursula_le_guin@gmail.com"));
+    assert!(html.contains("footer_form"));
+    assert!(html.contains("203.0.113.7"));
+    assert!(html.contains("consent-test-agent/1.0"));
+    assert!(html.contains("2024-11-01"));
+}
+
+#[tokio::test]
+async fn details_of_an_unknown_subscriber_are_not_found() {
+    // Arrange
+    let app = spawn_app().await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let response = app.get_subscriber_details(uuid::Uuid::new_v4()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[tokio::test]
+async fn the_export_includes_the_consent_records() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%2C%20guin&email=ursula_le_guin%40gmail.com&source=footer_form";
+    subscribe_through_a_proxy(&app, body).await;
+    app.test_user.login(&app).await;
+
+    // Act
+    let response = app.get_subscribers_export().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "text/csv; charset=utf-8"
+    );
+    let csv = response.text().await.unwrap();
+    let lines: Vec<_> = csv.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("id,email,name,status,subscribed_at,consent_source"));
+    // Values containing a comma are quoted
+    assert!(lines[1].contains(r#","le, guin",pending,"#));
+    assert!(lines[1].contains(",footer_form,203.0.113.7,consent-test-agent/1.0,2024-11-01,"));
+}
@@ -118,6 +118,7 @@ async fn subscribe_sends_a_confirmation_given_valid_data() {
 
     // Act
     let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
 
     // Assert
     // Mock asserts on drop”
@@ -149,3 +150,109 @@ async fn subscribe_fails_if_there_is_a_db_fatal_err() {
     // Assert
     assert_eq!(response.status(), 500);
 }
+
+#[tokio::test]
+async fn subscribe_succeeds_even_if_the_email_provider_is_down() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20user&email=user_email%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let outbox = sqlx::query!("SELECT recipient FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("The confirmation email is missing from the outbox");
+    assert_eq!(outbox.recipient, "user_email@gmail.com");
+}
+
+#[tokio::test]
+async fn confirmation_emails_are_retried_after_a_failure() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20user&email=user_email%40gmail.com";
+    app.post_subscriptions(body.into()).await;
+
+    // Act - Part 1 - The provider fails the first attempt
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_outbox_emails().await;
+
+    let outbox =
+        sqlx::query!("SELECT n_retries, execute_after > now() AS \"delayed!\" FROM email_outbox")
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("The confirmation email should still be in the outbox");
+    assert_eq!(outbox.n_retries, 1);
+    assert!(outbox.delayed);
+
+    // Act - Part 2 - The retry comes due and goes through
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    sqlx::query!("UPDATE email_outbox SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.dispatch_all_outbox_emails().await;
+
+    // Assert
+    let remaining = sqlx::query!("SELECT email_id FROM email_outbox")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(remaining.is_empty());
+}
+
+#[tokio::test]
+async fn confirmation_emails_rejected_by_the_provider_are_not_retried() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20user&email=user_email%40gmail.com";
+    app.post_subscriptions(body.into()).await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(serde_json::json!({
+            "ErrorCode": 406,
+            "Message": "You tried to send to recipient(s) that have been marked as inactive."
+        })))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_outbox_emails().await;
+
+    // Assert
+    let outbox =
+        sqlx::query!(r#"SELECT last_error, failed_at IS NOT NULL AS "failed!" FROM email_outbox"#)
+            .fetch_one(&app.db_pool)
+            .await
+            .expect("The failed email should be kept in the outbox");
+    assert!(outbox.failed);
+    assert!(outbox.last_error.unwrap().contains("marked as inactive"));
+    let subscriber = sqlx::query!("SELECT status FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscriber.status, "suppressed");
+}
@@ -2,6 +2,7 @@ use wiremock::matchers::{method, path};
 use wiremock::{Mock, ResponseTemplate};
 
 use crate::helpers::spawn_app;
+use crate::newsletter::create_unconfirmed_subscriber;
 
 #[tokio::test]
 async fn confirmations_without_token_are_rejected_with_a_400() {
@@ -30,6 +31,7 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
 
     // Use its received_requests method - it returns a vector of all the requests intercepted by
     // the server as long as request recording was enabled (the default).
@@ -56,6 +58,7 @@ async fn clicking_confirmation_link_confirm_the_new_subscriber() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_outbox_emails().await;
     // Use its received_requests method - it returns a vector of all the requests intercepted by
     // the server as long as request recording was enabled (the default).
     // Get the first intercepted request
@@ -80,3 +83,114 @@ async fn clicking_confirmation_link_confirm_the_new_subscriber() {
     assert_eq!(saved.name, "le user");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[tokio::test]
+async fn clicking_confirmation_link_shows_a_confirmed_page() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["Content-Type"],
+        "text/html; charset=utf-8"
+    );
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("Your subscription has been confirmed."));
+    assert!(html_page.contains("<title>Subscription confirmed - Zero2Prod Newsletter</title>"));
+}
+
+#[tokio::test]
+async fn clicking_confirmation_link_twice_shows_an_already_confirmed_page() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("This subscription has already been confirmed"));
+
+    let saved = sqlx::query!("SELECT status FROM subscriptions",)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription.");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[tokio::test]
+async fn an_unknown_token_shows_an_invalid_link_page() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!(
+        "{}/subscriptions/confirm?subscription_token=unknowntoken",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("This link is invalid or has expired"));
+}
+
+#[tokio::test]
+async fn a_database_error_shows_a_something_went_wrong_page() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    // Sabotage the database
+    sqlx::query!("ALTER TABLE subscriptions DROP COLUMN status;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+    let html_page = response.text().await.unwrap();
+    assert!(html_page.contains("Something went wrong"));
+}
+
+#[tokio::test]
+async fn api_clients_get_a_json_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = create_unconfirmed_subscriber(&app).await;
+    let get_json = || async {
+        let response = app
+            .api_client
+            .get(confirmation_links.html.clone())
+            .header("Accept", "application/json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        response.json::<serde_json::Value>().await.unwrap()
+    };
+
+    // Act
+    let first = get_json().await;
+    let second = get_json().await;
+
+    // Assert
+    assert_eq!(first["status"], "confirmed");
+    assert_eq!(second["status"], "already_confirmed");
+}
@@ -0,0 +1,120 @@
+use crate::helpers::{accept_batch, spawn_app, COLLECTOR};
+use crate::login::assert_is_redirect_to;
+use crate::newsletter::{create_confirmed_subscriber, when_delivering_a_batch};
+use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+use opentelemetry_proto::tonic::trace::v1::Span;
+use prost::Message;
+use std::time::Duration;
+use uuid::Uuid;
+use zero2prod::telemetry::flush_traces;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// A random W3C trace context, as a `traceparent` header and its trace and span IDs.
+fn trace_context() -> (String, String, String) {
+    let trace_id = Uuid::new_v4().simple().to_string();
+    let span_id = Uuid::new_v4().simple().to_string()[..16].to_owned();
+    (format!("00-{trace_id}-{span_id}-01"), trace_id, span_id)
+}
+
+/// The first span matching `predicate` the collector received.
+///
+/// Spans are exported once they end, in batches: the collector may take a moment to see them.
+async fn exported_span(predicate: impl Fn(&Span) -> bool) -> Span {
+    for _ in 0..50 {
+        tokio::task::spawn_blocking(flush_traces).await.unwrap();
+        let span = COLLECTOR
+            .received_requests()
+            .await
+            .unwrap()
+            .into_iter()
+            .flat_map(|request| {
+                ExportTraceServiceRequest::decode(request.body.as_slice())
+                    .unwrap()
+                    .resource_spans
+            })
+            .flat_map(|resource_spans| resource_spans.scope_spans)
+            .flat_map(|scope_spans| scope_spans.spans)
+            .find(|span| predicate(span));
+        if let Some(span) = span {
+            return span;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("No matching span was exported");
+}
+
+#[tokio::test]
+async fn requests_carrying_a_trace_context_continue_the_callers_trace() {
+    // Arrange
+    let app = spawn_app().await;
+    let (traceparent, trace_id, parent_span_id) = trace_context();
+
+    // Act
+    let response = app
+        .api_client
+        .get(format!("{}/health_check", &app.address))
+        .header("traceparent", traceparent)
+        .send()
+        .await
+        .expect("Failed to execute request.");
+
+    // Assert
+    assert!(response.status().is_success());
+    let span = exported_span(|span| hex(&span.trace_id) == trace_id).await;
+    assert_eq!(hex(&span.parent_span_id), parent_span_id);
+}
+
+#[tokio::test]
+async fn deliveries_are_traced_on_their_own_and_link_back_to_the_publish_request() {
+    // Arrange
+    let app = spawn_app().await;
+    create_confirmed_subscriber(&app).await;
+    app.test_user.login(&app).await;
+    when_delivering_a_batch()
+        .respond_with(accept_batch)
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    let (traceparent, publish_trace_id, _) = trace_context();
+    let response = app
+        .api_client
+        .post(format!("{}/admin/newsletters", &app.address))
+        .header("traceparent", traceparent)
+        .form(&serde_json::json!({
+            "title": "Newsletter title",
+            "text_content": "Newsletter body as plain text",
+            "html_content": "<p>Newsletter body as HTML</p>",
+            "idempotency_key": Uuid::new_v4().to_string(),
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request.");
+    assert_is_redirect_to(&response, "/admin/newsletters");
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert - The email provider is told about the delivery's trace
+    let requests = app.email_server.received_requests().await.unwrap();
+    let batch = requests
+        .iter()
+        .find(|request| request.url.path() == "/email/batch")
+        .unwrap();
+    let traceparent = batch.headers.get("traceparent").unwrap().to_str().unwrap();
+    let delivery_trace_id = traceparent.split('-').nth(1).unwrap().to_owned();
+    assert_ne!(delivery_trace_id, publish_trace_id);
+
+    // Assert - The delivery task links back to the request that published the issue
+    let task_span = exported_span(|span| {
+        span.name == "Execute delivery task"
+            && span
+                .links
+                .iter()
+                .any(|link| hex(&link.trace_id) == publish_trace_id)
+    })
+    .await;
+    assert_eq!(hex(&task_span.trace_id), delivery_trace_id);
+}